@@ -0,0 +1 @@
+DROP TABLE IF EXISTS controller_telemetry;
\ No newline at end of file
@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS controller_telemetry (
+    -- The time of the measurement the controller was sampled with (joins onto measurement.time)
+    time INTEGER PRIMARY KEY NOT NULL,
+
+    controller VARCHAR(30) NOT NULL,
+    heat_level FLOAT NOT NULL,
+    setpoint FLOAT NOT NULL,
+
+    -- PID
+    error FLOAT NULL,
+    p FLOAT NULL,
+    i FLOAT NULL,
+    d FLOAT NULL,
+
+    -- Predictive
+    predicted_delta FLOAT NULL,
+    q FLOAT NULL
+);
@@ -0,0 +1 @@
+DROP TABLE IF EXISTS thermal_model;
\ No newline at end of file
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS thermal_model (
+    time INTEGER PRIMARY KEY NOT NULL,
+
+    -- °C/s at full heat
+    heater_gain FLOAT NOT NULL,
+    -- 1/s
+    loss_coefficient FLOAT NOT NULL,
+    ambient_temp FLOAT NOT NULL,
+    lag_s FLOAT NOT NULL,
+    error_variance FLOAT NOT NULL,
+    samples INTEGER NOT NULL
+);
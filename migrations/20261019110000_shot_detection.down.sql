@@ -0,0 +1,2 @@
+ALTER TABLE shot DROP COLUMN confidence;
+ALTER TABLE shot DROP COLUMN detected;
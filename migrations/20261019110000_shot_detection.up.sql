@@ -0,0 +1,4 @@
+-- Shots detected from the temperature signature, rather than recorded from brew mode
+ALTER TABLE shot ADD COLUMN detected BOOLEAN NOT NULL DEFAULT FALSE;
+-- 0.0 - 1.0, only set for detected shots
+ALTER TABLE shot ADD COLUMN confidence FLOAT NULL;
@@ -0,0 +1 @@
+DROP TABLE IF EXISTS eta;
\ No newline at end of file
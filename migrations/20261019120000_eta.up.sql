@@ -0,0 +1,15 @@
+-- How long heating up or cooling down to the target took, compared with the first prediction.
+CREATE TABLE IF NOT EXISTS eta (
+    start_time INTEGER PRIMARY KEY NOT NULL,
+    -- heating or cooling
+    phase VARCHAR(10) NOT NULL,
+    target_temp FLOAT NOT NULL,
+    start_boiler_temp FLOAT NOT NULL,
+    start_grouphead_temp FLOAT NOT NULL,
+
+    predicted_boiler_s FLOAT NULL,
+    predicted_preheat_s FLOAT NULL,
+    actual_boiler_s FLOAT NOT NULL,
+    -- Not recorded when cooling, the grouphead is already hot
+    actual_preheat_s FLOAT NULL
+);
@@ -0,0 +1,8 @@
+-- Mode changes made at a time, e.g. turning the machine on before breakfast.
+CREATE TABLE IF NOT EXISTS schedule (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    name TEXT NOT NULL,
+    -- A five field cron expression, evaluated in local time
+    cron TEXT NOT NULL,
+    mode VARCHAR(10) NOT NULL
+);
@@ -0,0 +1,2 @@
+ALTER TABLE shot DROP COLUMN profile_id;
+DROP TABLE IF EXISTS profile;
\ No newline at end of file
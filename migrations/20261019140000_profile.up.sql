@@ -0,0 +1,16 @@
+-- Named settings for a coffee, applied together when activated.
+CREATE TABLE IF NOT EXISTS profile (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    name TEXT NOT NULL,
+    brew_target_temp FLOAT NOT NULL,
+    steam_target_temp FLOAT NOT NULL,
+    control_method VARCHAR(10) NOT NULL,
+    -- JSON, see ControllerParameters
+    controller_parameters TEXT NOT NULL,
+    require_preheat BOOLEAN NOT NULL,
+    target_shot_time_s INTEGER NOT NULL,
+    target_yield_g FLOAT NOT NULL
+);
+
+-- The profile that was active when the shot was pulled
+ALTER TABLE shot ADD COLUMN profile_id INTEGER NULL REFERENCES profile(id) ON DELETE SET NULL;
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS shot_setpoint;
+ALTER TABLE profile DROP COLUMN brew_curve;
\ No newline at end of file
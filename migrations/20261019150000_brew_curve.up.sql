@@ -0,0 +1,10 @@
+-- JSON, see BrewCurve. NULL holds the brew target for the whole shot
+ALTER TABLE profile ADD COLUMN brew_curve TEXT NULL;
+
+-- The setpoints sent to the controller during a shot that followed a brew curve
+CREATE TABLE IF NOT EXISTS shot_setpoint (
+    shot_start_time INTEGER NOT NULL REFERENCES shot(start_time) ON DELETE CASCADE,
+    time INTEGER NOT NULL,
+    target_temp_c FLOAT NOT NULL,
+    PRIMARY KEY (shot_start_time, time)
+);
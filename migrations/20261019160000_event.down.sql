@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS event_time;
+DROP TABLE IF EXISTS event;
\ No newline at end of file
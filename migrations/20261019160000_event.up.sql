@@ -0,0 +1,12 @@
+-- An append-only log of commands and state changes, telemetry isn't included.
+CREATE TABLE IF NOT EXISTS event (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    time INTEGER NOT NULL,
+    -- Who or what caused the event, e.g. mqtt:{client_id}, scheduler, safety
+    source TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    -- JSON
+    payload TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS event_time ON event (time);
@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+// A lumped thermal model of the boiler and grouphead, used to benchmark controllers without the machine.
+// The coefficients are rough fits to the measurements in models/boiler_levels and models/thermal_loss.
+pub struct SimulatedBoiler {
+    pub boiler_temp: f32,
+    pub grouphead_temp: f32,
+    // The rate cold water is drawn into the boiler in ml/s, e.g. while a shot is being pulled.
+    pub draw_rate: f32,
+    // The element takes a few seconds to heat up and cool down,
+    // so the heat it delivers lags behind the heat level applied to it.
+    element_heat: f32,
+    params: BoilerParameters,
+}
+
+pub struct BoilerParameters {
+    pub ambient_temp: f32,
+    pub inlet_temp: f32,
+    pub boiler_volume_ml: f32,
+    // The rate of temperature increase at full heat, in °C/s.
+    pub heater_gain: f32,
+    // The time constant of the element, in seconds.
+    pub heater_lag: f32,
+    // The rate heat is lost to the surroundings, as a fraction of the difference to ambient per second.
+    pub loss_coefficient: f32,
+    pub boiler_to_grouphead: f32,
+    pub grouphead_to_boiler: f32,
+    pub grouphead_loss_coefficient: f32,
+}
+
+impl Default for BoilerParameters {
+    fn default() -> Self {
+        BoilerParameters {
+            ambient_temp: 20.0,
+            inlet_temp: 20.0,
+            boiler_volume_ml: 300.0,
+            heater_gain: 0.63,
+            heater_lag: 5.0,
+            loss_coefficient: 0.001,
+            boiler_to_grouphead: 0.0005,
+            grouphead_to_boiler: 0.002,
+            grouphead_loss_coefficient: 0.0005,
+        }
+    }
+}
+
+// The resolution of the MAX31855 thermocouple amplifier.
+const THERMOCOUPLE_RESOLUTION_C: f32 = 0.25;
+
+impl SimulatedBoiler {
+    pub fn new(boiler_temp: f32, grouphead_temp: f32) -> Self {
+        SimulatedBoiler {
+            boiler_temp,
+            grouphead_temp,
+            draw_rate: 0.0,
+            element_heat: 0.0,
+            params: BoilerParameters::default(),
+        }
+    }
+
+    pub fn step(&mut self, heat_level: f32, dt: Duration) {
+        let dt = dt.as_secs_f32();
+        let p = &self.params;
+
+        self.element_heat += (heat_level - self.element_heat) * (dt / p.heater_lag).min(1.0);
+
+        let boiler_temp_change = p.heater_gain * self.element_heat
+            - p.loss_coefficient * (self.boiler_temp - p.ambient_temp)
+            - p.boiler_to_grouphead * (self.boiler_temp - self.grouphead_temp)
+            - (self.draw_rate / p.boiler_volume_ml) * (self.boiler_temp - p.inlet_temp);
+
+        let grouphead_temp_change = p.grouphead_to_boiler * (self.boiler_temp - self.grouphead_temp)
+            - p.grouphead_loss_coefficient * (self.grouphead_temp - p.ambient_temp);
+
+        self.boiler_temp += boiler_temp_change * dt;
+        self.grouphead_temp += grouphead_temp_change * dt;
+    }
+
+    // The boiler and grouphead temperatures as the thermocouples would read them.
+    pub fn read(&self) -> (f32, f32) {
+        (quantise(self.boiler_temp), quantise(self.grouphead_temp))
+    }
+}
+
+fn quantise(temp: f32) -> f32 {
+    (temp / THERMOCOUPLE_RESOLUTION_C).round() * THERMOCOUPLE_RESOLUTION_C
+}
@@ -0,0 +1,251 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+// The band around the target temperature that counts as being on target.
+pub const BAND_C: f32 = 0.5;
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Metrics {
+    // The furthest the temperature went past the target after reaching it.
+    pub overshoot_c: f32,
+    // How long it took for the temperature to stay within the band after the target was set,
+    // or None if it never settled.
+    pub settling_time_s: Option<f32>,
+    pub integral_absolute_error: f32,
+    pub time_in_band_pct: f32,
+    // The number of times the heater was switched on or off.
+    pub switch_count: u32,
+}
+
+// A period with a constant target temperature.
+struct Segment {
+    target: f32,
+    rising: Option<bool>,
+    reached: bool,
+    overshoot: f32,
+    duration: Duration,
+    // The time since the segment started at which the temperature was last outside the band.
+    last_out_of_band: Option<Duration>,
+    evaluated: bool,
+}
+
+pub struct MetricsRecorder {
+    segments: Vec<Segment>,
+    integral_absolute_error: f32,
+    time_in_band: Duration,
+    time_evaluated: Duration,
+    switch_count: u32,
+    heating: bool,
+}
+
+impl MetricsRecorder {
+    pub fn new(target: f32) -> Self {
+        let mut recorder = MetricsRecorder {
+            segments: vec![],
+            integral_absolute_error: 0.0,
+            time_in_band: Duration::ZERO,
+            time_evaluated: Duration::ZERO,
+            switch_count: 0,
+            heating: false,
+        };
+
+        recorder.start_segment(target, true);
+
+        recorder
+    }
+
+    // Periods where the controller under test isn't in control (e.g. steam) aren't evaluated.
+    pub fn start_segment(&mut self, target: f32, evaluated: bool) {
+        self.segments.push(Segment {
+            target,
+            rising: None,
+            reached: false,
+            overshoot: 0.0,
+            duration: Duration::ZERO,
+            last_out_of_band: None,
+            evaluated,
+        });
+    }
+
+    pub fn record(&mut self, temp: f32, heat_level: f32, dt: Duration) {
+        let segment = self.segments.last_mut().unwrap();
+        let error = temp - segment.target;
+        // A segment that starts on target is treated as approaching from below,
+        // so overshoot is only measured above the target.
+        let rising = *segment.rising.get_or_insert(error < BAND_C);
+
+        if !segment.reached && ((rising && error >= 0.0) || (!rising && error <= 0.0)) {
+            segment.reached = true;
+        }
+
+        if segment.reached {
+            let overshoot = if rising { error } else { -error };
+            segment.overshoot = segment.overshoot.max(overshoot);
+        }
+
+        let in_band = error.abs() <= BAND_C;
+
+        if !in_band {
+            segment.last_out_of_band = Some(segment.duration);
+        }
+
+        segment.duration += dt;
+
+        let heating = heat_level > 0.0;
+
+        if segment.evaluated {
+            self.time_evaluated += dt;
+            self.integral_absolute_error += error.abs() * dt.as_secs_f32();
+
+            if in_band {
+                self.time_in_band += dt;
+            }
+
+            if heating != self.heating {
+                self.switch_count += 1;
+            }
+        }
+
+        self.heating = heating;
+    }
+
+    pub fn finish(&self, sample_interval: Duration) -> Metrics {
+        let evaluated_segments = self.segments.iter().filter(|segment| segment.evaluated);
+
+        let overshoot_c = evaluated_segments
+            .clone()
+            .map(|segment| segment.overshoot)
+            .fold(0.0, f32::max);
+
+        let settling_time_s = evaluated_segments
+            .map(|segment| match segment.last_out_of_band {
+                // The temperature was outside the band at the end of the segment, so it never settled.
+                Some(last_out_of_band) if last_out_of_band + sample_interval >= segment.duration => {
+                    None
+                }
+                Some(last_out_of_band) => Some((last_out_of_band + sample_interval).as_secs_f32()),
+                None => Some(0.0),
+            })
+            .try_fold(0.0, |max: f32, settling_time| settling_time.map(|t| max.max(t)));
+
+        let time_in_band_pct = if self.time_evaluated.is_zero() {
+            0.0
+        } else {
+            self.time_in_band.as_secs_f32() / self.time_evaluated.as_secs_f32() * 100.0
+        };
+
+        Metrics {
+            overshoot_c,
+            settling_time_s,
+            integral_absolute_error: self.integral_absolute_error,
+            time_in_band_pct,
+            switch_count: self.switch_count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One sample a second keeps the expected times and integrals easy to work out.
+    const DT: Duration = Duration::from_secs(1);
+
+    // Records (temperature, heat level) samples against a single target.
+    fn metrics(target: f32, trace: &[(f32, f32)]) -> Metrics {
+        let mut recorder = MetricsRecorder::new(target);
+
+        for (temp, heat_level) in trace {
+            recorder.record(*temp, *heat_level, DT);
+        }
+
+        recorder.finish(DT)
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-4, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn overshooting_then_settling() {
+        let metrics = metrics(
+            90.0,
+            &[
+                (85.0, 1.0),
+                (88.0, 1.0),
+                (90.8, 0.0),
+                (91.5, 0.0),
+                (90.4, 0.0),
+                (90.2, 1.0),
+                (90.0, 1.0),
+                (89.9, 0.0),
+            ],
+        );
+
+        assert_close(metrics.overshoot_c, 1.5);
+        // The last sample outside the band is at 3s, so it's settled from 4s.
+        assert_eq!(metrics.settling_time_s, Some(4.0));
+        assert_close(metrics.integral_absolute_error, 10.0);
+        assert_close(metrics.time_in_band_pct, 50.0);
+        assert_eq!(metrics.switch_count, 4);
+    }
+
+    #[test]
+    fn never_reaching_the_target() {
+        let metrics = metrics(90.0, &[(85.0, 1.0), (86.0, 1.0), (87.0, 1.0)]);
+
+        assert_eq!(metrics.overshoot_c, 0.0);
+        assert_eq!(metrics.settling_time_s, None);
+        assert_close(metrics.integral_absolute_error, 12.0);
+        assert_eq!(metrics.time_in_band_pct, 0.0);
+        assert_eq!(metrics.switch_count, 1);
+    }
+
+    #[test]
+    fn cooling_to_a_lower_target() {
+        let metrics = metrics(
+            90.0,
+            &[
+                (95.0, 0.0),
+                (92.0, 0.0),
+                (89.7, 0.0),
+                (89.9, 0.0),
+                (90.0, 0.0),
+            ],
+        );
+
+        // Overshoot is measured below the target when it's approached from above.
+        assert_close(metrics.overshoot_c, 0.3);
+        assert_eq!(metrics.settling_time_s, Some(2.0));
+        assert_close(metrics.integral_absolute_error, 7.4);
+        assert_close(metrics.time_in_band_pct, 60.0);
+        assert_eq!(metrics.switch_count, 0);
+    }
+
+    #[test]
+    fn segments_that_are_not_evaluated_are_ignored() {
+        let mut recorder = MetricsRecorder::new(90.0);
+
+        recorder.record(90.0, 0.0, DT);
+        recorder.record(90.2, 0.0, DT);
+
+        recorder.start_segment(140.0, false);
+        recorder.record(100.0, 1.0, DT);
+        recorder.record(120.0, 1.0, DT);
+        recorder.record(130.0, 0.0, DT);
+
+        recorder.start_segment(90.0, true);
+        recorder.record(90.0, 0.0, DT);
+        recorder.record(89.8, 0.0, DT);
+
+        let metrics = recorder.finish(DT);
+
+        assert_close(metrics.overshoot_c, 0.2);
+        assert_eq!(metrics.settling_time_s, Some(0.0));
+        assert_close(metrics.integral_absolute_error, 0.4);
+        assert_eq!(metrics.time_in_band_pct, 100.0);
+        assert_eq!(metrics.switch_count, 0);
+    }
+}
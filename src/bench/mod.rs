@@ -0,0 +1,224 @@
+// Runs every control method through scripted scenarios against a simulated boiler,
+// so that changes to the controllers can be compared objectively.
+pub mod boiler;
+mod metrics;
+mod scenario;
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{
+    controller::{
+        get_controller, limit_heat_level, normalize_duty_cycle, ControlMethod,
+        ControllerParameters, HeatLevelHistory, SampleContext, HEAT_LEVEL_HISTORY_LENGTH,
+        SAMPLE_INTERVAL,
+    },
+    core::{config::PredictiveConfig, state::Mode},
+    models::PredictiveModels,
+};
+
+use self::{
+    boiler::SimulatedBoiler,
+    metrics::{Metrics, MetricsRecorder},
+    scenario::{Action, Scenario, SHOT_DRAW_RATE, STEAM_TEMPERATURE},
+};
+
+pub const CONTROL_METHODS: [ControlMethod; 4] = [
+    ControlMethod::Threshold,
+    ControlMethod::PID,
+    ControlMethod::Predictive,
+    ControlMethod::None,
+];
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BenchResult {
+    pub scenario: String,
+    pub control_method: ControlMethod,
+    pub metrics: Metrics,
+}
+
+pub fn run(json: bool) -> Result<()> {
+    let models = PredictiveModels::new()?;
+
+    let results = run_benchmarks(&models);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    } else {
+        print_table(&results);
+    }
+
+    Ok(())
+}
+
+pub fn run_benchmarks(models: &PredictiveModels) -> Vec<BenchResult> {
+    let mut results = vec![];
+
+    for scenario in scenario::scenarios() {
+        for control_method in CONTROL_METHODS {
+            results.push(BenchResult {
+                scenario: scenario.name.to_string(),
+                control_method,
+                metrics: run_scenario(&scenario, control_method, models),
+            });
+        }
+    }
+
+    results
+}
+
+// Drives the controller the same way the ControllerManager does, but in simulated time.
+fn run_scenario(
+    scenario: &Scenario,
+    control_method: ControlMethod,
+    models: &PredictiveModels,
+) -> Metrics {
+    let mut boiler = SimulatedBoiler::new(
+        scenario.initial_boiler_temp,
+        scenario.initial_grouphead_temp,
+    );
+    let mut target_temperature = scenario.target_temperature;
+    let mut mode = Mode::Active;
+    let predictive_fallback = PredictiveConfig::default().fallback_control_method;
+    let parameters = ControllerParameters::default();
+    let mut controller = get_controller(
+        &control_method,
+        target_temperature,
+        &parameters,
+        &predictive_fallback,
+    );
+    let mut heat_level_history = HeatLevelHistory::new(HEAT_LEVEL_HISTORY_LENGTH, SAMPLE_INTERVAL);
+    let mut boiler_temp_history: Vec<f32> = vec![];
+    let mut metrics = MetricsRecorder::new(target_temperature);
+    let mut heat_level: f32 = 0.0;
+
+    let started_at = SystemTime::now();
+    let mut elapsed = Duration::ZERO;
+    let mut steps = scenario.steps.iter().peekable();
+
+    while elapsed < scenario.duration {
+        let (boiler_temp, grouphead_temp) = boiler.read();
+
+        boiler_temp_history.push(boiler_temp);
+
+        while let Some(step) = steps.next_if(|step| step.at <= elapsed) {
+            match step.action {
+                Action::SetTargetTemperature(target) => {
+                    target_temperature = target;
+
+                    if let Some(controller) = &mut controller {
+                        controller.update_target_temperature(target);
+                    }
+
+                    metrics.start_segment(target, true);
+                }
+                Action::StartShot => {
+                    mode = Mode::Brew;
+                    boiler.draw_rate = SHOT_DRAW_RATE;
+                }
+                Action::EndShot => {
+                    mode = Mode::Active;
+                    boiler.draw_rate = 0.0;
+                }
+                Action::StartSteam => {
+                    // Steam mode always uses the threshold controller, see State::add_steam_mode_events.
+                    mode = Mode::Steam;
+                    controller = get_controller(
+                        &ControlMethod::Threshold,
+                        STEAM_TEMPERATURE,
+                        &parameters,
+                        &predictive_fallback,
+                    );
+
+                    if let Some(controller) = &mut controller {
+                        controller.initialise(heat_level, &boiler_temp_history);
+                    }
+
+                    metrics.start_segment(STEAM_TEMPERATURE, false);
+                }
+                Action::EndSteam => {
+                    mode = Mode::Active;
+                    controller = get_controller(
+                        &control_method,
+                        target_temperature,
+                        &parameters,
+                        &predictive_fallback,
+                    );
+
+                    if let Some(controller) = &mut controller {
+                        controller.initialise(heat_level, &boiler_temp_history);
+                    }
+
+                    metrics.start_segment(target_temperature, true);
+                }
+                Action::SetDrawRate(draw_rate) => {
+                    boiler.draw_rate = draw_rate;
+                }
+            }
+        }
+
+        if let Some(controller) = &mut controller {
+            let context = SampleContext {
+                timestamp: started_at + elapsed,
+                measurement_timestamp: started_at + elapsed,
+                dt: SAMPLE_INTERVAL,
+                boiler_temp,
+                grouphead_temp,
+                thermofilter_temp: None,
+                mode: &mode,
+                power_state: true,
+                heat_level_history: &heat_level_history,
+                models,
+                thermal_model: None,
+            };
+
+            let applied_heat_level =
+                limit_heat_level(controller.sample(&context), &mode, true, boiler_temp);
+
+            controller.track_applied_heat_level(applied_heat_level);
+
+            heat_level = normalize_duty_cycle(applied_heat_level) as f32 / 10.0;
+        }
+
+        heat_level_history.push(heat_level);
+        metrics.record(boiler_temp, heat_level, SAMPLE_INTERVAL);
+        boiler.step(heat_level, SAMPLE_INTERVAL);
+
+        elapsed += SAMPLE_INTERVAL;
+    }
+
+    metrics.finish(SAMPLE_INTERVAL)
+}
+
+fn print_table(results: &[BenchResult]) {
+    println!(
+        "{:<20} {:<12} {:>14} {:>18} {:>12} {:>12} {:>10}",
+        "Scenario",
+        "Method",
+        "Overshoot (°C)",
+        "Settling time (s)",
+        "IAE (°C·s)",
+        "In band (%)",
+        "Switches"
+    );
+
+    for result in results {
+        let metrics = &result.metrics;
+
+        println!(
+            "{:<20} {:<12} {:>14.2} {:>18} {:>12.1} {:>12.1} {:>10}",
+            result.scenario,
+            format!("{:?}", result.control_method),
+            metrics.overshoot_c,
+            metrics
+                .settling_time_s
+                .map_or(String::from("-"), |settling_time| format!("{:.1}", settling_time)),
+            metrics.integral_absolute_error,
+            metrics.time_in_band_pct,
+            metrics.switch_count
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+pub const BREW_TEMPERATURE: f32 = 95.0;
+pub const STEAM_TEMPERATURE: f32 = 130.0;
+
+// Roughly 2ml/s of water is pulled through the boiler during a shot.
+pub const SHOT_DRAW_RATE: f32 = 2.0;
+// Steaming boils off water, which costs about as much heat as drawing 1ml/s of cold water.
+pub const STEAM_DRAW_RATE: f32 = 1.0;
+
+pub struct Scenario {
+    pub name: &'static str,
+    pub duration: Duration,
+    pub initial_boiler_temp: f32,
+    pub initial_grouphead_temp: f32,
+    pub target_temperature: f32,
+    pub steps: Vec<ScenarioStep>,
+}
+
+pub struct ScenarioStep {
+    pub at: Duration,
+    pub action: Action,
+}
+
+pub enum Action {
+    SetTargetTemperature(f32),
+    StartShot,
+    EndShot,
+    StartSteam,
+    EndSteam,
+    // Draws water from the boiler without pulling a shot, e.g. steaming milk.
+    SetDrawRate(f32),
+}
+
+fn step(at_secs: u64, action: Action) -> ScenarioStep {
+    ScenarioStep {
+        at: Duration::from_secs(at_secs),
+        action,
+    }
+}
+
+pub fn scenarios() -> Vec<Scenario> {
+    vec![
+        Scenario {
+            name: "cold_start",
+            duration: Duration::from_secs(20 * 60),
+            initial_boiler_temp: 20.0,
+            initial_grouphead_temp: 20.0,
+            target_temperature: BREW_TEMPERATURE,
+            steps: vec![],
+        },
+        Scenario {
+            name: "setpoint_step",
+            duration: Duration::from_secs(10 * 60),
+            initial_boiler_temp: 92.0,
+            initial_grouphead_temp: 78.0,
+            target_temperature: 92.0,
+            steps: vec![step(30, Action::SetTargetTemperature(BREW_TEMPERATURE))],
+        },
+        Scenario {
+            name: "back_to_back_shots",
+            duration: Duration::from_secs(10 * 60),
+            initial_boiler_temp: BREW_TEMPERATURE,
+            initial_grouphead_temp: 80.0,
+            target_temperature: BREW_TEMPERATURE,
+            steps: vec![
+                step(60, Action::StartShot),
+                step(88, Action::EndShot),
+                step(120, Action::StartShot),
+                step(148, Action::EndShot),
+                step(180, Action::StartShot),
+                step(208, Action::EndShot),
+            ],
+        },
+        Scenario {
+            name: "steam_and_return",
+            duration: Duration::from_secs(15 * 60),
+            initial_boiler_temp: BREW_TEMPERATURE,
+            initial_grouphead_temp: 80.0,
+            target_temperature: BREW_TEMPERATURE,
+            steps: vec![
+                step(60, Action::StartSteam),
+                step(180, Action::SetDrawRate(STEAM_DRAW_RATE)),
+                step(240, Action::SetDrawRate(0.0)),
+                step(240, Action::EndSteam),
+            ],
+        },
+    ]
+}
@@ -0,0 +1,149 @@
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    core::{
+        state::{IsPowerOn, Mode},
+        thermal_model::ThermalModel,
+        util::FixedCapacityQueue,
+    },
+    models::PredictiveModels,
+};
+
+// Everything a controller can base its decision on.
+pub struct SampleContext<'a> {
+    // When the sample was taken.
+    pub timestamp: SystemTime,
+    // When the temperatures below were measured.
+    pub measurement_timestamp: SystemTime,
+    // The time elapsed since the previous sample.
+    pub dt: Duration,
+    pub boiler_temp: f32,
+    pub grouphead_temp: f32,
+    pub thermofilter_temp: Option<f32>,
+    pub mode: &'a Mode,
+    pub power_state: IsPowerOn,
+    pub heat_level_history: &'a HeatLevelHistory,
+    pub models: &'a PredictiveModels,
+    // The boiler's thermal model as estimated from live measurements, if there's enough data yet.
+    pub thermal_model: Option<&'a ThermalModel>,
+}
+
+// The heat levels applied to the boiler, one per sample interval.
+pub struct HeatLevelHistory {
+    // Heat levels are stored in increments of 0.1, which is the precision of the duty cycle.
+    queue: FixedCapacityQueue<u8>,
+    sample_interval: Duration,
+}
+
+impl HeatLevelHistory {
+    pub fn new(length: Duration, sample_interval: Duration) -> Self {
+        let capacity = (length.as_millis() / sample_interval.as_millis()) as usize;
+
+        HeatLevelHistory {
+            queue: FixedCapacityQueue::new(capacity),
+            sample_interval,
+        }
+    }
+
+    pub fn push(&mut self, heat_level: f32) {
+        self.queue.push((heat_level.clamp(0.0, 1.0) * 10.0).round() as u8);
+    }
+
+    pub fn latest(&self) -> Option<f32> {
+        self.queue.iter().last().map(|level| *level as f32 / 10.0)
+    }
+
+    // The sum of the heat levels applied over the window, i.e. the number of samples at full heat.
+    // This is what the predictive models call the rolling heat level, or `q`.
+    pub fn sum(&self, window: Duration) -> f32 {
+        let samples = (window.as_millis() / self.sample_interval.as_millis()) as usize;
+
+        let sum: i32 = self
+            .queue
+            .iter()
+            .rev()
+            .take(samples)
+            .map(|level| *level as i32)
+            .sum();
+
+        sum as f32 / 10.0
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::OnceLock;
+
+    use super::*;
+    use crate::controller::{HEAT_LEVEL_HISTORY_LENGTH, SAMPLE_INTERVAL};
+
+    // Loading the embedded models is slow, so every test shares one copy.
+    pub(crate) fn models() -> &'static PredictiveModels {
+        static MODELS: OnceLock<PredictiveModels> = OnceLock::new();
+
+        MODELS.get_or_init(|| PredictiveModels::new().unwrap())
+    }
+
+    pub(crate) fn history(heat_levels: &[f32]) -> HeatLevelHistory {
+        let mut history = HeatLevelHistory::new(HEAT_LEVEL_HISTORY_LENGTH, SAMPLE_INTERVAL);
+
+        for heat_level in heat_levels {
+            history.push(*heat_level);
+        }
+
+        history
+    }
+
+    pub(crate) fn context<'a>(
+        boiler_temp: f32,
+        grouphead_temp: f32,
+        heat_level_history: &'a HeatLevelHistory,
+    ) -> SampleContext<'a> {
+        SampleContext {
+            timestamp: SystemTime::UNIX_EPOCH,
+            measurement_timestamp: SystemTime::UNIX_EPOCH,
+            dt: SAMPLE_INTERVAL,
+            boiler_temp,
+            grouphead_temp,
+            thermofilter_temp: None,
+            mode: &Mode::Active,
+            power_state: true,
+            heat_level_history,
+            models: models(),
+            thermal_model: None,
+        }
+    }
+
+    #[test]
+    fn heat_levels_are_clamped_and_rounded() {
+        let history = history(&[0.34, 1.5, -0.2, 0.26]);
+
+        assert_eq!(history.latest(), Some(0.3));
+        assert_eq!(history.sum(HEAT_LEVEL_HISTORY_LENGTH), 1.6);
+        assert_eq!(HeatLevelHistory::new(HEAT_LEVEL_HISTORY_LENGTH, SAMPLE_INTERVAL).latest(), None);
+    }
+
+    #[test]
+    fn sum_only_includes_the_window() {
+        // 20 seconds at full heat followed by 10 seconds at half heat.
+        let mut heat_levels = vec![1.0; 200];
+        heat_levels.extend([0.5; 100]);
+        let history = history(&heat_levels);
+
+        assert_eq!(history.sum(Duration::from_secs(10)), 50.0);
+        assert_eq!(history.sum(Duration::from_secs(15)), 100.0);
+        assert_eq!(history.sum(HEAT_LEVEL_HISTORY_LENGTH), 250.0);
+    }
+
+    #[test]
+    fn history_is_trimmed_to_its_length() {
+        // 125 seconds at full heat, then 10 seconds off pushes the oldest 100 samples out.
+        let mut heat_levels = vec![1.0; 1250];
+        heat_levels.extend([0.0; 100]);
+        let history = history(&heat_levels);
+
+        assert_eq!(history.sum(HEAT_LEVEL_HISTORY_LENGTH), 1150.0);
+        assert_eq!(history.sum(Duration::from_secs(200)), 1150.0);
+        assert_eq!(history.latest(), Some(0.0));
+    }
+}
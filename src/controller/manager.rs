@@ -1,10 +1,13 @@
-use std::time::Duration;
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
 use anyhow::{anyhow, Result};
 
 use log::{debug, error, info};
 use rppal::gpio;
-use serde::{Deserialize, Serialize};
 use tokio::{
     select,
     sync::broadcast::Sender,
@@ -12,19 +15,23 @@ use tokio::{
 };
 use tokio_util::sync::CancellationToken;
 
-use super::{PidController, PredictiveController, ThresholdController};
+use super::{
+    get_controller, limit_heat_level, normalize_duty_cycle, ControlMethod, Controller,
+    ControllerDegradation, ControllerParameters, ControllerSettings, ControllerTelemetrySample,
+    HeatLevelHistory, SampleContext, HEAT_LEVEL_HISTORY_LENGTH, SAMPLE_INTERVAL,
+};
 use crate::{
     core::state::Event,
     core::{
-        state::{IsPowerOn, Mode},
-        util::FixedCapacityQueue,
+        state::{IsPowerOn, Mode, TemperatureMeasurement},
+        thermal_model::ThermalModel,
+        util,
     },
+    models::PredictiveModels,
 };
 
-pub trait Controller: Send + Sync {
-    fn sample(&mut self, boiler_temp: f32, grouphead_temp: f32, q: f32) -> f32;
-    fn update_target_temperature(&mut self, target_temp: f32);
-}
+// The number of boiler temperature readings kept for initialising a new controller.
+const BOILER_TEMP_HISTORY_LENGTH: usize = 10;
 
 pub struct ControllerManager {
     boiler_pin: u8,
@@ -32,17 +39,21 @@ pub struct ControllerManager {
     cancel_token: CancellationToken,
     tx: Sender<Event>,
     target_temperature: f32,
+    parameters: ControllerParameters,
     controller_handle: Option<JoinHandle<()>>,
     mode: Mode,
+    telemetry_interval: Duration,
+    models: Arc<PredictiveModels>,
+    thermal_model: Option<ThermalModel>,
+    predictive_fallback: ControlMethod,
 }
 
 impl ControllerManager {
     pub fn new(
         boiler_pin: u8,
-        control_method: &ControlMethod,
+        settings: ControllerSettings,
+        models: Arc<PredictiveModels>,
         tx: Sender<Event>,
-        target_temp: f32,
-        mode: Mode,
     ) -> Result<Self> {
         let mut output_pin = gpio::Gpio::new()?.get(boiler_pin)?.into_output();
 
@@ -54,12 +65,17 @@ impl ControllerManager {
 
         Ok(ControllerManager {
             boiler_pin,
-            control_method: control_method.clone(),
+            control_method: settings.control_method,
             cancel_token: CancellationToken::new(),
             tx,
-            target_temperature: target_temp,
+            target_temperature: settings.target_temperature,
+            parameters: settings.parameters,
             controller_handle: None,
-            mode,
+            mode: settings.mode,
+            telemetry_interval: settings.telemetry_interval,
+            models,
+            thermal_model: settings.thermal_model,
+            predictive_fallback: settings.predictive_fallback,
         })
     }
 
@@ -73,22 +89,37 @@ impl ControllerManager {
 
         let mut output_pin = gpio::Gpio::new()?.get(self.boiler_pin)?.into_output();
         let mut current_target_temperature = self.target_temperature.clone();
+        let mut current_control_method = self.control_method;
+        let mut parameters = self.parameters;
         let mut mode = self.mode.clone();
-        let mut controller: Option<Box<dyn Controller>> =
-            ControllerManager::get_controller(&self.control_method, current_target_temperature);
-
-        // The sample function is called at a 100ms interval, which is the precision with which we need to store heat_level records.
-        // The predictive model we're using has a maximum start_lag of 125 seconds, which is what we use for our look-back period.
-        // 50 seconds / 100ms = 500.
-        let mut q = FixedCapacityQueue::<u8>::new(500);
-        let mut current_boiler_temp: f32 = 0.0;
-        let mut current_grouphead_temp: f32 = 0.0;
+        let predictive_fallback = self.predictive_fallback;
+        let mut controller: Option<Box<dyn Controller>> = get_controller(
+            &current_control_method,
+            current_target_temperature,
+            &parameters,
+            &predictive_fallback,
+        );
+        let mut degradation: Option<ControllerDegradation> = None;
+
+        let mut heat_level_history = HeatLevelHistory::new(HEAT_LEVEL_HISTORY_LENGTH, SAMPLE_INTERVAL);
+        let mut current_measurement = TemperatureMeasurement {
+            boiler_temp: 0.0,
+            grouphead_temp: 0.0,
+            thermofilter_temp: None,
+            timestamp: SystemTime::now(),
+        };
+        let mut boiler_temp_history = VecDeque::<f32>::with_capacity(BOILER_TEMP_HISTORY_LENGTH);
         let mut power_state: IsPowerOn = true;
+        let telemetry_interval = self.telemetry_interval;
+        let mut last_telemetry_sent: Option<Instant> = None;
+        let mut models = self.models.clone();
+        let mut thermal_model = self.thermal_model.clone();
 
         let handle = task::spawn(async move {
             let mut current_duty_cycle: u8 = 0;
+            let mut last_sampled = Instant::now();
 
-            let mut interval = tokio::time::interval(Duration::from_millis(100));
+            let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
 
             loop {
                 select! {
@@ -100,26 +131,80 @@ impl ControllerManager {
                             current_duty_cycle = 0;
                         }
 
-                        if mode != Mode::Idle {
-                            if let Some(controller) = &mut controller {
-                                let duty_cycle = normalize_duty_cycle(controller.sample(current_boiler_temp, current_grouphead_temp, (q.sum as f32) / 10.0));
+                        if let Some(controller) = &mut controller {
+                            let context = SampleContext {
+                                timestamp: SystemTime::now(),
+                                measurement_timestamp: current_measurement.timestamp,
+                                dt: last_sampled.elapsed(),
+                                boiler_temp: current_measurement.boiler_temp,
+                                grouphead_temp: current_measurement.grouphead_temp,
+                                thermofilter_temp: current_measurement.thermofilter_temp,
+                                mode: &mode,
+                                power_state,
+                                heat_level_history: &heat_level_history,
+                                models: &models,
+                                thermal_model: thermal_model.as_ref(),
+                            };
+
+                            last_sampled = Instant::now();
+
+                            let heat_level = controller.sample(&context);
+                            let applied_heat_level = limit_heat_level(heat_level, &mode, power_state, current_measurement.boiler_temp);
 
-                                if current_duty_cycle != duty_cycle {
-                                    let (period, pulse_width) = duty_cycle_to_pulse_width(duty_cycle).unwrap();
-                                    info!("Duty Cycle: {}, Period: {:?}, Pulse Width: {:?}", duty_cycle, period, pulse_width);
-                                    output_pin.set_pwm(period, pulse_width).unwrap();
-                                    current_duty_cycle = duty_cycle;
-                                    boiler_state_changed = true;
+                            // The controller keeps being sampled while the output is limited,
+                            // so it needs to know what was applied to avoid winding up.
+                            controller.track_applied_heat_level(applied_heat_level);
+
+                            // Telemetry is throttled since it's only used for tuning,
+                            // and sending it on every sample is too much for the Pi Zero.
+                            let telemetry_due = !telemetry_interval.is_zero()
+                                && last_telemetry_sent.map_or(true, |sent| sent.elapsed() >= telemetry_interval);
+
+                            if telemetry_due {
+                                if let Some(telemetry) = controller.telemetry() {
+                                    if let Err(err) = tx.send(Event::ControllerTelemetryChanged(ControllerTelemetrySample {
+                                        time: util::get_unix_timestamp(current_measurement.timestamp).unwrap_or(0),
+                                        heat_level: applied_heat_level,
+                                        telemetry,
+                                    })) {
+                                        error!("Error sending controller telemetry: {}", err);
+                                    }
+
+                                    last_telemetry_sent = Some(Instant::now());
                                 }
                             }
-                        } else if current_duty_cycle == 0 && output_pin.is_set_high() {
+
+                            let duty_cycle = normalize_duty_cycle(applied_heat_level);
+
+                            if current_duty_cycle != duty_cycle {
+                                let (period, pulse_width) = duty_cycle_to_pulse_width(duty_cycle).unwrap();
+                                info!("Duty Cycle: {}, Period: {:?}, Pulse Width: {:?}", duty_cycle, period, pulse_width);
+                                output_pin.set_pwm(period, pulse_width).unwrap();
+                                current_duty_cycle = duty_cycle;
+                                boiler_state_changed = true;
+                            }
+                        } else if mode == Mode::Idle && current_duty_cycle == 0 && output_pin.is_set_high() {
                             output_pin.set_low();
                             current_duty_cycle = 0;
                             boiler_state_changed = true;
                             println!("Pin high state changed");
                         }
 
-                        q.push(current_duty_cycle);
+                        heat_level_history.push(current_duty_cycle as f32 / 10.0);
+
+                        let current_degradation = controller.as_ref().and_then(|controller| controller.degradation());
+
+                        if current_degradation != degradation {
+                            if let Some(current_degradation) = &current_degradation {
+                                error!("Controller degraded: {:?}", current_degradation);
+                            }
+
+                            degradation = current_degradation;
+
+                            if let Err(err) = tx.send(Event::ControllerDegradationChanged(degradation.clone())) {
+                                error!("Error sending controller degradation: {}", err);
+                            }
+                        }
 
                         if boiler_state_changed {
                             if let Err(err) = tx.send(Event::BoilerHeatLevelChanged(current_duty_cycle as f32 / 10.0)) {
@@ -132,11 +217,40 @@ impl ControllerManager {
                         match event {
                             Event::ControlMethodChanged(control_method) => {
                                 info!("Control method changed to {:?}", control_method);
-                                controller = ControllerManager::get_controller(&control_method, current_target_temperature);
+                                current_control_method = control_method;
+                                controller = get_controller(&current_control_method, current_target_temperature, &parameters, &predictive_fallback);
+
+                                if let Some(controller) = &mut controller {
+                                    controller.initialise(current_duty_cycle as f32 / 10.0, boiler_temp_history.make_contiguous());
+                                }
+                            }
+                            // The controller is replaced so that it's tuned with the new parameters,
+                            // carrying on from the current heat level.
+                            Event::ControllerParametersChanged(new_parameters) => {
+                                info!("Controller parameters changed to {:?}", new_parameters);
+                                parameters = new_parameters;
+                                controller = get_controller(&current_control_method, current_target_temperature, &parameters, &predictive_fallback);
+
+                                if let Some(controller) = &mut controller {
+                                    controller.initialise(current_duty_cycle as f32 / 10.0, boiler_temp_history.make_contiguous());
+                                }
                             }
                             Event::TemperatureChanged(temp) => {
-                                current_boiler_temp = temp.boiler_temp;
-                                current_grouphead_temp = temp.grouphead_temp;
+                                if boiler_temp_history.len() == BOILER_TEMP_HISTORY_LENGTH {
+                                    boiler_temp_history.pop_front();
+                                }
+
+                                boiler_temp_history.push_back(temp.boiler_temp);
+
+                                current_measurement = temp;
+                            }
+
+                            Event::ModelsChanged(new_models) => {
+                                models = new_models;
+                            }
+
+                            Event::ThermalModelChanged(new_thermal_model) => {
+                                thermal_model = Some(new_thermal_model);
                             }
 
                             Event::PowerStateChanged(new_power_state) => {
@@ -205,42 +319,11 @@ impl ControllerManager {
         Ok(())
     }
 
-    pub fn get_controller(
-        control_method: &ControlMethod,
-        target_temperature: f32,
-    ) -> Option<Box<dyn Controller>> {
-        match control_method {
-            ControlMethod::Threshold => {
-                Some(Box::new(ThresholdController::new(target_temperature)))
-            }
-            ControlMethod::PID => Some(Box::new(PidController::new(
-                45.0,
-                1.0,
-                60.0,
-                target_temperature,
-            ))),
-            ControlMethod::Predictive => {
-                Some(Box::new(PredictiveController::new(target_temperature)))
-            }
-            ControlMethod::None => None,
-        }
-    }
-
     pub fn set_target_temperature(&mut self, target_temperature: f32) {
         self.target_temperature = target_temperature;
     }
 }
 
-// Round the duty cycle to increments of 0.1
-// This is to avoid resetting the software PWM unnecessarily
-fn normalize_duty_cycle(duty_cycle: f32) -> u8 {
-    if duty_cycle < 0.0 || duty_cycle > 1.0 {
-        info!("Duty cycle out of range: {duty_cycle} (will return 0.0)");
-    }
-
-    return (duty_cycle * 10.0).round() as u8;
-}
-
 // The duty cycle is a percentage represented as 0.0 - 1.0, 0% and 100% respectively.
 fn duty_cycle_to_pulse_width(duty_cycle: u8) -> Result<(Duration, Duration)> {
     if duty_cycle > 10 {
@@ -263,20 +346,3 @@ fn duty_cycle_to_pulse_width(duty_cycle: u8) -> Result<(Duration, Duration)> {
 
     Ok((period_duration, pulse_width_duration))
 }
-
-#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
-pub enum ControlMethod {
-    // If the current temperature is < threshold, turn heat on, otherwise off.
-    #[serde(alias = "threshold", alias = "THRESHOLD")]
-    Threshold,
-
-    // https://en.wikipedia.org/wiki/PID_controller
-    #[serde(alias = "pid", alias = "Pid")]
-    PID,
-
-    #[serde(alias = "predictive", alias = "Predictive")]
-    Predictive,
-
-    #[serde(alias = "none")]
-    None,
-}
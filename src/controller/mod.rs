@@ -6,25 +6,30 @@ mod manager;
 #[path = "manager_stub.rs"]
 mod manager;
 
-// Conditional module definitions and re-exports
-#[cfg(all(target_arch = "arm", target_os = "linux"))]
-mod predictive;
-
-#[cfg(all(target_arch = "arm", target_os = "linux"))]
+mod context;
+mod parameters;
 mod pid;
-
-#[cfg(all(target_arch = "arm", target_os = "linux"))]
+mod predictive;
+mod sampling;
+mod settings;
+mod telemetry;
 mod threshold;
 
-// This section will only be compiled for ARM + Linux targets
-#[cfg(all(target_arch = "arm", target_os = "linux"))]
 pub(self) use {
-    self::pid::PidController,
-    manager::Controller,
-    predictive::PredictiveController,
-    threshold::ThresholdController,
+    self::pid::PidController, predictive::PredictiveController, threshold::ThresholdController,
 };
 
+pub(crate) use sampling::{
+    limit_heat_level, normalize_duty_cycle, HEAT_LEVEL_HISTORY_LENGTH, SAMPLE_INTERVAL,
+};
+
+// The boiler is never heated above this temperature, regardless of what the controller asks for.
+pub const MAX_BOILER_TEMP_C: f32 = 150.0;
+
 // These re-exports are always available
-pub use manager::ControlMethod;
+pub use context::{HeatLevelHistory, SampleContext};
 pub use manager::ControllerManager;
+pub use parameters::{ControllerParameters, PidParameters};
+pub use sampling::{get_controller, ControlMethod, Controller};
+pub use settings::ControllerSettings;
+pub use telemetry::{ControllerDegradation, ControllerTelemetry, ControllerTelemetrySample};
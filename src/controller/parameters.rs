@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+
+// Tuning for the controllers that have any, set by the active profile.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ControllerParameters {
+    pub pid: PidParameters,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PidParameters {
+    pub p: f32,
+    pub i: f32,
+    pub d: f32,
+}
+
+impl Default for PidParameters {
+    fn default() -> Self {
+        PidParameters {
+            p: 45.0,
+            i: 1.0,
+            d: 60.0,
+        }
+    }
+}
@@ -1,38 +1,202 @@
-use super::Controller;
+use super::{Controller, ControllerTelemetry, SampleContext};
 use log::info;
-use pid::Pid;
+
+// The PID output is bounded to -100..100 and mapped onto a heat level of 0.0..1.0.
+const OUTPUT_LIMIT: f32 = 100.0;
+
+// How strongly the integral term is pulled back towards the output that was actually applied
+// when the requested output could not be (back-calculation anti-windup).
+const ANTI_WINDUP_TRACKING_GAIN: f32 = 0.5;
 
 pub struct PidController {
     target_temperature: f32,
-    pid: Pid<f32>,
+    kp: f32,
+    ki: f32,
+    kd: f32,
+    integral: f32,
+    previous_boiler_temp: Option<f32>,
+    last_output: f32,
+    last_error: f32,
+    last_p: f32,
+    last_d: f32,
 }
 
 impl PidController {
     pub fn new(p: f32, i: f32, d: f32, target_temperature: f32) -> Self {
-        let mut pid = Pid::<f32>::new(target_temperature, 100.0);
-
-        pid.p(p, 100.0).i(i, 100.0).d(d, 100.0);
-
         info!("Created PID controller with target_temperature={:?}", target_temperature);
 
         PidController {
-            pid,
             target_temperature,
+            kp: p,
+            ki: i,
+            kd: d,
+            integral: 0.0,
+            previous_boiler_temp: None,
+            last_output: 0.0,
+            last_error: 0.0,
+            last_p: 0.0,
+            last_d: 0.0,
         }
     }
+
+    fn proportional(&self, boiler_temp: f32) -> f32 {
+        limit(self.kp * (self.target_temperature - boiler_temp))
+    }
 }
 
 impl Controller for PidController {
-    fn sample(&mut self, boiler_temp: f32, _grouphead_temp: f32, _q: f32) -> f32 {
-        let output = self.pid.next_control_output(boiler_temp);
+    fn sample(&mut self, context: &SampleContext) -> f32 {
+        let boiler_temp = context.boiler_temp;
+        let error = self.target_temperature - boiler_temp;
+
+        let p = self.proportional(boiler_temp);
 
-        let output = (output.output + 100.0) / 200.0;
-        output
+        self.integral = limit(self.integral + error * self.ki);
+
+        // The derivative is taken on the measurement rather than the error,
+        // so that changing the target temperature doesn't cause a kick.
+        let d = limit(match self.previous_boiler_temp {
+            Some(previous_boiler_temp) => -(boiler_temp - previous_boiler_temp) * self.kd,
+            None => 0.0,
+        });
+
+        self.previous_boiler_temp = Some(boiler_temp);
+
+        self.last_error = error;
+        self.last_p = p;
+        self.last_d = d;
+        self.last_output = limit(p + self.integral + d);
+
+        output_to_heat_level(self.last_output)
     }
 
     fn update_target_temperature(&mut self, target_temperature: f32) {
         self.target_temperature = target_temperature;
         info!("Updating target temperature to {}", target_temperature);
-        self.pid.setpoint(target_temperature);
+    }
+
+    fn initialise(&mut self, heat_level: f32, boiler_temp_history: &[f32]) {
+        let Some(boiler_temp) = boiler_temp_history.last() else {
+            return;
+        };
+
+        let output = heat_level_to_output(heat_level);
+
+        // Pick the integral term that makes the first sample reproduce the current heat level.
+        // The derivative term will be zero because the previous measurement is the current one.
+        self.integral = limit(output - self.proportional(*boiler_temp));
+        self.previous_boiler_temp = Some(*boiler_temp);
+        self.last_output = output;
+
+        info!(
+            "Initialised PID controller from heat_level={}, boiler_temp={}, integral={}",
+            heat_level, boiler_temp, self.integral
+        );
+    }
+
+    fn track_applied_heat_level(&mut self, heat_level: f32) {
+        let applied_output = heat_level_to_output(heat_level);
+
+        self.integral = limit(
+            self.integral + ANTI_WINDUP_TRACKING_GAIN * (applied_output - self.last_output),
+        );
+    }
+
+    fn telemetry(&self) -> Option<ControllerTelemetry> {
+        Some(ControllerTelemetry::Pid {
+            setpoint: self.target_temperature,
+            error: self.last_error,
+            p: self.last_p,
+            i: self.integral,
+            d: self.last_d,
+        })
+    }
+}
+
+fn limit(value: f32) -> f32 {
+    value.clamp(-OUTPUT_LIMIT, OUTPUT_LIMIT)
+}
+
+fn output_to_heat_level(output: f32) -> f32 {
+    (output + OUTPUT_LIMIT) / (OUTPUT_LIMIT * 2.0)
+}
+
+fn heat_level_to_output(heat_level: f32) -> f32 {
+    heat_level * OUTPUT_LIMIT * 2.0 - OUTPUT_LIMIT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::context::tests::{context, history};
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-4, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn proportional_output_is_mapped_to_a_heat_level() {
+        let history = history(&[]);
+        let mut controller = PidController::new(10.0, 0.0, 0.0, 95.0);
+
+        // 10 * 5 = 50, which is three quarters of the way from -100 to 100.
+        assert_close(controller.sample(&context(90.0, 80.0, &history)), 0.75);
+        assert_close(controller.sample(&context(95.0, 80.0, &history)), 0.5);
+        assert_close(controller.sample(&context(100.0, 80.0, &history)), 0.25);
+    }
+
+    #[test]
+    fn output_is_limited() {
+        let history = history(&[]);
+        let mut controller = PidController::new(10.0, 10.0, 0.0, 95.0);
+
+        assert_close(controller.sample(&context(20.0, 20.0, &history)), 1.0);
+        assert_close(controller.sample(&context(20.0, 20.0, &history)), 1.0);
+
+        // The integral is limited too, so it unwinds within a couple of samples.
+        assert_close(controller.sample(&context(150.0, 80.0, &history)), 0.0);
+        assert_close(controller.sample(&context(150.0, 80.0, &history)), 0.0);
+    }
+
+    #[test]
+    fn derivative_is_taken_on_the_measurement() {
+        let history = history(&[]);
+        let mut controller = PidController::new(0.0, 0.0, 10.0, 95.0);
+
+        assert_close(controller.sample(&context(90.0, 80.0, &history)), 0.5);
+        assert_close(controller.sample(&context(91.0, 80.0, &history)), 0.45);
+
+        // Changing the target doesn't kick the output.
+        controller.update_target_temperature(100.0);
+        assert_close(controller.sample(&context(91.0, 80.0, &history)), 0.5);
+    }
+
+    #[test]
+    fn initialise_reproduces_the_current_heat_level() {
+        let history = history(&[0.3]);
+        let mut controller = PidController::new(10.0, 0.5, 10.0, 95.0);
+
+        controller.initialise(0.3, &[94.0]);
+
+        // The integral grows by error * ki = 0.5 on the first sample.
+        assert_close(controller.sample(&context(94.0, 80.0, &history)), 0.3 + 0.5 / 200.0);
+    }
+
+    #[test]
+    fn applied_heat_level_pulls_the_integral_back() {
+        let history = history(&[]);
+        let mut controller = PidController::new(0.0, 10.0, 0.0, 95.0);
+
+        // The integral is 50 and so is the output.
+        assert_close(controller.sample(&context(90.0, 80.0, &history)), 0.75);
+
+        // Only half heat (output 0) was applied, so the integral moves halfway towards it.
+        controller.track_applied_heat_level(0.5);
+
+        let Some(ControllerTelemetry::Pid { i, .. }) = controller.telemetry() else {
+            panic!("Expected PID telemetry");
+        };
+
+        assert_close(i, 25.0);
     }
 }
@@ -1,51 +1,223 @@
+use std::time::Duration;
+
 use log::{error, info};
 
-use crate::{controller::Controller, models::PredictiveModels};
+use crate::controller::{
+    get_controller, ControlMethod, Controller, ControllerDegradation, ControllerParameters,
+    ControllerTelemetry, SampleContext,
+};
+
+// The window over which the heat level is summed to give the model's `q` input.
+const Q_WINDOW: Duration = Duration::from_secs(50);
+
+// After the model fails it needs to succeed for this many consecutive samples (5 seconds)
+// before it takes over from the fallback controller again, so the two don't flap.
+const RECOVERY_SAMPLES: u32 = 50;
+
+struct Fallback {
+    controller: Option<Box<dyn Controller>>,
+    reason: String,
+    successful_predictions: u32,
+}
 
 pub struct PredictiveController {
     target_temperature: f32,
-    model: PredictiveModels,
+    last_predicted_temp_diff: f32,
+    last_q: f32,
+    fallback_control_method: ControlMethod,
+    // Used to tune the fallback controller.
+    parameters: ControllerParameters,
+    fallback: Option<Fallback>,
 }
 
 impl PredictiveController {
-    pub fn new(target_temperature: f32) -> Self {
+    pub fn new(
+        target_temperature: f32,
+        fallback_control_method: ControlMethod,
+        parameters: ControllerParameters,
+    ) -> Self {
         PredictiveController {
             target_temperature,
-            model: PredictiveModels::new().unwrap(),
+            last_predicted_temp_diff: 0.0,
+            last_q: 0.0,
+            fallback_control_method,
+            parameters,
+            fallback: None,
         }
     }
 }
 
 impl Controller for PredictiveController {
-    fn sample(&mut self, boiler_temp_c: f32, grouphead_temp_c: f32, q: f32) -> f32 {
-        let predicted_temp_diff = self.model.predict_boiler_temp_diff(
-            grouphead_temp_c,
-            boiler_temp_c,
-            q,
-        );
+    fn sample(&mut self, context: &SampleContext) -> f32 {
+        let boiler_temp_c = context.boiler_temp;
+        let q = context.heat_level_history.sum(Q_WINDOW);
+
+        let predicted_temp_diff =
+            context
+                .models
+                .predict_boiler_temp_diff(context.grouphead_temp, boiler_temp_c, q);
 
-        if predicted_temp_diff.is_err() {
-            error!(
-                "Failed to predict boiler temperature difference: {:?}",
-                predicted_temp_diff
-            );
-            return 0.0;
+        match predicted_temp_diff {
+            Ok(predicted_temp_diff) => {
+                self.last_predicted_temp_diff = predicted_temp_diff;
+                self.last_q = q;
+
+                if let Some(fallback) = self.fallback.as_mut() {
+                    fallback.successful_predictions += 1;
+
+                    if fallback.successful_predictions >= RECOVERY_SAMPLES {
+                        info!("The predictive model has recovered, taking over from {:?}", self.fallback_control_method);
+                        self.fallback = None;
+                    }
+                }
+            }
+            Err(err) => match self.fallback.as_mut() {
+                Some(fallback) => {
+                    fallback.successful_predictions = 0;
+                }
+                None => {
+                    error!("Failed to predict boiler temperature difference, falling back to {:?}: {}", self.fallback_control_method, err);
+
+                    let mut controller = get_controller(
+                        &self.fallback_control_method,
+                        self.target_temperature,
+                        &self.parameters,
+                        &ControlMethod::None,
+                    );
+
+                    if let Some(controller) = &mut controller {
+                        controller.initialise(
+                            context.heat_level_history.latest().unwrap_or(0.0),
+                            &[boiler_temp_c],
+                        );
+                    }
+
+                    self.fallback = Some(Fallback {
+                        controller,
+                        reason: err.to_string(),
+                        successful_predictions: 0,
+                    });
+                }
+            },
         }
 
-        let predicted_temp_diff = predicted_temp_diff.unwrap();
+        if let Some(fallback) = self.fallback.as_mut() {
+            return fallback
+                .controller
+                .as_mut()
+                .map_or(0.0, |controller| controller.sample(context));
+        }
 
-        let heat_level = if boiler_temp_c + predicted_temp_diff > self.target_temperature {
+        let heat_level = if boiler_temp_c + self.last_predicted_temp_diff > self.target_temperature {
             0.0
         } else {
             1.0
         };
 
-        info!("Current: {}, Pred: {}, Q: {}, heat: {}", boiler_temp_c, predicted_temp_diff, q, heat_level);
+        info!("Current: {}, Pred: {}, Q: {}, heat: {}", boiler_temp_c, self.last_predicted_temp_diff, q, heat_level);
 
         heat_level
     }
 
     fn update_target_temperature(&mut self, target_temperature: f32) {
         self.target_temperature = target_temperature;
+
+        if let Some(controller) = self.fallback.as_mut().and_then(|fallback| fallback.controller.as_mut()) {
+            controller.update_target_temperature(target_temperature);
+        }
+    }
+
+    fn track_applied_heat_level(&mut self, heat_level: f32) {
+        if let Some(controller) = self.fallback.as_mut().and_then(|fallback| fallback.controller.as_mut()) {
+            controller.track_applied_heat_level(heat_level);
+        }
+    }
+
+    fn telemetry(&self) -> Option<ControllerTelemetry> {
+        if let Some(fallback) = self.fallback.as_ref() {
+            return fallback.controller.as_ref().and_then(|controller| controller.telemetry());
+        }
+
+        Some(ControllerTelemetry::Predictive {
+            setpoint: self.target_temperature,
+            predicted_delta: self.last_predicted_temp_diff,
+            q: self.last_q,
+        })
+    }
+
+    fn degradation(&self) -> Option<ControllerDegradation> {
+        self.fallback.as_ref().map(|fallback| ControllerDegradation {
+            control_method: ControlMethod::Predictive,
+            fallback_control_method: self.fallback_control_method,
+            reason: fallback.reason.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::context::tests::{context, history};
+
+    fn controller(target_temperature: f32) -> PredictiveController {
+        PredictiveController::new(target_temperature, ControlMethod::Threshold, ControllerParameters::default())
+    }
+
+    #[test]
+    fn heats_when_the_prediction_is_below_the_target() {
+        let history = history(&[0.0; 100]);
+        let mut controller = controller(93.0);
+
+        // The predicted change is at most 30°C, so these are either side of the target whatever the model says.
+        assert_eq!(controller.sample(&context(40.0, 30.0, &history)), 1.0);
+        assert_eq!(controller.sample(&context(130.0, 90.0, &history)), 0.0);
+        assert!(controller.degradation().is_none());
+    }
+
+    #[test]
+    fn q_is_the_heat_level_sum_over_the_window() {
+        // 60 seconds at half heat, then 40 seconds at full heat.
+        let mut heat_levels = vec![0.5; 600];
+        heat_levels.extend([1.0; 400]);
+        let history = history(&heat_levels);
+        let mut controller = controller(93.0);
+
+        controller.sample(&context(90.0, 80.0, &history));
+
+        let Some(ControllerTelemetry::Predictive { q, setpoint, .. }) = controller.telemetry() else {
+            panic!("Expected predictive telemetry");
+        };
+
+        assert_eq!(q, 400.0 + 50.0);
+        assert_eq!(setpoint, 93.0);
+    }
+
+    #[test]
+    fn falls_back_when_the_model_fails_and_recovers() {
+        let history = history(&[1.0]);
+        let mut controller = controller(93.0);
+
+        // A NaN input makes the model fail, the threshold controller takes over.
+        assert_eq!(controller.sample(&context(95.0, f32::NAN, &history)), 0.0);
+
+        let degradation = controller.degradation().unwrap();
+        assert_eq!(degradation.fallback_control_method, ControlMethod::Threshold);
+        assert!(controller.telemetry().is_none());
+
+        for _ in 1..RECOVERY_SAMPLES {
+            controller.sample(&context(95.0, 80.0, &history));
+            assert!(controller.degradation().is_some());
+        }
+
+        // Another failure restarts the recovery count.
+        controller.sample(&context(95.0, f32::NAN, &history));
+
+        for _ in 0..RECOVERY_SAMPLES {
+            assert!(controller.degradation().is_some());
+            controller.sample(&context(95.0, 80.0, &history));
+        }
+
+        assert!(controller.degradation().is_none());
+        assert!(matches!(controller.telemetry(), Some(ControllerTelemetry::Predictive { .. })));
     }
 }
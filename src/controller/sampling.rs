@@ -0,0 +1,117 @@
+// How the controllers are created and sampled, shared by the ControllerManager and the bench.
+// Nothing here touches the boiler, so it's available off the Pi.
+use std::time::Duration;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    ControllerDegradation, ControllerParameters, ControllerTelemetry, PidController,
+    PredictiveController, SampleContext, ThresholdController, MAX_BOILER_TEMP_C,
+};
+use crate::core::state::{IsPowerOn, Mode};
+
+pub trait Controller: Send + Sync {
+    fn sample(&mut self, context: &SampleContext) -> f32;
+    fn update_target_temperature(&mut self, target_temp: f32);
+
+    // Called when the controller takes over the boiler from another controller,
+    // so that it can carry on from the current heat level instead of starting from scratch.
+    fn initialise(&mut self, _heat_level: f32, _boiler_temp_history: &[f32]) {}
+
+    // Called after every sample with the heat level that was actually applied,
+    // which is lower than requested when the output is limited (e.g. in Idle mode).
+    fn track_applied_heat_level(&mut self, _heat_level: f32) {}
+
+    // The controller's internals as of the last sample, if it has any worth reporting.
+    fn telemetry(&self) -> Option<ControllerTelemetry> {
+        None
+    }
+
+    // Set while the controller has handed over to a fallback controller.
+    fn degradation(&self) -> Option<ControllerDegradation> {
+        None
+    }
+}
+
+// The controllers are sampled at a 100ms interval, which is also the precision of the heat level history.
+pub(crate) const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+// The predictive model we're using has a maximum start_lag of 125 seconds,
+// so that's how much heat level history is kept for the controllers to look back on.
+pub(crate) const HEAT_LEVEL_HISTORY_LENGTH: Duration = Duration::from_secs(125);
+
+// None when the boiler isn't to be heated at all.
+pub fn get_controller(
+    control_method: &ControlMethod,
+    target_temperature: f32,
+    parameters: &ControllerParameters,
+    predictive_fallback: &ControlMethod,
+) -> Option<Box<dyn Controller>> {
+    match control_method {
+        ControlMethod::Threshold => Some(Box::new(ThresholdController::new(target_temperature))),
+        ControlMethod::PID => Some(Box::new(PidController::new(
+            parameters.pid.p,
+            parameters.pid.i,
+            parameters.pid.d,
+            target_temperature,
+        ))),
+        ControlMethod::Predictive => {
+            // The predictive controller can't fall back to itself.
+            let fallback = if *predictive_fallback == ControlMethod::Predictive {
+                ControlMethod::Threshold
+            } else {
+                *predictive_fallback
+            };
+
+            Some(Box::new(PredictiveController::new(
+                target_temperature,
+                fallback,
+                *parameters,
+            )))
+        }
+        ControlMethod::None => None,
+    }
+}
+
+// The safety limiter - the boiler is only heated when the machine is on and not overheating,
+// and the heat level is kept within 0.0 - 1.0.
+pub(crate) fn limit_heat_level(
+    heat_level: f32,
+    mode: &Mode,
+    power_state: IsPowerOn,
+    boiler_temp: f32,
+) -> f32 {
+    if *mode == Mode::Idle || !power_state || boiler_temp >= MAX_BOILER_TEMP_C {
+        return 0.0;
+    }
+
+    heat_level.clamp(0.0, 1.0)
+}
+
+// Round the duty cycle to increments of 0.1
+// This is to avoid resetting the software PWM unnecessarily
+pub(crate) fn normalize_duty_cycle(duty_cycle: f32) -> u8 {
+    if duty_cycle < 0.0 || duty_cycle > 1.0 {
+        info!("Duty cycle out of range: {duty_cycle} (will return 0.0)");
+    }
+
+    return (duty_cycle * 10.0).round() as u8;
+}
+
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum ControlMethod {
+    // If the current temperature is < threshold, turn heat on, otherwise off.
+    #[serde(alias = "threshold", alias = "THRESHOLD")]
+    Threshold,
+
+    // https://en.wikipedia.org/wiki/PID_controller
+    #[serde(alias = "pid", alias = "Pid")]
+    PID,
+
+    #[serde(alias = "predictive", alias = "Predictive")]
+    Predictive,
+
+    #[serde(alias = "none")]
+    None,
+}
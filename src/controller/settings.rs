@@ -0,0 +1,18 @@
+use std::time::Duration;
+
+use super::{ControlMethod, ControllerParameters};
+use crate::core::{state::Mode, thermal_model::ThermalModel};
+
+// What the controller manager starts with, it's kept up to date with events once it's running.
+#[derive(Debug, Clone)]
+pub struct ControllerSettings {
+    pub control_method: ControlMethod,
+    pub target_temperature: f32,
+    pub parameters: ControllerParameters,
+    pub mode: Mode,
+    pub thermal_model: Option<ThermalModel>,
+    // Used by the predictive controller when its prediction can't be trusted.
+    pub predictive_fallback: ControlMethod,
+    // How often the controller's internals are published, zero disables telemetry.
+    pub telemetry_interval: Duration,
+}
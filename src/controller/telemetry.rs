@@ -0,0 +1,53 @@
+use serde::Serialize;
+
+use super::ControlMethod;
+
+// A snapshot of a controller's internals, used for tuning.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "controller", rename_all = "camelCase")]
+pub enum ControllerTelemetry {
+    #[serde(rename_all = "camelCase")]
+    Pid {
+        setpoint: f32,
+        error: f32,
+        p: f32,
+        i: f32,
+        d: f32,
+    },
+    #[serde(rename_all = "camelCase")]
+    Predictive {
+        setpoint: f32,
+        predicted_delta: f32,
+        q: f32,
+    },
+}
+
+impl ControllerTelemetry {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControllerTelemetry::Pid { .. } => "pid",
+            ControllerTelemetry::Predictive { .. } => "predictive",
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ControllerTelemetrySample {
+    // The time of the measurement the controller was sampled with,
+    // so that telemetry can be joined onto the measurement table.
+    pub time: i64,
+    pub heat_level: f32,
+    #[serde(flatten)]
+    pub telemetry: ControllerTelemetry,
+}
+
+// A controller that can't work as intended and has handed over to a simpler one,
+// e.g. the predictive controller when its model fails.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ControllerDegradation {
+    pub control_method: ControlMethod,
+    pub fallback_control_method: ControlMethod,
+    pub reason: String,
+}
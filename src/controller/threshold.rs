@@ -1,4 +1,4 @@
-use super::Controller;
+use super::{Controller, SampleContext};
 
 pub struct ThresholdController {
     target_temperature: f32,
@@ -11,8 +11,8 @@ impl ThresholdController {
 }
 
 impl Controller for ThresholdController {
-    fn sample(&mut self, boiler_temp: f32, _grouphead_temp: f32, _q: f32) -> f32 {
-        if boiler_temp < self.target_temperature {
+    fn sample(&mut self, context: &SampleContext) -> f32 {
+        if context.boiler_temp < self.target_temperature {
             1.0
         } else {
             0.0
@@ -23,3 +23,29 @@ impl Controller for ThresholdController {
         self.target_temperature = target_temp;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::context::tests::{context, history};
+
+    #[test]
+    fn heats_below_the_target() {
+        let history = history(&[]);
+        let mut controller = ThresholdController::new(93.0);
+
+        assert_eq!(controller.sample(&context(92.9, 80.0, &history)), 1.0);
+        assert_eq!(controller.sample(&context(93.0, 80.0, &history)), 0.0);
+        assert_eq!(controller.sample(&context(95.0, 80.0, &history)), 0.0);
+    }
+
+    #[test]
+    fn follows_the_target_temperature() {
+        let history = history(&[]);
+        let mut controller = ThresholdController::new(93.0);
+
+        controller.update_target_temperature(96.0);
+
+        assert_eq!(controller.sample(&context(95.0, 80.0, &history)), 1.0);
+    }
+}
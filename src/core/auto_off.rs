@@ -0,0 +1,141 @@
+use serde::Serialize;
+
+use super::config::AutoOffConfig;
+
+pub enum AutoOffEvent {
+    Warning(AutoOffWarning),
+    // The warning no longer applies, because there was some activity or the machine was turned off.
+    Cleared,
+    TimedOut,
+}
+
+// Published on gesha/auto_off/warning before the machine is turned off, null once the timer is reset.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AutoOffWarning {
+    // When the machine will be turned off unless there's some activity.
+    pub off_at: i64,
+}
+
+// Turns the machine off after a period without shots, steaming or commands.
+pub struct AutoOffTimer {
+    timeout_ms: i64,
+    warning_ms: i64,
+    last_activity: i64,
+    warned: bool,
+}
+
+impl AutoOffTimer {
+    // None when the timeout is 0, which disables auto off.
+    pub fn new(config: &AutoOffConfig, time: i64) -> Option<Self> {
+        (config.timeout_s > 0).then(|| AutoOffTimer {
+            timeout_ms: config.timeout_s as i64 * 1000,
+            warning_ms: config.warning_s as i64 * 1000,
+            last_activity: time,
+            warned: false,
+        })
+    }
+
+    pub fn reset(&mut self, time: i64) {
+        self.last_activity = time;
+    }
+
+    // The timer only runs while `is_running`, e.g. not while brewing or when the machine is off.
+    pub fn update(&mut self, time: i64, is_running: bool) -> Option<AutoOffEvent> {
+        if !is_running {
+            self.reset(time);
+        }
+
+        let off_at = self.last_activity + self.timeout_ms;
+
+        if time >= off_at {
+            // The timer starts again in case the transition to idle is rejected.
+            self.reset(time);
+            self.warned = false;
+
+            return Some(AutoOffEvent::TimedOut);
+        }
+
+        let is_warning = time >= off_at - self.warning_ms;
+
+        if is_warning == self.warned {
+            return None;
+        }
+
+        self.warned = is_warning;
+
+        Some(if is_warning {
+            AutoOffEvent::Warning(AutoOffWarning { off_at })
+        } else {
+            AutoOffEvent::Cleared
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timer() -> AutoOffTimer {
+        AutoOffTimer::new(
+            &AutoOffConfig {
+                timeout_s: 600,
+                warning_s: 120,
+            },
+            0,
+        )
+        .unwrap()
+    }
+
+    fn is_warning(event: &Option<AutoOffEvent>, expected_off_at: i64) -> bool {
+        matches!(event, Some(AutoOffEvent::Warning(AutoOffWarning { off_at })) if *off_at == expected_off_at)
+    }
+
+    #[test]
+    fn warns_before_the_timeout_and_then_times_out() {
+        let mut timer = timer();
+
+        assert!(timer.update(479_999, true).is_none());
+        assert!(is_warning(&timer.update(480_000, true), 600_000));
+        assert!(timer.update(540_000, true).is_none());
+        assert!(matches!(timer.update(600_000, true), Some(AutoOffEvent::TimedOut)));
+
+        // The timer starts over in case turning off is rejected.
+        assert!(timer.update(600_100, true).is_none());
+        assert!(is_warning(&timer.update(1_080_000, true), 1_200_000));
+    }
+
+    #[test]
+    fn activity_postpones_the_timeout() {
+        let mut timer = timer();
+
+        assert!(is_warning(&timer.update(480_000, true), 600_000));
+
+        timer.reset(500_000);
+
+        assert!(matches!(timer.update(500_100, true), Some(AutoOffEvent::Cleared)));
+        assert!(timer.update(700_000, true).is_none());
+        assert!(is_warning(&timer.update(980_000, true), 1_100_000));
+    }
+
+    #[test]
+    fn the_timer_only_runs_while_the_machine_is_running() {
+        let mut timer = timer();
+
+        assert!(timer.update(1_000_000, false).is_none());
+        assert!(timer.update(1_479_999, true).is_none());
+        assert!(is_warning(&timer.update(1_480_000, true), 1_600_000));
+        assert!(matches!(timer.update(1_500_000, false), Some(AutoOffEvent::Cleared)));
+    }
+
+    #[test]
+    fn a_timeout_of_zero_disables_auto_off() {
+        let config = AutoOffConfig {
+            timeout_s: 0,
+            ..Default::default()
+        };
+
+        assert!(AutoOffTimer::new(&config, 0).is_none());
+        assert!(AutoOffTimer::new(&AutoOffConfig::default(), 0).is_none());
+    }
+}
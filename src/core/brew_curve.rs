@@ -0,0 +1,245 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use super::config::TargetBounds;
+
+// Setpoints are rounded to this many °C, so a ramp doesn't change the target on every sample.
+const SETPOINT_RESOLUTION: f32 = 0.1;
+
+// A brew temperature that changes over the shot, e.g. starting at 93°C and ramping down to 90°C over 25 seconds.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BrewCurve {
+    pub interpolation: Interpolation,
+    // In order of time, the first point's target is held before it and the last's after it.
+    pub points: Vec<CurvePoint>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CurvePoint {
+    // Seconds since the start of the shot.
+    pub time_s: f32,
+    pub target_temp: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Interpolation {
+    // The target ramps between points.
+    Linear,
+    // The target changes to each point's target when it's reached.
+    Step,
+}
+
+impl BrewCurve {
+    pub fn validate(&self, bounds: &TargetBounds) -> Result<()> {
+        if self.points.is_empty() {
+            return Err(anyhow!("The brew curve needs at least one point"));
+        }
+
+        for (index, point) in self.points.iter().enumerate() {
+            if point.time_s.is_nan() || point.time_s < 0.0 {
+                return Err(anyhow!("The brew curve's times can't be negative"));
+            }
+
+            if index > 0 && point.time_s <= self.points[index - 1].time_s {
+                return Err(anyhow!("The brew curve's points must be in order of time"));
+            }
+
+            if !bounds.contains(point.target_temp) {
+                return Err(anyhow!(
+                    "The brew curve's target {}°C must be between {}°C and {}°C",
+                    point.target_temp,
+                    bounds.min,
+                    bounds.max
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn target_at(&self, elapsed_s: f32) -> f32 {
+        let next_index = self
+            .points
+            .iter()
+            .position(|point| point.time_s > elapsed_s)
+            .unwrap_or(self.points.len());
+
+        if next_index == 0 {
+            return self.points[0].target_temp;
+        }
+
+        let previous = &self.points[next_index - 1];
+
+        let Some(next) = self.points.get(next_index) else {
+            return previous.target_temp;
+        };
+
+        match self.interpolation {
+            Interpolation::Step => previous.target_temp,
+            Interpolation::Linear => {
+                let progress = (elapsed_s - previous.time_s) / (next.time_s - previous.time_s);
+
+                previous.target_temp + (next.target_temp - previous.target_temp) * progress
+            }
+        }
+    }
+}
+
+// A setpoint that was sent to the controller during a shot, stored in the shot_setpoint table.
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ShotSetpoint {
+    pub time: i64,
+    pub target_temp_c: f32,
+}
+
+// Follows a curve from the start of a shot, keeping the setpoints it ran for the shot's record.
+pub struct BrewCurveRun {
+    curve: BrewCurve,
+    start_time: i64,
+    setpoint: Option<f32>,
+    trace: Vec<ShotSetpoint>,
+}
+
+impl BrewCurveRun {
+    pub fn new(curve: BrewCurve, start_time: i64) -> Self {
+        BrewCurveRun {
+            curve,
+            start_time,
+            setpoint: None,
+            trace: vec![],
+        }
+    }
+
+    pub fn setpoint(&self) -> Option<f32> {
+        self.setpoint
+    }
+
+    // Returns the new setpoint when it's changed.
+    pub fn update(&mut self, time: i64) -> Option<f32> {
+        let elapsed_s = (time - self.start_time).max(0) as f32 / 1000.0;
+        let target =
+            (self.curve.target_at(elapsed_s) / SETPOINT_RESOLUTION).round() * SETPOINT_RESOLUTION;
+
+        if self.setpoint == Some(target) {
+            return None;
+        }
+
+        self.setpoint = Some(target);
+        self.trace.push(ShotSetpoint {
+            time,
+            target_temp_c: target,
+        });
+
+        Some(target)
+    }
+
+    pub fn into_trace(self) -> Vec<ShotSetpoint> {
+        self.trace
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve(interpolation: Interpolation) -> BrewCurve {
+        BrewCurve {
+            interpolation,
+            points: vec![
+                CurvePoint {
+                    time_s: 5.0,
+                    target_temp: 93.0,
+                },
+                CurvePoint {
+                    time_s: 15.0,
+                    target_temp: 90.0,
+                },
+                CurvePoint {
+                    time_s: 25.0,
+                    target_temp: 91.0,
+                },
+            ],
+        }
+    }
+
+    fn bounds() -> TargetBounds {
+        TargetBounds {
+            min: 80.0,
+            max: 100.0,
+        }
+    }
+
+    #[test]
+    fn step_holds_each_target_until_the_next_point() {
+        let curve = curve(Interpolation::Step);
+
+        assert_eq!(curve.target_at(5.0), 93.0);
+        assert_eq!(curve.target_at(14.9), 93.0);
+        assert_eq!(curve.target_at(15.0), 90.0);
+        assert_eq!(curve.target_at(24.0), 90.0);
+    }
+
+    #[test]
+    fn linear_ramps_between_points() {
+        let curve = curve(Interpolation::Linear);
+
+        assert_eq!(curve.target_at(5.0), 93.0);
+        assert_eq!(curve.target_at(10.0), 91.5);
+        assert_eq!(curve.target_at(15.0), 90.0);
+        assert_eq!(curve.target_at(17.5), 90.25);
+    }
+
+    #[test]
+    fn holds_the_first_and_last_targets() {
+        for interpolation in [Interpolation::Linear, Interpolation::Step] {
+            let curve = curve(interpolation);
+
+            assert_eq!(curve.target_at(0.0), 93.0);
+            assert_eq!(curve.target_at(4.9), 93.0);
+            assert_eq!(curve.target_at(25.0), 91.0);
+            assert_eq!(curve.target_at(120.0), 91.0);
+        }
+    }
+
+    #[test]
+    fn validates_points() {
+        assert!(curve(Interpolation::Linear).validate(&bounds()).is_ok());
+
+        let with_point = |index: usize, time_s: f32, target_temp: f32| {
+            let mut curve = curve(Interpolation::Linear);
+            curve.points[index] = CurvePoint { time_s, target_temp };
+            curve.validate(&bounds())
+        };
+
+        assert!(with_point(0, -1.0, 93.0).is_err());
+        assert!(with_point(0, f32::NAN, 93.0).is_err());
+        assert!(with_point(1, 5.0, 90.0).is_err());
+        assert!(with_point(2, 25.0, 101.0).is_err());
+
+        let empty = BrewCurve {
+            interpolation: Interpolation::Step,
+            points: vec![],
+        };
+
+        assert!(empty.validate(&bounds()).is_err());
+    }
+
+    #[test]
+    fn run_only_reports_changed_setpoints() {
+        let mut run = BrewCurveRun::new(curve(Interpolation::Linear), 1_000);
+
+        assert_eq!(run.update(1_000), Some(93.0));
+        assert_eq!(run.update(1_100), None);
+        assert_eq!(run.update(11_000), Some(91.5));
+        assert_eq!(run.setpoint(), Some(91.5));
+
+        let trace = run.into_trace();
+
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace[1].time, 11_000);
+    }
+}
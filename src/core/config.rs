@@ -4,6 +4,10 @@ use log::error;
 use serde::{Deserialize, Serialize};
 use std::io::{self, ErrorKind};
 
+use crate::controller::ControlMethod;
+
+use super::db::DB_PATH;
+
 const CONFIG_NAMES: [&str; 2] = ["gesha.config.yaml", "gesha.config.yml"];
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -13,7 +17,306 @@ pub struct Config {
     pub grouphead_spi: Option<Spi>,
     pub thermofilter_spi: Option<Spi>,
     pub mqtt_url: Option<String>,
+    // Every topic is published under this prefix, e.g. gesha/mode.
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub mqtt_topic_prefix: String,
+    #[serde(default)]
+    pub home_assistant: HomeAssistantConfig,
+    pub boiler_pin: Option<u8>,
+    // Defaults to DB_PATH.
+    pub db_path: Option<String>,
+    #[serde(default)]
+    pub controller_telemetry: ControllerTelemetryConfig,
+    #[serde(default)]
+    pub models: ModelsConfig,
+    #[serde(default)]
+    pub thermal_model: ThermalModelConfig,
+    #[serde(default)]
+    pub shot_detection: ShotDetectionConfig,
+    #[serde(default)]
+    pub predictive: PredictiveConfig,
+    #[serde(default)]
+    pub auto_off: AutoOffConfig,
+    #[serde(default)]
+    pub shot_timer: ShotTimerConfig,
+    #[serde(default)]
+    pub targets: TargetTemperatureConfig,
+    #[serde(default)]
+    pub power_relay: PowerRelayConfig,
+    #[serde(default)]
+    pub relay_commands: RelayCommandConfig,
+    // The machines run by this instance, if there's more than one.
+    // Each has its own sensors, heater, relay and DB, and everything else is shared.
+    #[serde(default)]
+    pub machines: Vec<MachineConfig>,
+    // Set on the config for each machine, see Config::machines.
+    #[serde(skip)]
+    pub machine_id: Option<String>,
+}
+
+fn default_mqtt_topic_prefix() -> String {
+    String::from("gesha")
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HomeAssistantConfig {
+    // Whether the discovery configs are published, so that the machine shows up in Home Assistant.
+    // Off by default, the configs are retained and add the machine to any Home Assistant using the broker.
+    pub discovery: bool,
+    // Home Assistant's discovery prefix.
+    pub discovery_prefix: String,
+}
+
+impl Default for HomeAssistantConfig {
+    fn default() -> Self {
+        HomeAssistantConfig {
+            discovery: false,
+            discovery_prefix: String::from("homeassistant"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MachineConfig {
+    // Used in the machine's topics, {mqttTopicPrefix}/{id}/...
+    pub id: String,
+    pub boiler_spi: Option<Spi>,
+    pub grouphead_spi: Option<Spi>,
+    pub thermofilter_spi: Option<Spi>,
     pub boiler_pin: u8,
+    pub power_relay: PowerRelayConfig,
+    // Defaults to gesha-{id}.db next to DB_PATH.
+    pub db_path: Option<String>,
+}
+
+// Paths to ONNX models that replace the ones embedded in the binary.
+// The embedded model is used for any path that isn't set or fails to load.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ModelsConfig {
+    pub extraction_temperature_path: Option<String>,
+    pub boiler_temp_diff_path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ControllerTelemetryConfig {
+    // How often the controller's internals are published, 0 disables telemetry.
+    pub interval_ms: u64,
+    // Whether telemetry is also written to the controller_telemetry table.
+    pub persist: bool,
+}
+
+impl Default for ControllerTelemetryConfig {
+    fn default() -> Self {
+        ControllerTelemetryConfig {
+            interval_ms: 1_000,
+            persist: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PredictiveConfig {
+    // The controller that takes over when the predictive model fails.
+    pub fallback_control_method: ControlMethod,
+}
+
+impl Default for PredictiveConfig {
+    fn default() -> Self {
+        PredictiveConfig {
+            fallback_control_method: ControlMethod::PID,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ThermalModelConfig {
+    // How often the boiler's thermal model is estimated from the measurements, published,
+    // and written to the thermal_model table. 0 disables estimation.
+    pub interval_s: u64,
+    // How much weight each 10 second window keeps relative to the next,
+    // lower values track changes faster but are noisier. 0.995 remembers roughly the last half hour.
+    pub forgetting_factor: f64,
+}
+
+impl Default for ThermalModelConfig {
+    fn default() -> Self {
+        ThermalModelConfig {
+            interval_s: 60,
+            forgetting_factor: 0.995,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ShotDetectionConfig {
+    // Off until it's been checked against the machine, see "Shot detection" in the README.
+    pub enabled: bool,
+    // The rate of boiler temperature drop in °C/s that's considered the start of a shot,
+    // lower values detect more shots but also more false ones.
+    pub sensitivity: f32,
+    // Detected shots with a lower confidence (0.0 - 1.0) are discarded.
+    pub min_confidence: f32,
+    // Whether a detected shot switches the machine into brew mode, as if it had been requested.
+    pub auto_brew: bool,
+}
+
+impl Default for ShotDetectionConfig {
+    fn default() -> Self {
+        ShotDetectionConfig {
+            enabled: false,
+            sensitivity: 0.3,
+            min_confidence: 0.5,
+            auto_brew: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AutoOffConfig {
+    // How long the machine is left on without a shot, steaming or a command before it's turned off, 0 disables it.
+    // Standby is meant to be left on, so it's not timed.
+    pub timeout_s: u64,
+    // How long before turning off a warning is published on gesha/auto_off/warning, should be less than the timeout.
+    pub warning_s: u64,
+}
+
+impl Default for AutoOffConfig {
+    fn default() -> Self {
+        AutoOffConfig {
+            timeout_s: 0,
+            warning_s: 300,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ShotTimerConfig {
+    // The shot time to aim for, published with the timer.
+    pub target_s: u64,
+    // Shots are ended by returning to active mode after this long, 0 doesn't limit them.
+    pub max_s: u64,
+    // A GPIO pin that's set high to cut the pump when a shot is ended at the maximum.
+    pub pump_off_pin: Option<u8>,
+    // How long the pump is kept off for, giving time to turn off the brew switch.
+    pub pump_off_hold_s: u64,
+}
+
+impl Default for ShotTimerConfig {
+    fn default() -> Self {
+        ShotTimerConfig {
+            target_s: 28,
+            max_s: 60,
+            pump_off_pin: None,
+            pump_off_hold_s: 10,
+        }
+    }
+}
+
+// The relay that switches the machine's power, selected with driver, e.g.
+// powerRelay: { driver: tasmota, topic: silvia }
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "driver", rename_all = "camelCase")]
+pub enum PowerRelayConfig {
+    // An ESPHome switch, published on {device}/switch/{switch}/...
+    Esphome { device: String, switch: String },
+    // A Shelly Gen2 device, topicPrefix is the MQTT prefix configured on the device.
+    #[serde(rename_all = "camelCase")]
+    Shelly {
+        topic_prefix: String,
+        #[serde(default)]
+        switch_id: u8,
+    },
+    // A Tasmota device, relay is only needed for devices with more than one relay.
+    Tasmota {
+        topic: String,
+        #[serde(default)]
+        relay: Option<u8>,
+    },
+    // A relay wired to a GPIO pin, activeLow for relay boards that switch on when the pin is low.
+    #[serde(rename_all = "camelCase")]
+    Gpio {
+        pin: u8,
+        #[serde(default)]
+        active_low: bool,
+    },
+}
+
+impl Default for PowerRelayConfig {
+    fn default() -> Self {
+        PowerRelayConfig::Esphome {
+            device: String::from("ms-silvia-switch"),
+            switch: String::from("power"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RelayCommandConfig {
+    // How long to wait for the relay to confirm a command, doubled for each retry.
+    pub timeout_ms: u64,
+    // How many times a command is resent before the mode is rolled back.
+    pub retries: u32,
+}
+
+impl Default for RelayCommandConfig {
+    fn default() -> Self {
+        RelayCommandConfig {
+            timeout_ms: 3_000,
+            retries: 3,
+        }
+    }
+}
+
+// The range of target temperatures that can be set for each mode, in °C.
+// Requests outside of the range are rejected on gesha/temperature/target/rejected.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TargetTemperatureConfig {
+    pub brew: TargetBounds,
+    pub steam: TargetBounds,
+    pub standby: TargetBounds,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct TargetBounds {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl TargetBounds {
+    // NaN is never in range.
+    pub fn contains(&self, temperature: f32) -> bool {
+        temperature >= self.min && temperature <= self.max
+    }
+}
+
+impl Default for TargetTemperatureConfig {
+    fn default() -> Self {
+        TargetTemperatureConfig {
+            brew: TargetBounds {
+                min: 80.0,
+                max: 105.0,
+            },
+            steam: TargetBounds {
+                min: 110.0,
+                max: 145.0,
+            },
+            standby: TargetBounds {
+                min: 40.0,
+                max: 95.0,
+            },
+        }
+    }
 }
 
 impl Config {
@@ -41,6 +344,54 @@ impl Config {
             config_paths
         ))
     }
+
+    // The config for each machine, which is the top level config when no machines are listed.
+    pub fn machines(&self) -> Result<Vec<Config>> {
+        if self.mqtt_topic_prefix.is_empty() || self.mqtt_topic_prefix.contains(['+', '#']) {
+            return Err(anyhow!(
+                "{:?} can't be used as the MQTT topic prefix",
+                self.mqtt_topic_prefix
+            ));
+        }
+
+        if self.machines.is_empty() {
+            return Ok(vec![self.clone()]);
+        }
+
+        let mut configs: Vec<Config> = vec![];
+
+        for machine in self.machines.iter() {
+            if machine.id.is_empty() || machine.id.contains(['/', '+', '#']) {
+                return Err(anyhow!(
+                    "{:?} can't be used as a machine ID in MQTT topics",
+                    machine.id
+                ));
+            }
+
+            if configs
+                .iter()
+                .any(|config| config.machine_id.as_ref() == Some(&machine.id))
+            {
+                return Err(anyhow!("There's more than one machine with the ID {}", machine.id));
+            }
+
+            configs.push(Config {
+                boiler_spi: machine.boiler_spi,
+                grouphead_spi: machine.grouphead_spi,
+                thermofilter_spi: machine.thermofilter_spi,
+                boiler_pin: Some(machine.boiler_pin),
+                db_path: Some(machine.db_path.clone().unwrap_or_else(|| {
+                    DB_PATH.replace("gesha.db", &format!("gesha-{}.db", machine.id))
+                })),
+                power_relay: machine.power_relay.clone(),
+                machines: vec![],
+                machine_id: Some(machine.id.clone()),
+                ..self.clone()
+            });
+        }
+
+        Ok(configs)
+    }
 }
 
 #[derive(Clone, Copy, Serialize, Deserialize, Debug)]
@@ -73,3 +424,87 @@ impl ValueEnum for Spi {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> Config {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn a_single_machine_uses_the_top_level_config() {
+        let machines = config("boilerPin: 26\ndbPath: /tmp/gesha.db").machines().unwrap();
+
+        let [machine] = machines.as_slice() else {
+            panic!("Expected one machine, got {}", machines.len());
+        };
+
+        assert_eq!(machine.machine_id, None);
+        assert_eq!(machine.boiler_pin, Some(26));
+        assert_eq!(machine.db_path.as_deref(), Some("/tmp/gesha.db"));
+        assert_eq!(machine.mqtt_topic_prefix, "gesha");
+    }
+
+    #[test]
+    fn machines_get_their_own_db_and_share_the_rest() {
+        let machines = config(
+            r#"
+mqttTopicPrefix: kitchen
+machines:
+  - id: silvia
+    boilerPin: 26
+    powerRelay: { driver: tasmota, topic: silvia }
+  - id: gaggia
+    boilerPin: 27
+    powerRelay: { driver: gpio, pin: 5 }
+    dbPath: /tmp/gaggia.db
+"#,
+        )
+        .machines()
+        .unwrap();
+
+        let table = [
+            ("silvia", 26, "/opt/gesha/var/db/gesha-silvia.db"),
+            ("gaggia", 27, "/tmp/gaggia.db"),
+        ];
+
+        assert_eq!(machines.len(), table.len());
+
+        for (machine, (id, boiler_pin, db_path)) in machines.iter().zip(table) {
+            assert_eq!(machine.machine_id.as_deref(), Some(id));
+            assert_eq!(machine.boiler_pin, Some(boiler_pin));
+            assert_eq!(machine.db_path.as_deref(), Some(db_path));
+            assert_eq!(machine.mqtt_topic_prefix, "kitchen");
+            assert!(machine.machines.is_empty());
+        }
+
+        assert!(matches!(&machines[0].power_relay, PowerRelayConfig::Tasmota { topic, .. } if topic == "silvia"));
+        assert!(matches!(machines[1].power_relay, PowerRelayConfig::Gpio { pin: 5, .. }));
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let machine = |id: &str| format!("  - {{ id: {id:?}, boilerPin: 26, powerRelay: {{ driver: gpio, pin: 5 }} }}\n");
+
+        let table = [
+            ("mqttTopicPrefix: \"\"".to_string(), "can't be used as the MQTT topic prefix"),
+            ("mqttTopicPrefix: gesha/+".to_string(), "can't be used as the MQTT topic prefix"),
+            ("mqttTopicPrefix: gesha/#".to_string(), "can't be used as the MQTT topic prefix"),
+            (format!("machines:\n{}", machine("")), "can't be used as a machine ID"),
+            (format!("machines:\n{}", machine("a/b")), "can't be used as a machine ID"),
+            (format!("machines:\n{}", machine("a+")), "can't be used as a machine ID"),
+            (
+                format!("machines:\n{}{}", machine("silvia"), machine("silvia")),
+                "more than one machine with the ID silvia",
+            ),
+        ];
+
+        for (yaml, error) in table {
+            let err = config(&yaml).machines().unwrap_err();
+
+            assert!(err.to_string().contains(error), "{yaml}: {err}");
+        }
+    }
+}
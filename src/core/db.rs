@@ -13,11 +13,24 @@ use sqlx::{migrate, query, query_as, Pool, QueryBuilder, Sqlite, SqlitePool};
 use tokio::{select, sync::RwLock, time};
 use tokio_util::sync::CancellationToken;
 
+use crate::controller::{ControlMethod, ControllerTelemetry, ControllerTelemetrySample};
+
+use super::{
+    brew_curve::ShotSetpoint,
+    eta::EtaRecord,
+    event_log::EventLogEntry,
+    profile::{Profile, ProfileSettings},
+    schedule::{NewSchedule, Schedule},
+    state::Mode,
+    thermal_model::ThermalModel,
+};
+
 use super::mqtt::Range;
 
 pub struct Db {
     handle: Pool<Sqlite>,
     measurement_write_queue: Arc<RwLock<VecDeque<Measurement>>>,
+    controller_telemetry_write_queue: Arc<RwLock<VecDeque<ControllerTelemetrySample>>>,
     measurement_interval_cancel: CancellationToken,
     measurement_interval_handle: Option<tokio::task::JoinHandle<()>>,
 }
@@ -37,6 +50,7 @@ impl Db {
         Ok(Db {
             handle: pool,
             measurement_write_queue: Arc::new(RwLock::new(VecDeque::new())),
+            controller_telemetry_write_queue: Arc::new(RwLock::new(VecDeque::new())),
             measurement_interval_cancel: CancellationToken::new(),
             measurement_interval_handle: None,
         })
@@ -74,6 +88,27 @@ impl Db {
         Ok(())
     }
 
+    // Either all of the items are written or none are.
+    pub async fn write_configs(&self, config_items: &[ConfigItem]) -> Result<()> {
+        let mut transaction = self.handle.begin().await?;
+
+        for config_item in config_items {
+            query("INSERT INTO config VALUES (?1, ?2) ON CONFLICT(key) DO UPDATE SET value = ?2 WHERE key = ?1")
+                .bind(&config_item.key)
+                .bind(&config_item.value)
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        transaction.commit().await?;
+
+        for config_item in config_items {
+            info!("Wrote {}={} to the DB", config_item.key, config_item.value);
+        }
+
+        Ok(())
+    }
+
     pub async fn write_measurement_queue(&mut self, measurement: Measurement) -> Result<()> {
         let mut queue = self.measurement_write_queue.write().await;
         queue.push_back(measurement);
@@ -81,8 +116,19 @@ impl Db {
         Ok(())
     }
 
+    pub async fn write_controller_telemetry_queue(
+        &mut self,
+        sample: ControllerTelemetrySample,
+    ) -> Result<()> {
+        let mut queue = self.controller_telemetry_write_queue.write().await;
+        queue.push_back(sample);
+
+        Ok(())
+    }
+
     pub fn start_measurement_writer_interval(&mut self, duration: Duration) {
         let queue = self.measurement_write_queue.clone();
+        let telemetry_queue = self.controller_telemetry_write_queue.clone();
         let handle = self.handle.clone();
 
         let abort = self.measurement_interval_cancel.clone();
@@ -109,14 +155,31 @@ impl Db {
                 }
             }
 
+            async fn drain_controller_telemetry(
+                handle: &Pool<Sqlite>,
+                queue: &Arc<RwLock<VecDeque<ControllerTelemetrySample>>>,
+            ) {
+                let mut telemetry_queue = queue.write().await;
+
+                let samples: Vec<ControllerTelemetrySample> = telemetry_queue.drain(..).collect();
+
+                if !samples.is_empty() {
+                    if let Err(err) = Db::write_controller_telemetry(handle, samples).await {
+                        error!("Failed to write controller telemetry: {}", err);
+                    }
+                }
+            }
+
             loop {
                 select! {
                     _ = interval.tick() => {
                         drain_measurements(&handle, &queue).await;
+                        drain_controller_telemetry(&handle, &telemetry_queue).await;
                     },
                     _ = abort.cancelled() => {
                         info!("Stopping measurement writer interval");
                         drain_measurements(&handle, &queue).await;
+                        drain_controller_telemetry(&handle, &telemetry_queue).await;
                         break;
                     }
                 }
@@ -164,6 +227,286 @@ impl Db {
         Ok(())
     }
 
+    pub async fn write_controller_telemetry(
+        pool: &Pool<Sqlite>,
+        samples: Vec<ControllerTelemetrySample>,
+    ) -> Result<()> {
+        info!("Writing {} controller telemetry samples to the DB", samples.len());
+
+        let mut query_builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            "INSERT OR REPLACE INTO controller_telemetry (time, controller, heat_level, setpoint, error, p, i, d, predicted_delta, q) "
+        );
+
+        query_builder.push_values(samples, |mut b, sample| {
+            b.push_bind(sample.time)
+                .push_bind(sample.telemetry.name())
+                .push_bind(sample.heat_level);
+
+            match sample.telemetry {
+                ControllerTelemetry::Pid {
+                    setpoint,
+                    error,
+                    p,
+                    i,
+                    d,
+                } => {
+                    b.push_bind(setpoint)
+                        .push_bind(Some(error))
+                        .push_bind(Some(p))
+                        .push_bind(Some(i))
+                        .push_bind(Some(d))
+                        .push_bind(None::<f32>)
+                        .push_bind(None::<f32>);
+                }
+                ControllerTelemetry::Predictive {
+                    setpoint,
+                    predicted_delta,
+                    q,
+                } => {
+                    b.push_bind(setpoint)
+                        .push_bind(None::<f32>)
+                        .push_bind(None::<f32>)
+                        .push_bind(None::<f32>)
+                        .push_bind(None::<f32>)
+                        .push_bind(Some(predicted_delta))
+                        .push_bind(Some(q));
+                }
+            }
+        });
+
+        let query = query_builder.build();
+
+        if let Err(err) = query.execute(pool).await {
+            return Err(anyhow!("Error writing controller telemetry to the DB: {err}"));
+        }
+
+        Ok(())
+    }
+
+    pub async fn write_thermal_model(&self, model: &ThermalModel) -> Result<()> {
+        query(
+            "INSERT OR REPLACE INTO thermal_model (time, heater_gain, loss_coefficient, ambient_temp, lag_s, error_variance, samples) VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(model.time)
+        .bind(model.heater_gain)
+        .bind(model.loss_coefficient)
+        .bind(model.ambient_temp)
+        .bind(model.lag_s)
+        .bind(model.error_variance)
+        .bind(model.samples)
+        .execute(&self.handle)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn write_eta_record(&self, record: &EtaRecord) -> Result<()> {
+        query(
+            "INSERT INTO eta (start_time, phase, target_temp, start_boiler_temp, start_grouphead_temp, predicted_boiler_s, predicted_preheat_s, actual_boiler_s, actual_preheat_s) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(record.start_time)
+        .bind(serde_plain::to_string(&record.phase)?)
+        .bind(record.target_temp)
+        .bind(record.start_boiler_temp)
+        .bind(record.start_grouphead_temp)
+        .bind(record.predicted_boiler_s)
+        .bind(record.predicted_preheat_s)
+        .bind(record.actual_boiler_s)
+        .bind(record.actual_preheat_s)
+        .execute(&self.handle)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn read_schedules(&self) -> Result<Vec<Schedule>> {
+        let rows = query_as::<_, (i64, String, String, String)>(
+            "SELECT id, name, cron, mode FROM schedule ORDER BY id",
+        )
+        .fetch_all(&self.handle)
+        .await?;
+
+        rows.into_iter()
+            .map(|(id, name, cron, mode)| {
+                Ok(Schedule {
+                    id,
+                    name,
+                    cron,
+                    mode: serde_plain::from_str::<Mode>(&mode)?,
+                })
+            })
+            .collect()
+    }
+
+    pub async fn write_schedule(&self, schedule: &NewSchedule) -> Result<i64> {
+        let result = query("INSERT INTO schedule (name, cron, mode) VALUES (?, ?, ?)")
+            .bind(&schedule.name)
+            .bind(&schedule.cron)
+            .bind(serde_plain::to_string(&schedule.mode)?)
+            .execute(&self.handle)
+            .await?;
+
+        Ok(result.last_insert_rowid())
+    }
+
+    pub async fn delete_schedule(&self, id: i64) -> Result<()> {
+        let result = query("DELETE FROM schedule WHERE id = ?")
+            .bind(id)
+            .execute(&self.handle)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow!("There is no schedule with the ID {id}"));
+        }
+
+        Ok(())
+    }
+
+    pub async fn read_profiles(&self) -> Result<Vec<Profile>> {
+        let rows = query_as::<_, (i64, String, f32, f32, String, String, bool, i64, f32, Option<String>)>(
+            "SELECT id, name, brew_target_temp, steam_target_temp, control_method, controller_parameters, require_preheat, target_shot_time_s, target_yield_g, brew_curve FROM profile ORDER BY id",
+        )
+        .fetch_all(&self.handle)
+        .await?;
+
+        rows.into_iter()
+            .map(
+                |(
+                    id,
+                    name,
+                    brew_target_temp,
+                    steam_target_temp,
+                    control_method,
+                    controller_parameters,
+                    require_preheat,
+                    target_shot_time_s,
+                    target_yield_g,
+                    brew_curve,
+                )| {
+                    Ok(Profile {
+                        id,
+                        settings: ProfileSettings {
+                            name,
+                            brew_target_temp,
+                            steam_target_temp,
+                            control_method: serde_plain::from_str::<ControlMethod>(&control_method)?,
+                            controller_parameters: serde_json::from_str(&controller_parameters)?,
+                            require_preheat,
+                            target_shot_time_s: target_shot_time_s as u64,
+                            target_yield_g,
+                            brew_curve: brew_curve
+                                .map(|brew_curve| serde_json::from_str(&brew_curve))
+                                .transpose()?,
+                        },
+                    })
+                },
+            )
+            .collect()
+    }
+
+    pub async fn write_profile(&self, profile: &ProfileSettings) -> Result<i64> {
+        let result = query(
+            "INSERT INTO profile (name, brew_target_temp, steam_target_temp, control_method, controller_parameters, require_preheat, target_shot_time_s, target_yield_g, brew_curve) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&profile.name)
+        .bind(profile.brew_target_temp)
+        .bind(profile.steam_target_temp)
+        .bind(serde_plain::to_string(&profile.control_method)?)
+        .bind(serde_json::to_string(&profile.controller_parameters)?)
+        .bind(profile.require_preheat)
+        .bind(profile.target_shot_time_s as i64)
+        .bind(profile.target_yield_g)
+        .bind(profile.brew_curve.as_ref().map(serde_json::to_string).transpose()?)
+        .execute(&self.handle)
+        .await?;
+
+        Ok(result.last_insert_rowid())
+    }
+
+    pub async fn update_profile(&self, profile: &Profile) -> Result<()> {
+        let settings = &profile.settings;
+
+        let result = query(
+            "UPDATE profile SET name = ?, brew_target_temp = ?, steam_target_temp = ?, control_method = ?, controller_parameters = ?, require_preheat = ?, target_shot_time_s = ?, target_yield_g = ?, brew_curve = ? WHERE id = ?",
+        )
+        .bind(&settings.name)
+        .bind(settings.brew_target_temp)
+        .bind(settings.steam_target_temp)
+        .bind(serde_plain::to_string(&settings.control_method)?)
+        .bind(serde_json::to_string(&settings.controller_parameters)?)
+        .bind(settings.require_preheat)
+        .bind(settings.target_shot_time_s as i64)
+        .bind(settings.target_yield_g)
+        .bind(settings.brew_curve.as_ref().map(serde_json::to_string).transpose()?)
+        .bind(profile.id)
+        .execute(&self.handle)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow!("There is no profile with the ID {}", profile.id));
+        }
+
+        Ok(())
+    }
+
+    pub async fn delete_profile(&self, id: i64) -> Result<()> {
+        let result = query("DELETE FROM profile WHERE id = ?")
+            .bind(id)
+            .execute(&self.handle)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow!("There is no profile with the ID {id}"));
+        }
+
+        Ok(())
+    }
+
+    // The most recent estimate made at or before the time.
+    pub async fn read_thermal_model_at(&self, time: i64) -> Result<Option<ThermalModel>> {
+        let model = query_as::<_, ThermalModel>(
+            "SELECT time, heater_gain, loss_coefficient, ambient_temp, lag_s, error_variance, samples FROM thermal_model WHERE time <= ? ORDER BY time DESC LIMIT 1",
+        )
+        .bind(time)
+        .fetch_optional(&self.handle)
+        .await?;
+
+        Ok(model)
+    }
+
+    // The most recent measurements with a thermofilter temperature, at most one per interval, oldest first.
+    // Only the newest `max_rows` are grouped, so the whole table isn't scanned.
+    pub async fn read_thermofilter_measurements(
+        &self,
+        interval_ms: i64,
+        limit: i64,
+        max_rows: i64,
+    ) -> Result<Vec<ThermofilterMeasurement>> {
+        let mut measurements = query_as::<_, ThermofilterMeasurement>(
+            r#"
+            SELECT MIN(time) as time, boiler_temp_c, grouphead_temp_c, thermofilter_temp_c
+            FROM (
+                SELECT time, boiler_temp_c, grouphead_temp_c, thermofilter_temp_c
+                FROM measurement
+                WHERE thermofilter_temp_c IS NOT NULL
+                ORDER BY time DESC
+                LIMIT ?
+            )
+            GROUP BY time / ?
+            ORDER BY time DESC
+            LIMIT ?"#,
+        )
+        .bind(max_rows)
+        .bind(interval_ms)
+        .bind(limit)
+        .fetch_all(&self.handle)
+        .await?;
+
+        measurements.reverse();
+
+        Ok(measurements)
+    }
+
     pub async fn read_measurements(&self, range: &Range) -> Result<Vec<Measurement>> {
         let Range {
             from,
@@ -252,7 +595,14 @@ impl Db {
         Ok(measurements)
     }
 
-    pub async fn write_shot(&self, start_time: i64, end_time: i64) -> Result<()> {
+    // Detected shots have a confidence, shots recorded from brew mode don't.
+    pub async fn write_shot(
+        &self,
+        start_time: i64,
+        end_time: i64,
+        confidence: Option<f32>,
+        profile_id: Option<i64>,
+    ) -> Result<()> {
         let range = Range {
             id: "".to_string(),
             from: start_time,
@@ -285,27 +635,107 @@ impl Db {
             total_time: end_time - start_time,
             brew_temp_average_c: brew_temp_sum_c / measurement_count,
             grouphead_temp_avg_c: grouphead_temp_sum_c / measurement_count,
+            detected: confidence.is_some(),
+            confidence,
+            profile_id,
         };
 
         query!(
-            "INSERT INTO shot (start_time, end_time, total_time, brew_temp_average_c, grouphead_temp_avg_c) VALUES (?, ?, ?, ?, ?)",
+            "INSERT INTO shot (start_time, end_time, total_time, brew_temp_average_c, grouphead_temp_avg_c, detected, confidence, profile_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
             shot.start_time,
             shot.end_time,
             shot.total_time,
             shot.brew_temp_average_c,
             shot.grouphead_temp_avg_c,
+            shot.detected,
+            shot.confidence,
+            shot.profile_id,
         ).execute(&self.handle).await?;
 
         Ok(())
     }
 
+    pub async fn write_shot_setpoints(
+        &self,
+        shot_start_time: i64,
+        setpoints: Vec<ShotSetpoint>,
+    ) -> Result<()> {
+        if setpoints.is_empty() {
+            return Ok(());
+        }
+
+        let mut query_builder: QueryBuilder<Sqlite> =
+            QueryBuilder::new("INSERT INTO shot_setpoint (shot_start_time, time, target_temp_c) ");
+
+        query_builder.push_values(setpoints, |mut b, setpoint| {
+            b.push_bind(shot_start_time)
+                .push_bind(setpoint.time)
+                .push_bind(setpoint.target_temp_c);
+        });
+
+        query_builder.build().execute(&self.handle).await?;
+
+        Ok(())
+    }
+
+    // Entries are only ever appended.
+    pub async fn write_event_log_entry(
+        &self,
+        time: i64,
+        source: &str,
+        kind: &str,
+        payload: &serde_json::Value,
+    ) -> Result<()> {
+        query("INSERT INTO event (time, source, kind, payload) VALUES (?, ?, ?, ?)")
+            .bind(time)
+            .bind(source)
+            .bind(kind)
+            .bind(serde_json::to_string(payload)?)
+            .execute(&self.handle)
+            .await?;
+
+        Ok(())
+    }
+
+    // Newest first, like the shot history.
+    pub async fn read_event_log(&self, range: &Range) -> Result<Vec<EventLogEntry>> {
+        let limit = range.limit.unwrap_or(-1);
+
+        let rows = query_as::<_, (i64, i64, String, String, String)>(
+            r#"
+            SELECT id, time, source, kind, payload
+            FROM event
+            WHERE time > ? AND time < ?
+            ORDER BY id DESC
+            LIMIT ?"#,
+        )
+        .bind(range.from)
+        .bind(range.to)
+        .bind(limit)
+        .fetch_all(&self.handle)
+        .await?;
+
+        rows.into_iter()
+            .map(|(id, time, source, kind, payload)| {
+                Ok(EventLogEntry {
+                    id,
+                    time,
+                    source,
+                    kind,
+                    payload: serde_json::from_str(&payload)?,
+                })
+            })
+            .collect()
+    }
+
     pub async fn read_shots(&self, range: &Range) -> Result<Vec<Shot>> {
         let limit = range.limit.unwrap_or(-1);
 
         let shots: Vec<Shot> = query_as!(
             Shot,
             r#"
-            SELECT start_time, end_time, total_time, brew_temp_average_c as "brew_temp_average_c: f32", grouphead_temp_avg_c as "grouphead_temp_avg_c: f32"
+            SELECT start_time, end_time, total_time, brew_temp_average_c as "brew_temp_average_c: f32", grouphead_temp_avg_c as "grouphead_temp_avg_c: f32",
+                detected, confidence as "confidence: f32", profile_id
             FROM shot
             WHERE start_time > ? AND start_time < ?
             ORDER BY start_time DESC
@@ -335,6 +765,14 @@ pub struct Measurement {
     pub steam: bool,
 }
 
+#[derive(Clone, Copy, sqlx::FromRow)]
+pub struct ThermofilterMeasurement {
+    pub time: i64,
+    pub boiler_temp_c: f32,
+    pub grouphead_temp_c: f32,
+    pub thermofilter_temp_c: f32,
+}
+
 #[derive(Serialize, Clone, Copy)]
 #[serde(rename_all = "camelCase")]
 pub struct Shot {
@@ -343,6 +781,11 @@ pub struct Shot {
     pub total_time: i64,
     pub brew_temp_average_c: f32,
     pub grouphead_temp_avg_c: f32,
+    // Whether the shot was detected from the temperature, rather than recorded from brew mode.
+    pub detected: bool,
+    pub confidence: Option<f32>,
+    // The profile that was active, None if there wasn't one or it's since been deleted.
+    pub profile_id: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -351,5 +794,10 @@ pub struct ConfigItem {
     pub value: String,
 }
 
+pub const DB_PATH: &str = "/opt/gesha/var/db/gesha.db";
+
 pub const DB_KEY_TARGET_TEMPERATURE: &str = "TargetTemperature";
 pub const DB_KEY_CONTROL_METHOD: &str = "ControlMethod";
+pub const DB_KEY_STANDBY_TARGET_TEMPERATURE: &str = "StandbyTargetTemperature";
+pub const DB_KEY_STEAM_TARGET_TEMPERATURE: &str = "SteamTargetTemperature";
+pub const DB_KEY_ACTIVE_PROFILE: &str = "ActiveProfile";
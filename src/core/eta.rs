@@ -0,0 +1,580 @@
+use std::{collections::VecDeque, time::Duration};
+
+use serde::Serialize;
+
+use crate::models::get_preheat_temperature;
+
+use super::{state::Mode, thermal_model::ThermalModel};
+
+// How often the ETA is published.
+const ETA_INTERVAL_MS: i64 = 5_000;
+
+// The boiler is considered to be at the target temperature within this band.
+const TARGET_BAND_C: f32 = 0.5;
+
+// The grouphead warms up towards a steady temperature, slowing down as it gets closer.
+// Its rate of change is fitted against its temperature over this window, split into buckets
+// to smooth out the 0.25 °C resolution of the thermocouple.
+const GROUPHEAD_WINDOW_MS: i64 = 300_000;
+const GROUPHEAD_BUCKET_MS: i64 = 30_000;
+
+// A rough fit of the boiler from models/boiler_levels and models/thermal_loss,
+// used until the online estimate of the thermal model is available.
+const DEFAULT_THERMAL_MODEL: ThermalModel = ThermalModel {
+    time: 0,
+    heater_gain: 0.6,
+    loss_coefficient: 0.001,
+    ambient_temp: 20.0,
+    lag_s: 10.0,
+    error_variance: 0.0,
+    samples: 0,
+};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum EtaPhase {
+    // Heating up to the target, e.g. after power on.
+    Heating,
+    // Cooling down to the target, e.g. after steaming.
+    Cooling,
+    Ready,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Eta {
+    pub timestamp: i64,
+    pub phase: EtaPhase,
+    pub target_temp: f32,
+    // Seconds until the boiler is at the target temperature, None if it can't be predicted.
+    pub boiler_s: Option<f32>,
+    // Seconds until the grouphead is preheated for the target temperature.
+    pub preheat_s: Option<f32>,
+    // Seconds until both the boiler and grouphead are ready, or just the boiler if preheating isn't required.
+    pub ready_s: Option<f32>,
+}
+
+// The first predictions made for a heat up or cool down, and how long it actually took.
+#[derive(Clone, Debug)]
+pub struct EtaRecord {
+    pub start_time: i64,
+    pub phase: EtaPhase,
+    pub target_temp: f32,
+    pub start_boiler_temp: f32,
+    pub start_grouphead_temp: f32,
+    pub predicted_boiler_s: Option<f32>,
+    pub predicted_preheat_s: Option<f32>,
+    pub actual_boiler_s: f32,
+    pub actual_preheat_s: Option<f32>,
+}
+
+struct Episode {
+    record: EtaRecord,
+    boiler_reached_at: Option<i64>,
+    preheated_at: Option<i64>,
+}
+
+// A measurement and what the machine was doing when it was taken.
+pub struct EtaSample<'a> {
+    pub time: i64,
+    pub boiler_temp: f32,
+    pub grouphead_temp: f32,
+    pub heat_level: f32,
+    pub target_temp: f32,
+    pub mode: &'a Mode,
+    // The fitted thermal model, if there's one yet.
+    pub thermal_model: Option<&'a ThermalModel>,
+}
+
+pub struct EtaTracker {
+    grouphead_history: VecDeque<(i64, f32)>,
+    heat_level_sum: f32,
+    heat_level_count: u32,
+    published_at: i64,
+    episode: Option<Episode>,
+    require_preheat: bool,
+}
+
+impl Default for EtaTracker {
+    fn default() -> Self {
+        EtaTracker::new()
+    }
+}
+
+impl EtaTracker {
+    pub fn new() -> Self {
+        EtaTracker {
+            grouphead_history: VecDeque::new(),
+            heat_level_sum: 0.0,
+            heat_level_count: 0,
+            published_at: 0,
+            episode: None,
+            require_preheat: true,
+        }
+    }
+
+    // Without preheating, the machine is ready as soon as the boiler is at the target temperature.
+    pub fn set_require_preheat(&mut self, require_preheat: bool) {
+        self.require_preheat = require_preheat;
+    }
+
+    // Returns the ETA when it's due to be published, and the record of a heat up or cool down that just finished.
+    pub fn update(&mut self, sample: &EtaSample) -> (Option<Eta>, Option<EtaRecord>) {
+        let EtaSample {
+            time,
+            boiler_temp,
+            grouphead_temp,
+            heat_level,
+            target_temp,
+            mode,
+            thermal_model,
+        } = *sample;
+
+        self.grouphead_history.push_back((time, grouphead_temp));
+
+        while let Some((sample_time, _)) = self.grouphead_history.front() {
+            if time - sample_time > GROUPHEAD_WINDOW_MS {
+                self.grouphead_history.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        // The ETA only makes sense while the boiler is being held at a target,
+        // shots and the machine being off interrupt the heat up.
+        if !matches!(mode, Mode::Active | Mode::Steam | Mode::Standby) {
+            self.episode = None;
+            return (None, None);
+        }
+
+        self.heat_level_sum += heat_level;
+        self.heat_level_count += 1;
+
+        if self
+            .episode
+            .as_ref()
+            .is_some_and(|episode| episode.record.target_temp != target_temp)
+        {
+            self.episode = None;
+        }
+
+        // In standby the ETA is how long it'd take to be ready if woken up now,
+        // which can't be compared with how long it actually took.
+        let record = if *mode == Mode::Standby {
+            self.episode = None;
+            None
+        } else {
+            self.track_episode(time, boiler_temp, grouphead_temp, target_temp)
+        };
+
+        if time - self.published_at < ETA_INTERVAL_MS {
+            return (None, record);
+        }
+
+        // The heat level is averaged over the interval, since the controllers switch the heater on and off.
+        let heat_level = self.heat_level_sum / self.heat_level_count as f32;
+
+        self.published_at = time;
+        self.heat_level_sum = 0.0;
+        self.heat_level_count = 0;
+
+        let thermal_model = thermal_model.unwrap_or(&DEFAULT_THERMAL_MODEL);
+        let phase = phase(boiler_temp, target_temp);
+
+        let boiler_s = match phase {
+            EtaPhase::Ready => Some(0.0),
+            _ => time_to_target(thermal_model, boiler_temp, target_temp, heat_level),
+        };
+
+        // The grouphead is already hot after steaming.
+        let preheat_s = match phase {
+            EtaPhase::Cooling => Some(0.0),
+            _ => self.time_to_preheat(grouphead_temp, target_temp),
+        };
+
+        let ready_s = match (boiler_s, preheat_s) {
+            (Some(boiler_s), _) if !self.require_preheat => Some(boiler_s),
+            (Some(boiler_s), Some(preheat_s)) => Some(boiler_s.max(preheat_s)),
+            _ => None,
+        };
+
+        if let Some(episode) = self.episode.as_mut() {
+            episode.record.predicted_boiler_s = episode.record.predicted_boiler_s.or(boiler_s);
+            episode.record.predicted_preheat_s = episode.record.predicted_preheat_s.or(preheat_s);
+        }
+
+        (
+            Some(Eta {
+                timestamp: time,
+                phase,
+                target_temp,
+                boiler_s,
+                preheat_s,
+                ready_s,
+            }),
+            record,
+        )
+    }
+
+    fn track_episode(
+        &mut self,
+        time: i64,
+        boiler_temp: f32,
+        grouphead_temp: f32,
+        target_temp: f32,
+    ) -> Option<EtaRecord> {
+        let episode = match self.episode.as_mut() {
+            Some(episode) => episode,
+            None => {
+                let phase = phase(boiler_temp, target_temp);
+
+                if phase == EtaPhase::Ready {
+                    return None;
+                }
+
+                self.episode.insert(Episode {
+                    record: EtaRecord {
+                        start_time: time,
+                        phase,
+                        target_temp,
+                        start_boiler_temp: boiler_temp,
+                        start_grouphead_temp: grouphead_temp,
+                        predicted_boiler_s: None,
+                        predicted_preheat_s: None,
+                        actual_boiler_s: 0.0,
+                        actual_preheat_s: None,
+                    },
+                    boiler_reached_at: None,
+                    preheated_at: None,
+                })
+            }
+        };
+
+        let is_boiler_reached = match episode.record.phase {
+            EtaPhase::Cooling => boiler_temp <= target_temp + TARGET_BAND_C,
+            _ => boiler_temp >= target_temp - TARGET_BAND_C,
+        };
+
+        if is_boiler_reached && episode.boiler_reached_at.is_none() {
+            episode.boiler_reached_at = Some(time);
+        }
+
+        let is_preheated = episode.record.phase == EtaPhase::Cooling
+            || grouphead_temp as f64 >= get_preheat_temperature(target_temp as f64);
+
+        if is_preheated && episode.preheated_at.is_none() {
+            episode.preheated_at = Some(time);
+        }
+
+        let (Some(boiler_reached_at), Some(preheated_at)) =
+            (episode.boiler_reached_at, episode.preheated_at)
+        else {
+            return None;
+        };
+
+        let mut record = self.episode.take()?.record;
+
+        record.actual_boiler_s = seconds(boiler_reached_at - record.start_time);
+
+        if record.phase == EtaPhase::Heating {
+            record.actual_preheat_s = Some(seconds(preheated_at - record.start_time));
+        }
+
+        Some(record)
+    }
+
+    // Fits dG/dt = k * (G∞ - G) to the grouphead's history and solves it for the preheat temperature.
+    fn time_to_preheat(&self, grouphead_temp: f32, target_temp: f32) -> Option<f32> {
+        let preheat_temp = get_preheat_temperature(target_temp as f64) as f32;
+
+        if grouphead_temp >= preheat_temp {
+            return Some(0.0);
+        }
+
+        let (first_time, _) = *self.grouphead_history.front()?;
+
+        let mut buckets: Vec<(f32, f32)> = vec![];
+
+        for (time, temp) in self.grouphead_history.iter() {
+            let bucket = ((time - first_time) / GROUPHEAD_BUCKET_MS) as usize;
+
+            if bucket == buckets.len() {
+                buckets.push((0.0, 0.0));
+            }
+
+            let (sum, count) = &mut buckets[bucket];
+            *sum += temp;
+            *count += 1.0;
+        }
+
+        // The mean temperature and rate of change between consecutive buckets.
+        let rates: Vec<(f32, f32)> = buckets
+            .windows(2)
+            .filter(|pair| pair[0].1 > 0.0 && pair[1].1 > 0.0)
+            .map(|pair| {
+                let (a, b) = (pair[0].0 / pair[0].1, pair[1].0 / pair[1].1);
+                ((a + b) / 2.0, (b - a) / seconds(GROUPHEAD_BUCKET_MS))
+            })
+            .collect();
+
+        let (_, latest_rate) = *rates.last()?;
+
+        // The grouphead isn't warming up, so it'll never be preheated at this rate.
+        if latest_rate <= 0.0 {
+            return None;
+        }
+
+        // With too little history to fit, the current rate is extrapolated instead.
+        let linear_estimate = Some((preheat_temp - grouphead_temp) / latest_rate);
+
+        if rates.len() < 3 {
+            return linear_estimate;
+        }
+
+        let n = rates.len() as f32;
+        let mean_temp = rates.iter().map(|(temp, _)| temp).sum::<f32>() / n;
+        let mean_rate = rates.iter().map(|(_, rate)| rate).sum::<f32>() / n;
+
+        let (covariance, variance) =
+            rates
+                .iter()
+                .fold((0.0, 0.0), |(covariance, variance), (temp, rate)| {
+                    (
+                        covariance + (temp - mean_temp) * (rate - mean_rate),
+                        variance + (temp - mean_temp).powi(2),
+                    )
+                });
+
+        if variance == 0.0 {
+            return linear_estimate;
+        }
+
+        // rate = k * G∞ - k * G
+        let k = -covariance / variance;
+
+        if k <= 0.0 {
+            return linear_estimate;
+        }
+
+        let steady_state_temp = (mean_rate + k * mean_temp) / k;
+
+        // The fit is unreliable while the grouphead is still accelerating, e.g. just after power on.
+        if steady_state_temp <= preheat_temp {
+            return linear_estimate;
+        }
+
+        Some(((steady_state_temp - grouphead_temp) / (steady_state_temp - preheat_temp)).ln() / k)
+    }
+}
+
+fn phase(boiler_temp: f32, target_temp: f32) -> EtaPhase {
+    if boiler_temp < target_temp - TARGET_BAND_C {
+        EtaPhase::Heating
+    } else if boiler_temp > target_temp + TARGET_BAND_C {
+        EtaPhase::Cooling
+    } else {
+        EtaPhase::Ready
+    }
+}
+
+// Solves the thermal model for the time the boiler reaches the target temperature.
+// When heating, the controller has to apply at least enough heat to reach the target,
+// so full heat is assumed if the current heat level would level off below it.
+// When cooling the heater is off.
+fn time_to_target(
+    thermal_model: &ThermalModel,
+    boiler_temp: f32,
+    target_temp: f32,
+    heat_level: f32,
+) -> Option<f32> {
+    let heat_level = if boiler_temp > target_temp {
+        0.0
+    } else if steady_state_temp(thermal_model, heat_level)? > target_temp {
+        heat_level
+    } else {
+        1.0
+    };
+
+    let steady_state_temp = steady_state_temp(thermal_model, heat_level)?;
+
+    // (target - Tss) / (T - Tss) = e^(-kt)
+    let ratio = (target_temp - steady_state_temp) / (boiler_temp - steady_state_temp);
+
+    if ratio <= 0.0 || ratio > 1.0 {
+        return None;
+    }
+
+    let seconds = -ratio.ln() / thermal_model.loss_coefficient;
+
+    // The boiler doesn't respond to the heater until the lag has passed.
+    if heat_level > 0.0 {
+        Some(seconds + thermal_model.lag_s)
+    } else {
+        Some(seconds)
+    }
+}
+
+// The temperature the boiler levels off at for a constant heat level.
+fn steady_state_temp(thermal_model: &ThermalModel, heat_level: f32) -> Option<f32> {
+    if thermal_model.loss_coefficient <= 0.0 {
+        return None;
+    }
+
+    Some(
+        thermal_model.ambient_temp
+            + thermal_model.heater_gain * heat_level / thermal_model.loss_coefficient,
+    )
+}
+
+fn seconds(milliseconds: i64) -> f32 {
+    Duration::from_millis(milliseconds.max(0) as u64).as_secs_f32()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODEL: ThermalModel = ThermalModel {
+        time: 0,
+        heater_gain: 0.5,
+        loss_coefficient: 0.005,
+        ambient_temp: 20.0,
+        lag_s: 10.0,
+        error_variance: 0.0,
+        samples: 0,
+    };
+
+    fn sample(
+        time: i64,
+        boiler_temp: f32,
+        grouphead_temp: f32,
+        heat_level: f32,
+        target_temp: f32,
+    ) -> EtaSample<'static> {
+        EtaSample {
+            time,
+            boiler_temp,
+            grouphead_temp,
+            heat_level,
+            target_temp,
+            mode: &Mode::Active,
+            thermal_model: Some(&MODEL),
+        }
+    }
+
+    #[test]
+    fn heating_eta_solves_the_thermal_model_plus_the_lag() {
+        let mut tracker = EtaTracker::new();
+
+        let (eta, _) = tracker.update(&sample(10_000, 60.0, 80.0, 1.0, 95.0));
+        let eta = eta.unwrap();
+
+        // Full heat levels off at 20 + 0.5 / 0.005 = 120 °C.
+        let expected = -((95.0f32 - 120.0) / (60.0 - 120.0)).ln() / 0.005 + 10.0;
+
+        assert_eq!(eta.phase, EtaPhase::Heating);
+        assert!((eta.boiler_s.unwrap() - expected).abs() < 0.01, "{eta:?}");
+        assert_eq!(eta.preheat_s, Some(0.0));
+        assert_eq!(eta.ready_s, eta.boiler_s);
+    }
+
+    #[test]
+    fn cooling_eta_assumes_the_heater_is_off() {
+        let mut tracker = EtaTracker::new();
+
+        let (eta, _) = tracker.update(&sample(10_000, 130.0, 90.0, 0.4, 95.0));
+        let eta = eta.unwrap();
+
+        // With the heater off the boiler cools towards ambient, and there's no lag.
+        let expected = -((95.0f32 - 20.0) / (130.0 - 20.0)).ln() / 0.005;
+
+        assert_eq!(eta.phase, EtaPhase::Cooling);
+        assert!((eta.boiler_s.unwrap() - expected).abs() < 0.01, "{eta:?}");
+        assert_eq!(eta.preheat_s, Some(0.0));
+    }
+
+    #[test]
+    fn a_grouphead_that_isnt_warming_has_no_preheat_eta() {
+        let mut tracker = EtaTracker::new();
+        let mut last_eta = None;
+
+        for time in (0..180_000).step_by(1_000) {
+            if let (Some(eta), _) = tracker.update(&sample(time, 60.0, 40.0, 1.0, 95.0)) {
+                last_eta = Some(eta);
+            }
+        }
+
+        let eta = last_eta.unwrap();
+
+        assert_eq!(eta.preheat_s, None);
+        assert!(eta.boiler_s.is_some());
+        assert_eq!(eta.ready_s, None);
+
+        // Without preheating the machine is ready once the boiler is.
+        tracker.set_require_preheat(false);
+
+        let (eta, _) = tracker.update(&sample(180_000, 60.0, 40.0, 1.0, 95.0));
+        let eta = eta.unwrap();
+
+        assert_eq!(eta.ready_s, eta.boiler_s);
+    }
+
+    #[test]
+    fn a_record_is_emitted_when_the_target_is_reached() {
+        let mut tracker = EtaTracker::new();
+        let mut records = vec![];
+
+        // Heating from 90 °C at 0.1 °C/s with a preheated grouphead reaches the band at 94.5 °C after 45 s.
+        for step in 0..60 {
+            let (_, record) = tracker.update(&sample(
+                step * 1_000,
+                90.0 + 0.1 * step as f32,
+                80.0,
+                1.0,
+                95.0,
+            ));
+            records.extend(record);
+        }
+
+        let [record] = records.as_slice() else {
+            panic!("Expected one record, got {}", records.len());
+        };
+
+        assert_eq!(record.phase, EtaPhase::Heating);
+        assert_eq!(record.start_time, 0);
+        assert_eq!(record.start_boiler_temp, 90.0);
+        assert!((record.actual_boiler_s - 45.0).abs() <= 1.0, "{record:?}");
+        assert_eq!(record.actual_preheat_s, Some(0.0));
+        assert!(record.predicted_boiler_s.is_some());
+    }
+
+    #[test]
+    fn changing_the_target_starts_a_new_episode() {
+        let mut tracker = EtaTracker::new();
+        let mut records = vec![];
+
+        for step in 0..20 {
+            let (_, record) = tracker.update(&sample(step * 1_000, 90.0, 80.0, 1.0, 95.0));
+            records.extend(record);
+        }
+
+        for step in 20..40 {
+            let (_, record) = tracker.update(&sample(
+                step * 1_000,
+                86.0 + 0.5 * (step - 20) as f32,
+                80.0,
+                1.0,
+                92.0,
+            ));
+            records.extend(record);
+        }
+
+        let [record] = records.as_slice() else {
+            panic!("Expected one record, got {}", records.len());
+        };
+
+        assert_eq!(record.start_time, 20_000);
+        assert_eq!(record.target_temp, 92.0);
+        assert_eq!(record.start_boiler_temp, 86.0);
+    }
+}
@@ -0,0 +1,236 @@
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use super::{
+    mqtt::{MqttIncomingMessage, MqttOutgoingMessage},
+    state::Event,
+    transition::TransitionTrigger,
+};
+
+// A row of the event table, returned on gesha/event/history/{id}.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EventLogEntry {
+    pub id: i64,
+    pub time: i64,
+    // Who or what caused the event, e.g. "mqtt:kitchen-tablet", "scheduler" or "safety".
+    pub source: String,
+    pub kind: String,
+    pub payload: Value,
+}
+
+// The entry's (source, kind, payload), or None for events that aren't logged.
+// Telemetry and history queries are left out, they'd swamp the log.
+pub fn describe(event: &Event) -> Result<Option<(String, String, Value)>> {
+    let is_logged = match event {
+        Event::TemperatureChanged(_)
+        | Event::BoilerHeatLevelChanged(_)
+        | Event::ControllerTelemetryChanged(_)
+        | Event::ThermalModelChanged(_)
+        | Event::StateSnapshotChanged(_) => false,
+        Event::IncomingMqttMessage(message, _) => !matches!(
+            message,
+            MqttIncomingMessage::TemperatureHistoryRequest(_)
+                | MqttIncomingMessage::ShotHistoryRequest(_)
+                | MqttIncomingMessage::EventHistoryRequest(_)
+                | MqttIncomingMessage::StateRequest(_)
+                | MqttIncomingMessage::ScheduleListRequest
+                | MqttIncomingMessage::ProfileListRequest
+        ),
+        // Most outgoing messages echo an event that's already logged.
+        Event::OutgoingMqttMessage(message) => matches!(
+            message,
+            MqttOutgoingMessage::ExternRelayPowerStateSetCmd(_)
+                | MqttOutgoingMessage::ModeTransitionUpdate(_)
+                | MqttOutgoingMessage::TargetTemperatureRejected(_)
+                | MqttOutgoingMessage::AutoOffWarningUpdate(_)
+        ),
+        _ => true,
+    };
+
+    if !is_logged {
+        return Ok(None);
+    }
+
+    // MQTT messages are logged by what they are, rather than as an incoming or outgoing message.
+    let value = match event {
+        Event::IncomingMqttMessage(message, _) => serde_json::to_value(message)?,
+        Event::OutgoingMqttMessage(message) => serde_json::to_value(message)?,
+        event => serde_json::to_value(event)?,
+    };
+
+    let kind = value["kind"].as_str().unwrap_or_default().to_string();
+
+    Ok(Some((source(event), kind, value["payload"].clone())))
+}
+
+// An error handling the event, logged with the event's source.
+pub fn describe_error(event: &Event, err: &anyhow::Error) -> Result<(String, String, Value)> {
+    let event_kind = match describe(event)? {
+        Some((_, kind, _)) => kind,
+        None => serde_json::to_value(event)?["kind"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+    };
+
+    Ok((
+        source(event),
+        String::from("error"),
+        json!({ "event": event_kind, "message": err.to_string() }),
+    ))
+}
+
+fn source(event: &Event) -> String {
+    match event {
+        Event::IncomingMqttMessage(
+            MqttIncomingMessage::ExternRelayAvailabilityChanged(_)
+            | MqttIncomingMessage::ExternRelayPowerStateChanged(_),
+            _,
+        )
+        | Event::RelayCommandFailed(_) => String::from("relay"),
+        Event::IncomingMqttMessage(_, Some(client_id)) => format!("mqtt:{client_id}"),
+        Event::IncomingMqttMessage(_, None) => String::from("mqtt"),
+        Event::ModeTransitionRequest { trigger, .. } => trigger_source(trigger),
+        Event::OutgoingMqttMessage(MqttOutgoingMessage::ModeTransitionUpdate(transition)) => {
+            trigger_source(&transition.trigger)
+        }
+        Event::TemperatureReadError(_) => String::from("sensors"),
+        Event::ControllerDegradationChanged(_) => String::from("controller"),
+        _ => String::from("gesha"),
+    }
+}
+
+fn trigger_source(trigger: &TransitionTrigger) -> String {
+    String::from(match trigger {
+        TransitionTrigger::User | TransitionTrigger::Wake => "mqtt",
+        TransitionTrigger::RelayPowerOn
+        | TransitionTrigger::RelayPowerOff
+        | TransitionTrigger::RelayUnavailable
+        | TransitionTrigger::RelayCommandFailed => "relay",
+        TransitionTrigger::ShotDetected | TransitionTrigger::ShotDetectionEnded => "shotDetector",
+        TransitionTrigger::Schedule => "scheduler",
+        TransitionTrigger::Inactivity => "autoOff",
+        TransitionTrigger::MaxShotDuration => "shotTimer",
+        TransitionTrigger::SafetyTripped => "safety",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::core::{
+        db::Db,
+        mqtt::Range,
+        state::{Mode, TemperatureMeasurement},
+        thermal_model::ThermalModel,
+    };
+
+    fn range(from: i64, to: i64, limit: Option<i64>) -> Range {
+        Range {
+            id: String::new(),
+            from,
+            to,
+            limit,
+            bucket_size: None,
+        }
+    }
+
+    #[test]
+    fn telemetry_and_queries_are_not_logged() {
+        let events = [
+            Event::TemperatureChanged(TemperatureMeasurement {
+                boiler_temp: 95.0,
+                grouphead_temp: 80.0,
+                thermofilter_temp: None,
+                timestamp: SystemTime::now(),
+            }),
+            Event::BoilerHeatLevelChanged(0.5),
+            Event::ThermalModelChanged(ThermalModel {
+                time: 0,
+                heater_gain: 0.5,
+                loss_coefficient: 0.005,
+                ambient_temp: 20.0,
+                lag_s: 10.0,
+                error_variance: 0.0,
+                samples: 0,
+            }),
+            Event::IncomingMqttMessage(MqttIncomingMessage::EventHistoryRequest(range(0, 1, None)), None),
+            Event::IncomingMqttMessage(MqttIncomingMessage::ProfileListRequest, None),
+            Event::OutgoingMqttMessage(MqttOutgoingMessage::ModeUpdate(Mode::Active)),
+        ];
+
+        for event in events.iter() {
+            assert!(describe(event).unwrap().is_none(), "{event:?}");
+        }
+    }
+
+    #[test]
+    fn commands_and_changes_are_logged_with_their_source() {
+        let table = [
+            (
+                Event::IncomingMqttMessage(MqttIncomingMessage::ModeSet(Mode::Active), Some(String::from("tablet"))),
+                "mqtt:tablet",
+                "modeSet",
+            ),
+            (
+                Event::IncomingMqttMessage(MqttIncomingMessage::ExternRelayPowerStateChanged(true), None),
+                "relay",
+                "externRelayPowerStateChanged",
+            ),
+            (
+                Event::ModeTransitionRequest {
+                    mode: Mode::Active,
+                    trigger: TransitionTrigger::Schedule,
+                },
+                "scheduler",
+                "modeTransitionRequest",
+            ),
+            (Event::ModeChanged(Mode::Active), "gesha", "modeChanged"),
+        ];
+
+        for (event, expected_source, expected_kind) in table {
+            let (source, kind, _) = describe(&event).unwrap().unwrap();
+
+            assert_eq!((source.as_str(), kind.as_str()), (expected_source, expected_kind), "{event:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn entries_are_appended_and_read_newest_first() {
+        let db_path = std::env::temp_dir().join(format!("gesha-event-log-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&db_path);
+
+        let db = Db::new(db_path.to_str().unwrap()).await.unwrap();
+
+        // The same event twice is two entries.
+        for time in [1_000, 2_000, 3_000, 3_000, 4_000] {
+            db.write_event_log_entry(time, "mqtt", "modeSet", &json!("active"))
+                .await
+                .unwrap();
+        }
+
+        let entries = db.read_event_log(&range(0, i64::MAX, None)).await.unwrap();
+        let times: Vec<i64> = entries.iter().map(|entry| entry.time).collect();
+
+        assert_eq!(times, vec![4_000, 3_000, 3_000, 2_000, 1_000]);
+        assert!(entries.windows(2).all(|pair| pair[0].id > pair[1].id));
+        assert_eq!(entries[0].payload, json!("active"));
+
+        // The range is exclusive.
+        let entries = db.read_event_log(&range(1_000, 4_000, None)).await.unwrap();
+        let times: Vec<i64> = entries.iter().map(|entry| entry.time).collect();
+
+        assert_eq!(times, vec![3_000, 3_000, 2_000]);
+
+        let entries = db.read_event_log(&range(0, i64::MAX, Some(2))).await.unwrap();
+        let times: Vec<i64> = entries.iter().map(|entry| entry.time).collect();
+
+        assert_eq!(times, vec![4_000, 3_000]);
+
+        let _ = std::fs::remove_file(&db_path);
+    }
+}
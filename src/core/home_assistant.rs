@@ -0,0 +1,193 @@
+use serde_json::{json, Value};
+
+use super::config::{Config, TargetBounds};
+
+// Home Assistant's MQTT discovery configs for a machine, as (topic, config) pairs.
+// The machine's entities are grouped into one device, which is available while gesha is running.
+pub fn discovery_configs(config: &Config, topic_prefix: &str) -> Vec<(String, Value)> {
+    let discovery_prefix = &config.home_assistant.discovery_prefix;
+    let node_id = config.machine_id.as_deref().unwrap_or("gesha");
+    let topic = |topic: &str| format!("{topic_prefix}/{topic}");
+
+    let device = json!({
+        "identifiers": [format!("gesha_{node_id}")],
+        "name": match &config.machine_id {
+            Some(machine_id) => format!("Gesha {machine_id}"),
+            None => String::from("Gesha"),
+        },
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+
+    let availability = json!([{ "topic": topic("availability") }]);
+
+    let entity = |component: &str, object_id: &str, mut entity_config: Value| {
+        entity_config["unique_id"] = json!(format!("{node_id}_{object_id}"));
+        entity_config["device"] = device.clone();
+        entity_config["availability"] = availability.clone();
+
+        (
+            format!("{discovery_prefix}/{component}/{node_id}/{object_id}/config"),
+            entity_config,
+        )
+    };
+
+    let temperature_sensor = |object_id: &str, name: &str, instrument: &str| {
+        entity(
+            "sensor",
+            object_id,
+            json!({
+                "name": name,
+                "state_topic": topic(&format!("temperature/{instrument}")),
+                "value_template": "{{ value_json.value }}",
+                "device_class": "temperature",
+                "state_class": "measurement",
+                "unit_of_measurement": "°C",
+            }),
+        )
+    };
+
+    let TargetBounds { min, max } = config.targets.brew;
+
+    let mut configs = vec![
+        // Heat is any mode but idle, and the presets are the modes with their own targets.
+        // The target temperature is set with the brew target topic.
+        entity(
+            "climate",
+            "boiler",
+            json!({
+                "name": "Boiler",
+                "modes": ["off", "heat"],
+                "mode_state_topic": topic("mode"),
+                "mode_state_template": "{{ 'off' if value_json in ['idle', 'offline'] else 'heat' }}",
+                "mode_command_topic": topic("mode/set"),
+                "mode_command_template": "{{ 'active' if value == 'heat' else 'idle' }}",
+                "preset_modes": ["brew", "steam", "standby"],
+                "preset_mode_state_topic": topic("mode"),
+                "preset_mode_value_template": "{{ value_json if value_json in ['brew', 'steam', 'standby'] else 'none' }}",
+                "preset_mode_command_topic": topic("mode/set"),
+                "temperature_state_topic": topic("temperature/target"),
+                "temperature_command_topic": topic("temperature/target/brew/set"),
+                "current_temperature_topic": topic("temperature/boiler"),
+                "current_temperature_template": "{{ value_json.value }}",
+                "min_temp": min,
+                "max_temp": max,
+                "temp_step": 0.5,
+                "temperature_unit": "C",
+            }),
+        ),
+        temperature_sensor("boiler_temperature", "Boiler temperature", "boiler"),
+        temperature_sensor(
+            "grouphead_temperature",
+            "Grouphead temperature",
+            "grouphead",
+        ),
+        temperature_sensor(
+            "extraction_temperature",
+            "Predicted extraction temperature",
+            "thermofilter_predicted",
+        ),
+        entity(
+            "sensor",
+            "boiler_level",
+            json!({
+                "name": "Boiler level",
+                "state_topic": topic("boiler_level"),
+                "value_template": "{{ (value_json.value * 100) | round(0) }}",
+                "state_class": "measurement",
+                "unit_of_measurement": "%",
+            }),
+        ),
+        entity(
+            "switch",
+            "power",
+            json!({
+                "name": "Power",
+                "state_topic": topic("mode"),
+                "value_template": "{{ 'OFF' if value_json in ['idle', 'offline'] else 'ON' }}",
+                "command_topic": topic("mode/set"),
+                "payload_on": "active",
+                "payload_off": "idle",
+                "state_on": "ON",
+                "state_off": "OFF",
+            }),
+        ),
+        entity(
+            "select",
+            "control_method",
+            json!({
+                "name": "Control method",
+                "state_topic": topic("control_method"),
+                "value_template": "{{ value_json }}",
+                "command_topic": topic("control_method/set"),
+                "options": ["Threshold", "PID", "Predictive", "None"],
+            }),
+        ),
+    ];
+
+    // The thermofilter is only fitted when measuring the extraction temperature.
+    if config.thermofilter_spi.is_some() {
+        configs.push(temperature_sensor(
+            "thermofilter_temperature",
+            "Thermofilter temperature",
+            "thermofilter",
+        ));
+    }
+
+    configs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discovery_configs_use_the_configured_prefixes() {
+        let mut config: Config =
+            serde_yaml::from_str("homeAssistant: { discovery: true, discoveryPrefix: ha }").unwrap();
+        config.machine_id = Some(String::from("silvia"));
+
+        let configs = discovery_configs(&config, "kitchen/silvia");
+
+        assert!(!configs.is_empty());
+
+        for (topic, entity_config) in configs.iter() {
+            assert!(topic.starts_with("ha/") && topic.ends_with("/config"), "{topic}");
+            assert!(topic.contains("/silvia/"), "{topic}");
+            assert_eq!(entity_config["availability"], json!([{ "topic": "kitchen/silvia/availability" }]));
+            assert_eq!(entity_config["device"]["identifiers"], json!(["gesha_silvia"]));
+
+            let topics = entity_config
+                .as_object()
+                .unwrap()
+                .iter()
+                .filter(|(key, _)| key.ends_with("_topic"));
+
+            for (key, value) in topics {
+                assert!(value.as_str().unwrap().starts_with("kitchen/silvia/"), "{topic} {key}: {value}");
+            }
+        }
+    }
+
+    #[test]
+    fn the_thermofilter_sensor_is_only_discovered_when_fitted() {
+        let has_thermofilter = |config: &Config| {
+            discovery_configs(config, "gesha")
+                .iter()
+                .any(|(topic, _)| topic == "homeassistant/sensor/gesha/thermofilter_temperature/config")
+        };
+
+        let config: Config = serde_yaml::from_str("{}").unwrap();
+        assert!(!has_thermofilter(&config));
+
+        let config: Config = serde_yaml::from_str("thermofilterSpi: Rpi1").unwrap();
+        assert!(has_thermofilter(&config));
+    }
+
+    #[test]
+    fn discovery_is_off_by_default() {
+        let config: Config = serde_yaml::from_str("{}").unwrap();
+
+        assert!(!config.home_assistant.discovery);
+        assert_eq!(config.home_assistant.discovery_prefix, "homeassistant");
+    }
+}
@@ -0,0 +1,208 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use log::{error, info};
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+use crate::{
+    controller::{ControllerManager, ControllerSettings},
+    core::{
+        config::Config,
+        mqtt::{machine_topic_prefix, MachineInfo, Mqtt, MqttOutgoingMessage},
+        power_relay::{self, RelayCommandTracker},
+        schedule::Scheduler,
+        shot_timer::ShotTimer,
+        snapshot::StatePublisher,
+        state::{Event, State},
+        thermocouple::ThermocouplePoller,
+    },
+    models::PredictiveModels,
+};
+
+// An espresso machine with its own event bus, state, controller, sensors and MQTT connection.
+pub struct Machine {
+    pub id: Option<String>,
+    pub topic_prefix: String,
+    tx: Sender<Event>,
+    state: State,
+    mqtt: Mqtt,
+    controller_manager: ControllerManager,
+    // Kept so that the poller lives as long as the machine.
+    _thermocouples: ThermocouplePoller,
+    scheduler: Scheduler,
+    shot_timer: ShotTimer,
+    state_publisher: StatePublisher,
+    relay_command_tracker: RelayCommandTracker,
+}
+
+impl Machine {
+    // Returns the receiver for the machine's events, which are handled with Machine::handle_event.
+    pub async fn start(
+        config: &Config,
+        models: Arc<PredictiveModels>,
+    ) -> Result<(Machine, Receiver<Event>)> {
+        let (tx, rx) = broadcast::channel::<Event>(10_000);
+
+        let state = State::new(tx.clone(), config, models.clone()).await?;
+
+        let power_relay = power_relay::new_power_relay(&config.power_relay, tx.clone())?;
+
+        let mut mqtt = Mqtt::new(config, power_relay, tx.clone())?;
+
+        mqtt.start().await?;
+
+        let mut relay_command_tracker =
+            RelayCommandTracker::new(&config.relay_commands, tx.clone());
+
+        relay_command_tracker.start();
+
+        let mut controller_manager = ControllerManager::new(
+            config
+                .boiler_pin
+                .ok_or_else(|| anyhow!("No boiler pin configured"))?,
+            ControllerSettings {
+                control_method: state.control_method,
+                target_temperature: state.target_temperature,
+                parameters: state.controller_parameters,
+                mode: state.mode.clone(),
+                thermal_model: state.thermal_model.clone(),
+                predictive_fallback: config.predictive.fallback_control_method,
+                telemetry_interval: Duration::from_millis(config.controller_telemetry.interval_ms),
+            },
+            models.clone(),
+            tx.clone(),
+        )?;
+
+        controller_manager.start()?;
+
+        let mut thermocouples =
+            ThermocouplePoller::new(state.mode.clone(), tx.clone(), config.clone());
+
+        thermocouples.poll()?;
+
+        let mut scheduler = Scheduler::new(state.schedules.clone(), tx.clone(), Arc::new(Local::now));
+
+        scheduler.start();
+
+        let mut shot_timer = ShotTimer::new(
+            &config.shot_timer,
+            state.active_profile.as_ref(),
+            tx.clone(),
+        );
+
+        shot_timer.start()?;
+
+        let mut state_publisher = StatePublisher::new(tx.clone());
+
+        state_publisher.start();
+
+        let machine = Machine {
+            id: config.machine_id.clone(),
+            topic_prefix: machine_topic_prefix(
+                &config.mqtt_topic_prefix,
+                config.machine_id.as_deref(),
+            ),
+            tx,
+            state,
+            mqtt,
+            controller_manager,
+            _thermocouples: thermocouples,
+            scheduler,
+            shot_timer,
+            state_publisher,
+            relay_command_tracker,
+        };
+
+        Ok((machine, rx))
+    }
+
+    pub fn send(&self, event: Event) -> Result<()> {
+        self.tx.send(event)?;
+
+        Ok(())
+    }
+
+    pub async fn handle_event(&mut self, event: &Event) -> Result<()> {
+        if let Err(err) = self.state.record_event(event).await {
+            error!("Error writing event to the event log: {}", err);
+        }
+
+        match event {
+            // The state is not updated by outgoing MQTT messages,
+            // they're use to update external devices to state changes that
+            // have already happened. We don't want to handle these events in the state.
+            Event::OutgoingMqttMessage(message) => {
+                self.mqtt.publish(message).await?;
+            }
+            event => {
+                // Log all events except temperature changes and controller telemetry -
+                // they can happen every 100ms and would spam the logs.
+                if !matches!(
+                    event,
+                    Event::TemperatureChanged(_) | Event::ControllerTelemetryChanged(_)
+                ) {
+                    info!("Received event: {:?}", event);
+                }
+
+                match self.state.handle_event(event).await {
+                    Ok(events) => {
+                        for event in events.iter() {
+                            self.tx.send(event.clone())?;
+                        }
+                    }
+                    Err(err) => {
+                        error!("Error updating state: {}", err);
+
+                        if let Err(err) = self.state.record_error(event, &err).await {
+                            error!("Error writing error to the event log: {}", err);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn reload_models(&mut self) -> Result<()> {
+        for event in self.state.reload_models().await? {
+            self.tx.send(event)?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn stop(&mut self) -> Result<()> {
+        self.mqtt.stop().await?;
+        self.controller_manager.stop().await?;
+        self.scheduler.stop().await?;
+        self.shot_timer.stop().await?;
+        self.state_publisher.stop().await?;
+        self.relay_command_tracker.stop().await?;
+        self.state.stop().await?;
+
+        Ok(())
+    }
+}
+
+// Lists the machines on gesha/machines, only when there's more than one.
+pub fn machines_update(machines: &[Machine]) -> Option<Event> {
+    let machines = machines
+        .iter()
+        .filter_map(|machine| {
+            machine.id.as_ref().map(|id| MachineInfo {
+                id: id.clone(),
+                topic_prefix: machine.topic_prefix.clone(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if machines.is_empty() {
+        return None;
+    }
+
+    Some(Event::OutgoingMqttMessage(
+        MqttOutgoingMessage::MachinesUpdate(machines),
+    ))
+}
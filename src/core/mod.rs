@@ -1,7 +1,22 @@
+pub mod auto_off;
+pub mod brew_curve;
 pub mod config;
 pub mod db;
+pub mod eta;
+pub mod event_log;
+pub mod home_assistant;
+#[cfg(all(target_arch = "arm", target_os = "linux"))]
+pub mod machine;
 pub mod mqtt;
+pub mod power_relay;
+pub mod profile;
+pub mod schedule;
+pub mod shot_detector;
+pub mod snapshot;
+pub mod shot_timer;
 pub mod state;
+pub mod thermal_model;
 #[cfg(all(target_arch = "arm", target_os = "linux"))]
 pub mod thermocouple;
+pub mod transition;
 pub mod util;
@@ -2,54 +2,123 @@ use anyhow::{anyhow, Result};
 use log::{debug, error, info};
 use rumqttc::v5::{
     mqttbytes::{
-        v5::{Packet, Publish},
+        v5::{LastWill, Packet, Publish},
         QoS,
     },
     AsyncClient, Event as MqttEvent, MqttOptions,
 };
 use serde::{Deserialize, Serialize};
-use std::{str, time::SystemTime};
+use std::{str, sync::Arc, time::SystemTime};
 use tokio::{select, sync::broadcast::Sender, task, time};
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    controller::ControlMethod,
+    controller::{ControlMethod, ControllerDegradation, ControllerTelemetrySample},
     core::{
-        state::{Event, IsPowerOn},
+        auto_off::AutoOffWarning,
+        config::Config,
+        eta::Eta,
+        home_assistant,
+        power_relay::{PowerRelay, RelayCommandFailure},
+        profile::{Profile, ProfileSettings},
+        schedule::{NewSchedule, Schedule},
+        shot_timer::ShotTimerUpdate,
+        snapshot::{StateRequest, StateSnapshot},
+        state::{Event, IsPowerOn, TargetMode},
+        thermal_model::ThermalModel,
+        transition::ModeTransition,
         util,
     },
+    models::PredictiveModelsInfo,
 };
 
 use super::{db::ConfigItem, state::Mode};
 
-const TOPIC_EXTERN_POWER_STATUS: &str = "ms-silvia-switch/status";
-const TOPIC_EXTERN_POWER_STATE_CHANGE: &str = "ms-silvia-switch/switch/power/state";
-const TOPIC_EXTERN_POWER_COMMAND: &str = "ms-silvia-switch/switch/power/command";
-
-const TOPIC_CONTROL_METHOD_CHANGE_REQUEST: &str = "gesha/control_method/set";
-const TOPIC_TARGET_TEMPERATURE_CHANGE_REQUEST: &str = "gesha/temperature/target/set";
-const TOPIC_MODE_CHANGE: &str = "gesha/mode/set";
-const TOPIC_TEMPERATURE_HISTORY_REQUEST: &str = "gesha/temperature/history/command";
-const TOPIC_MANUAL_BOILER_HEAT_LEVEL_REQUEST: &str = "gesha/boiler_level/set";
-const TOPIC_SHOT_HISTORY_REQUEST: &str = "gesha/shot/history/command";
-const TOPIC_CONFIG_SET: &str = "gesha/config/set";
+const TOPIC_CONTROL_METHOD_CHANGE_REQUEST: &str = "control_method/set";
+// Sets the brew target, kept for clients from before each mode had a target.
+const TOPIC_TARGET_TEMPERATURE_CHANGE_REQUEST: &str = "temperature/target/set";
+const TOPIC_BREW_TARGET_TEMPERATURE_CHANGE_REQUEST: &str = "temperature/target/brew/set";
+const TOPIC_STEAM_TARGET_TEMPERATURE_CHANGE_REQUEST: &str = "temperature/target/steam/set";
+const TOPIC_MODE_CHANGE: &str = "mode/set";
+const TOPIC_TEMPERATURE_HISTORY_REQUEST: &str = "temperature/history/command";
+const TOPIC_MANUAL_BOILER_HEAT_LEVEL_REQUEST: &str = "boiler_level/set";
+const TOPIC_SHOT_HISTORY_REQUEST: &str = "shot/history/command";
+const TOPIC_CONFIG_SET: &str = "config/set";
+const TOPIC_MODELS_RELOAD: &str = "models/reload";
+const TOPIC_SCHEDULE_CREATE: &str = "schedule/create";
+const TOPIC_SCHEDULE_DELETE: &str = "schedule/delete";
+const TOPIC_SCHEDULE_LIST: &str = "schedule/list";
+const TOPIC_PROFILE_CREATE: &str = "profile/create";
+const TOPIC_PROFILE_UPDATE: &str = "profile/update";
+const TOPIC_PROFILE_DELETE: &str = "profile/delete";
+const TOPIC_PROFILE_LIST: &str = "profile/list";
+const TOPIC_PROFILE_ACTIVATE: &str = "profile/activate";
+const TOPIC_STANDBY_TARGET_TEMPERATURE_CHANGE_REQUEST: &str = "temperature/target/standby/set";
+const TOPIC_WAKE: &str = "wake";
+const TOPIC_EVENT_HISTORY_REQUEST: &str = "event/history/command";
+const TOPIC_STATE_REQUEST: &str = "state/command";
+
+// "online" while gesha is connected, and "offline" when it stops or the broker publishes the last will.
+const TOPIC_AVAILABILITY: &str = "availability";
+const AVAILABILITY_ONLINE: &str = "online";
+const AVAILABILITY_OFFLINE: &str = "offline";
+
+// How long to wait before reconnecting to the MQTT server after losing the connection.
+const RECONNECT_DELAY: time::Duration = time::Duration::from_secs(1);
+
+// An MQTT v5 user property that identifies who sent a command, e.g. "kitchen-tablet".
+const USER_PROPERTY_CLIENT_ID: &str = "client_id";
 
 pub struct Mqtt {
     uri: String,
+    // The configured prefix, which is shared by every machine.
+    root_topic_prefix: String,
+    // The root prefix, or {root}/{machine_id} when there's more than one machine.
+    topic_prefix: String,
+    home_assistant_configs: Vec<(String, serde_json::Value)>,
     event_tx: Sender<Event>,
     cancel_token: CancellationToken,
     client: Option<AsyncClient>,
+    power_relay: Arc<dyn PowerRelay>,
 }
 
 impl Mqtt {
-    pub fn new(uri: &str, event_tx: Sender<Event>) -> Result<Self> {
+    pub fn new(
+        config: &Config,
+        power_relay: Arc<dyn PowerRelay>,
+        event_tx: Sender<Event>,
+    ) -> Result<Self> {
         let cancel_token = CancellationToken::new();
 
+        let uri = config
+            .mqtt_url
+            .as_deref()
+            .ok_or_else(|| anyhow!("No MQTT server configured"))?;
+
+        // Each machine has its own connection, so they need their own client IDs.
+        let uri = match &config.machine_id {
+            Some(machine_id) => with_client_id_suffix(uri, machine_id),
+            None => String::from(uri),
+        };
+
+        let topic_prefix =
+            machine_topic_prefix(&config.mqtt_topic_prefix, config.machine_id.as_deref());
+
+        let home_assistant_configs = if config.home_assistant.discovery {
+            home_assistant::discovery_configs(config, &topic_prefix)
+        } else {
+            vec![]
+        };
+
         let mqtt = Mqtt {
-            uri: String::from(uri),
+            uri,
+            root_topic_prefix: config.mqtt_topic_prefix.clone(),
+            topic_prefix,
+            home_assistant_configs,
             event_tx,
             cancel_token,
             client: None,
+            power_relay,
         };
 
         Ok(mqtt)
@@ -60,16 +129,20 @@ impl Mqtt {
 
         let internal_cancel_token = self.cancel_token.clone();
 
-        let options = MqttOptions::parse_url(self.uri.clone())?;
+        let options = mqtt_options(&self.uri, &self.topic(TOPIC_AVAILABILITY))?;
 
         let (client, mut event_loop) = AsyncClient::new(options, 10);
 
-        self.client = Some(client);
-
-        self.subscribe().await?;
+        self.client = Some(client.clone());
 
         let mut rx = self.event_tx.subscribe();
         let tx = self.event_tx.clone();
+        let power_relay = self.power_relay.clone();
+        let topic_prefix = self.topic_prefix.clone();
+
+        let subscriptions = self.subscriptions();
+        let availability_topic = self.topic(TOPIC_AVAILABILITY);
+        let home_assistant_configs = self.home_assistant_configs.clone();
 
         self.publish(&MqttOutgoingMessage::ModeUpdate(Mode::Idle))
             .await?;
@@ -77,11 +150,49 @@ impl Mqtt {
         task::spawn(async move {
             loop {
                 select! {
-                    Ok(notification) = event_loop.poll() => {
+                    notification = event_loop.poll() => {
+                        // The client reconnects the next time the event loop is polled.
+                        let notification = match notification {
+                            Ok(notification) => notification,
+                            Err(err) => {
+                                error!("MQTT connection error: {}", err);
+                                time::sleep(RECONNECT_DELAY).await;
+                                continue;
+                            }
+                        };
+
+                        // Announced from another task, since the requests are only sent while the event loop is polled.
+                        if let MqttEvent::Incoming(Packet::ConnAck(_)) = notification {
+                            info!("Connected to the MQTT server");
+
+                            let announcement = announce(
+                                client.clone(),
+                                subscriptions.clone(),
+                                availability_topic.clone(),
+                                home_assistant_configs.clone(),
+                            );
+
+                            task::spawn(async move {
+                                if let Err(err) = announcement.await {
+                                    error!("Failed to announce gesha to the MQTT server: {}", err);
+                                }
+                            });
+                        }
+
                         if let MqttEvent::Incoming(Packet::Publish(publish_event)) = notification {
                             debug!("Received = {:?}", publish_event);
 
-                            match TryInto::<Event>::try_into(publish_event) {
+                            // Messages from the power relay are parsed by its driver.
+                            let relay_message = str::from_utf8(&publish_event.topic)
+                                .ok()
+                                .and_then(|topic| power_relay.parse(topic, &publish_event.payload));
+
+                            let event = match relay_message {
+                                Some(message) => message.map(|message| Event::IncomingMqttMessage(message, None)),
+                                None => parse_incoming(&topic_prefix, publish_event),
+                            };
+
+                            match event {
                                 Ok(event) => {
                                     debug!("Sending event: {:?}", event);
                                     if let Err(err) = tx.send(event) {
@@ -127,6 +238,27 @@ impl Mqtt {
                                     error!("Failed to send event: {}", err);
                                 }
                             }
+                            Event::ControllerTelemetryChanged(sample) => {
+                                if let Err(err) = tx.send(Event::OutgoingMqttMessage(
+                                    MqttOutgoingMessage::ControllerTelemetryUpdate(sample),
+                                )) {
+                                    error!("Failed to send event: {}", err);
+                                }
+                            }
+                            Event::ControllerDegradationChanged(degradation) => {
+                                if let Err(err) = tx.send(Event::OutgoingMqttMessage(
+                                    MqttOutgoingMessage::ControllerDegradationUpdate(degradation),
+                                )) {
+                                    error!("Failed to send event: {}", err);
+                                }
+                            }
+                            Event::ThermalModelChanged(thermal_model) => {
+                                if let Err(err) = tx.send(Event::OutgoingMqttMessage(
+                                    MqttOutgoingMessage::ThermalModelUpdate(thermal_model),
+                                )) {
+                                    error!("Failed to send event: {}", err);
+                                }
+                            }
                             _ => {}
                         }
                     },
@@ -144,7 +276,12 @@ impl Mqtt {
         self.client
             .as_ref()
             .unwrap()
-            .publish("gesha/mode", QoS::AtLeastOnce, true, "offline")
+            .publish(
+                self.topic(TOPIC_AVAILABILITY),
+                QoS::AtLeastOnce,
+                true,
+                AVAILABILITY_OFFLINE,
+            )
             .await?;
 
         // Wait for a tick of the event loop to ensure the goodbye message is sent...
@@ -156,29 +293,41 @@ impl Mqtt {
         Ok(())
     }
 
-    pub async fn subscribe(&self) -> Result<()> {
-        if let Some(client) = &self.client {
-            let topics = vec![
-                TOPIC_EXTERN_POWER_STATUS,
-                TOPIC_EXTERN_POWER_STATE_CHANGE,
-                TOPIC_CONTROL_METHOD_CHANGE_REQUEST,
-                TOPIC_TARGET_TEMPERATURE_CHANGE_REQUEST,
-                TOPIC_MODE_CHANGE,
-                TOPIC_TEMPERATURE_HISTORY_REQUEST,
-                TOPIC_MANUAL_BOILER_HEAT_LEVEL_REQUEST,
-                TOPIC_SHOT_HISTORY_REQUEST,
-                TOPIC_CONFIG_SET,
-            ];
-            for topic in topics {
-                client
-                    .subscribe(topic, QoS::ExactlyOnce)
-                    .await
-                    .map_err(|err| anyhow!("Failed to subscribe to {}, got {}", topic, err))?;
-            }
-            Ok(())
-        } else {
-            Err(anyhow!("No MQTT client available"))
-        }
+    fn topic(&self, topic: &str) -> String {
+        format!("{}/{topic}", self.topic_prefix)
+    }
+
+    fn subscriptions(&self) -> Vec<String> {
+        let mut topics: Vec<String> = [
+            TOPIC_CONTROL_METHOD_CHANGE_REQUEST,
+            TOPIC_TARGET_TEMPERATURE_CHANGE_REQUEST,
+            TOPIC_BREW_TARGET_TEMPERATURE_CHANGE_REQUEST,
+            TOPIC_STEAM_TARGET_TEMPERATURE_CHANGE_REQUEST,
+            TOPIC_MODE_CHANGE,
+            TOPIC_TEMPERATURE_HISTORY_REQUEST,
+            TOPIC_MANUAL_BOILER_HEAT_LEVEL_REQUEST,
+            TOPIC_SHOT_HISTORY_REQUEST,
+            TOPIC_CONFIG_SET,
+            TOPIC_MODELS_RELOAD,
+            TOPIC_SCHEDULE_CREATE,
+            TOPIC_SCHEDULE_DELETE,
+            TOPIC_SCHEDULE_LIST,
+            TOPIC_PROFILE_CREATE,
+            TOPIC_PROFILE_UPDATE,
+            TOPIC_PROFILE_DELETE,
+            TOPIC_PROFILE_LIST,
+            TOPIC_PROFILE_ACTIVATE,
+            TOPIC_STANDBY_TARGET_TEMPERATURE_CHANGE_REQUEST,
+            TOPIC_WAKE,
+            TOPIC_EVENT_HISTORY_REQUEST,
+            TOPIC_STATE_REQUEST,
+        ]
+        .iter()
+        .map(|topic| self.topic(topic))
+        .collect();
+        topics.extend(self.power_relay.topics());
+
+        topics
     }
 
     pub async fn publish(&self, message: &MqttOutgoingMessage) -> Result<()> {
@@ -187,48 +336,152 @@ impl Mqtt {
         }
 
         let (topic, payload, retain) = match message {
-            MqttOutgoingMessage::ExternRelayPowerStateSetCmd(power_status) => (
-                TOPIC_EXTERN_POWER_COMMAND.to_string(),
-                (if *power_status { "ON" } else { "OFF" }).to_string(),
+            // Relays that aren't switched over MQTT are switched by set_power.
+            MqttOutgoingMessage::ExternRelayPowerStateSetCmd(power_status) => {
+                match self.power_relay.set_power(*power_status)? {
+                    Some(command) => (command.topic, command.payload, false),
+                    None => return Ok(()),
+                }
+            }
+            MqttOutgoingMessage::RelayCommandFailedUpdate(failure) => (
+                self.topic("relay/error"),
+                serde_json::to_string(failure)?,
                 false,
             ),
             MqttOutgoingMessage::ModeUpdate(status) => (
-                String::from("gesha/mode"),
+                self.topic("mode"),
                 serde_json::to_string(status)?,
                 true,
             ),
+            MqttOutgoingMessage::ModeTransitionUpdate(transition) => (
+                self.topic("mode/transition"),
+                serde_json::to_string(transition)?,
+                false,
+            ),
             MqttOutgoingMessage::BoilerStatusUpdate(heat_level) => (
-                String::from("gesha/boiler_level"),
+                self.topic("boiler_level"),
                 serde_json::to_string(heat_level)?,
                 true,
             ),
             MqttOutgoingMessage::TemperatureUpdate(instrument, measurement) => (
-                format!("gesha/temperature/{instrument}"),
+                self.topic(&format!("temperature/{instrument}")),
                 serde_json::to_string(measurement)?,
                 true,
             ),
+            MqttOutgoingMessage::PredictedTemperatureUpdate(instrument, prediction) => (
+                self.topic(&format!("temperature/{instrument}")),
+                serde_json::to_string(prediction)?,
+                true,
+            ),
             MqttOutgoingMessage::TargetTemperatureUpdate(temp) => (
-                format!("gesha/temperature/target"),
+                self.topic("temperature/target"),
+                serde_json::to_string(temp)?,
+                true,
+            ),
+            MqttOutgoingMessage::ModeTargetTemperatureUpdate(mode, temp) => (
+                self.topic(&format!(
+                    "temperature/target/{}",
+                    serde_plain::to_string(mode)?
+                )),
                 serde_json::to_string(temp)?,
                 true,
             ),
+            MqttOutgoingMessage::TargetTemperatureRejected(rejection) => (
+                self.topic("temperature/target/rejected"),
+                serde_json::to_string(rejection)?,
+                false,
+            ),
             MqttOutgoingMessage::ControlMethodUpdate(control_method) => (
-                format!("gesha/control_method"),
+                self.topic("control_method"),
                 serde_json::to_string(control_method)?,
                 true,
             ),
+            MqttOutgoingMessage::ControllerTelemetryUpdate(sample) => (
+                self.topic("controller/telemetry"),
+                serde_json::to_string(sample)?,
+                false,
+            ),
+            // null when the controller is working normally.
+            MqttOutgoingMessage::ControllerDegradationUpdate(degradation) => (
+                self.topic("controller/degraded"),
+                serde_json::to_string(degradation)?,
+                true,
+            ),
             MqttOutgoingMessage::TemperatureHistoryResponse(id, result) => (
-                format!("gesha/temperature/history/{id}"),
+                self.topic(&format!("temperature/history/{id}")),
                 result.to_string(),
                 false,
             ),
             MqttOutgoingMessage::ShotHistoryResponse(id, result) => (
-                format!("gesha/shot/history/{id}"),
+                self.topic(&format!("shot/history/{id}")),
                 result.to_string(),
                 false,
             ),
+            MqttOutgoingMessage::StateUpdate(snapshot) => (
+                self.topic("state"),
+                serde_json::to_string(snapshot)?,
+                true,
+            ),
+            MqttOutgoingMessage::StateResponse(id, snapshot) => (
+                self.topic(&format!("state/{id}")),
+                serde_json::to_string(snapshot)?,
+                false,
+            ),
+            MqttOutgoingMessage::EventHistoryResponse(id, result) => (
+                self.topic(&format!("event/history/{id}")),
+                result.to_string(),
+                false,
+            ),
+            MqttOutgoingMessage::ShotTimerUpdate(update) => (
+                self.topic("shot/timer"),
+                serde_json::to_string(update)?,
+                false,
+            ),
+            MqttOutgoingMessage::ModelsUpdate(info) => (
+                self.topic("models"),
+                serde_json::to_string(info)?,
+                true,
+            ),
+            MqttOutgoingMessage::ThermalModelUpdate(thermal_model) => (
+                self.topic("thermal_model"),
+                serde_json::to_string(thermal_model)?,
+                true,
+            ),
+            MqttOutgoingMessage::EtaUpdate(eta) => (
+                self.topic("eta"),
+                serde_json::to_string(eta)?,
+                true,
+            ),
+            // null when the machine isn't about to be turned off.
+            MqttOutgoingMessage::AutoOffWarningUpdate(warning) => (
+                self.topic("auto_off/warning"),
+                serde_json::to_string(warning)?,
+                true,
+            ),
+            MqttOutgoingMessage::SchedulesUpdate(schedules) => (
+                self.topic("schedule"),
+                serde_json::to_string(schedules)?,
+                true,
+            ),
+            MqttOutgoingMessage::ProfilesUpdate(profiles) => (
+                self.topic("profile"),
+                serde_json::to_string(profiles)?,
+                true,
+            ),
+            // null when no profile is active.
+            MqttOutgoingMessage::ActiveProfileUpdate(profile) => (
+                self.topic("profile/active"),
+                serde_json::to_string(profile)?,
+                true,
+            ),
+            // Machines share the discovery topic, so it isn't under the machine's prefix.
+            MqttOutgoingMessage::MachinesUpdate(machines) => (
+                format!("{}/machines", self.root_topic_prefix),
+                serde_json::to_string(machines)?,
+                true,
+            ),
             MqttOutgoingMessage::ConfigUpdate(config_item) => (
-                format!("gesha/config/{}", config_item.key),
+                self.topic(&format!("config/{}", config_item.key)),
                 config_item.value.to_string(),
                 true,
             ),
@@ -245,20 +498,110 @@ impl Mqtt {
     }
 }
 
-#[derive(Clone, Debug)]
+// An entry of gesha/machines, listing the machines run by this instance.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MachineInfo {
+    pub id: String,
+    pub topic_prefix: String,
+}
+
+// The broker publishes the last will if gesha disconnects without stopping, e.g. when it crashes or loses power.
+fn mqtt_options(uri: &str, availability_topic: &str) -> Result<MqttOptions> {
+    let mut options = MqttOptions::parse_url(uri)?;
+
+    options.set_last_will(LastWill::new(
+        availability_topic,
+        AVAILABILITY_OFFLINE,
+        QoS::AtLeastOnce,
+        true,
+        None,
+    ));
+
+    Ok(options)
+}
+
+// Subscribes and announces that gesha is online, after every (re)connect.
+// The session is clean, so the subscriptions are lost when the connection is,
+// and the broker may have published the last will.
+async fn announce(
+    client: AsyncClient,
+    subscriptions: Vec<String>,
+    availability_topic: String,
+    home_assistant_configs: Vec<(String, serde_json::Value)>,
+) -> Result<()> {
+    for topic in subscriptions {
+        client
+            .subscribe(&topic, QoS::ExactlyOnce)
+            .await
+            .map_err(|err| anyhow!("Failed to subscribe to {}, got {}", topic, err))?;
+    }
+
+    for (topic, config) in home_assistant_configs.iter() {
+        client
+            .publish(topic, QoS::AtLeastOnce, true, serde_json::to_string(config)?)
+            .await
+            .map_err(|err| anyhow!("Failed to publish {}, got {}", topic, err))?;
+    }
+
+    client
+        .publish(availability_topic, QoS::AtLeastOnce, true, AVAILABILITY_ONLINE)
+        .await
+        .map_err(|err| anyhow!("Failed to publish availability, got {}", err))?;
+
+    Ok(())
+}
+
+pub fn machine_topic_prefix(root_topic_prefix: &str, machine_id: Option<&str>) -> String {
+    match machine_id {
+        Some(machine_id) => format!("{root_topic_prefix}/{machine_id}"),
+        None => String::from(root_topic_prefix),
+    }
+}
+
+// Appends -{suffix} to the client_id in the URL, or adds one if there isn't one.
+fn with_client_id_suffix(uri: &str, suffix: &str) -> String {
+    match uri.find("client_id=") {
+        Some(start) => {
+            let end = uri[start..]
+                .find('&')
+                .map(|end| start + end)
+                .unwrap_or(uri.len());
+
+            format!("{}-{suffix}{}", &uri[..end], &uri[end..])
+        }
+        None if uri.contains('?') => format!("{uri}&client_id=gesha-{suffix}"),
+        None => format!("{uri}?client_id=gesha-{suffix}"),
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", content = "payload", rename_all = "camelCase")]
 pub enum MqttIncomingMessage {
     ExternRelayAvailabilityChanged(bool),
     ExternRelayPowerStateChanged(IsPowerOn),
     ControlMethodSet(ControlMethod),
-    TemperatureTargetSet(f32),
+    TemperatureTargetSet(TargetMode, f32),
     ModeSet(Mode),
+    Wake,
     TemperatureHistoryRequest(Range),
     BoilerLevelSet(f32),
     ShotHistoryRequest(Range),
+    EventHistoryRequest(Range),
+    StateRequest(StateRequest),
     ConfigSet(ConfigItem),
+    ModelsReloadRequest,
+    ScheduleCreate(NewSchedule),
+    ScheduleDelete(i64),
+    ScheduleListRequest,
+    ProfileCreate(ProfileSettings),
+    ProfileUpdate(Profile),
+    ProfileDelete(i64),
+    ProfileListRequest,
+    ProfileActivate(i64),
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Range {
     pub id: String,
@@ -268,17 +611,38 @@ pub struct Range {
     pub bucket_size: Option<i64>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", content = "payload", rename_all = "camelCase")]
 pub enum MqttOutgoingMessage {
     ExternRelayPowerStateSetCmd(IsPowerOn),
+    RelayCommandFailedUpdate(RelayCommandFailure),
     ModeUpdate(Mode),
+    ModeTransitionUpdate(ModeTransition),
     BoilerStatusUpdate(ValueChange),
     TemperatureUpdate(String, ValueChange),
+    PredictedTemperatureUpdate(String, PredictionChange),
     TemperatureHistoryResponse(String, String),
+    // The target the boiler is currently being held at, whichever mode it's for.
     TargetTemperatureUpdate(f32),
+    ModeTargetTemperatureUpdate(TargetMode, f32),
+    TargetTemperatureRejected(TargetTemperatureRejection),
     ControlMethodUpdate(ControlMethod),
+    ControllerTelemetryUpdate(ControllerTelemetrySample),
+    ControllerDegradationUpdate(Option<ControllerDegradation>),
     ShotHistoryResponse(String, String),
+    EventHistoryResponse(String, String),
+    StateUpdate(StateSnapshot),
+    StateResponse(String, StateSnapshot),
+    ShotTimerUpdate(ShotTimerUpdate),
     ConfigUpdate(ConfigItem),
+    MachinesUpdate(Vec<MachineInfo>),
+    ModelsUpdate(PredictiveModelsInfo),
+    ThermalModelUpdate(ThermalModel),
+    EtaUpdate(Eta),
+    SchedulesUpdate(Vec<Schedule>),
+    ProfilesUpdate(Vec<Profile>),
+    ActiveProfileUpdate(Option<Profile>),
+    AutoOffWarningUpdate(Option<AutoOffWarning>),
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -288,78 +652,328 @@ pub struct ValueChange {
     pub timestamp: i64,
 }
 
-impl TryInto<Event> for Publish {
-    type Error = anyhow::Error;
+// Published on gesha/temperature/target/rejected when a target is out of the mode's configured range.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TargetTemperatureRejection {
+    pub mode: TargetMode,
+    pub temperature: f32,
+    pub min: f32,
+    pub max: f32,
+    pub reason: String,
+}
 
-    fn try_into(self) -> std::result::Result<Event, Self::Error> {
-        let topic = str::from_utf8(&self.topic)?;
+// A predicted value with its prediction interval, the bounds are None until there's enough data to estimate them.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PredictionChange {
+    pub value: f32,
+    pub lower: Option<f32>,
+    pub upper: Option<f32>,
+    pub timestamp: i64,
+}
 
-        match topic {
-            TOPIC_CONTROL_METHOD_CHANGE_REQUEST => {
-                let control_method = serde_yaml::from_slice(&self.payload)?;
-                Ok(Event::IncomingMqttMessage(
-                    MqttIncomingMessage::ControlMethodSet(control_method),
-                ))
-            }
-            TOPIC_TARGET_TEMPERATURE_CHANGE_REQUEST => Ok(Event::IncomingMqttMessage(
-                MqttIncomingMessage::TemperatureTargetSet(serde_yaml::from_slice(&self.payload)?),
-            )),
-            TOPIC_MODE_CHANGE => {
-                let mode: Mode = serde_yaml::from_slice(&self.payload)?;
-                Ok(Event::IncomingMqttMessage(MqttIncomingMessage::ModeSet(
-                    mode,
-                )))
-            }
-            TOPIC_TEMPERATURE_HISTORY_REQUEST => {
-                let range: Range = serde_json::from_slice(&self.payload)?;
+// Parses a message on one of the machine's topics, matching the topic without the machine's prefix.
+fn parse_incoming(topic_prefix: &str, publish: Publish) -> Result<Event> {
+    let full_topic = str::from_utf8(&publish.topic)?;
+
+    let topic = full_topic
+        .strip_prefix(topic_prefix)
+        .and_then(|topic| topic.strip_prefix('/'))
+        .ok_or_else(|| anyhow!("There is no incoming message for the topic {}", full_topic))?;
+
+    // Set by clients so that their commands are attributed to them in the event log.
+    let client_id = publish.properties.as_ref().and_then(|properties| {
+        properties
+            .user_properties
+            .iter()
+            .find(|(key, _)| key == USER_PROPERTY_CLIENT_ID)
+            .map(|(_, value)| value.clone())
+    });
+
+    let message = match topic {
+        TOPIC_CONTROL_METHOD_CHANGE_REQUEST => {
+            let control_method = serde_yaml::from_slice(&publish.payload)?;
+            Ok(MqttIncomingMessage::ControlMethodSet(control_method))
+        }
+        TOPIC_TARGET_TEMPERATURE_CHANGE_REQUEST
+        | TOPIC_BREW_TARGET_TEMPERATURE_CHANGE_REQUEST => {
+            Ok(MqttIncomingMessage::TemperatureTargetSet(
+                TargetMode::Brew,
+                serde_yaml::from_slice(&publish.payload)?,
+            ))
+        }
+        TOPIC_STEAM_TARGET_TEMPERATURE_CHANGE_REQUEST => {
+            Ok(MqttIncomingMessage::TemperatureTargetSet(
+                TargetMode::Steam,
+                serde_yaml::from_slice(&publish.payload)?,
+            ))
+        }
+        TOPIC_STANDBY_TARGET_TEMPERATURE_CHANGE_REQUEST => {
+            Ok(MqttIncomingMessage::TemperatureTargetSet(
+                TargetMode::Standby,
+                serde_yaml::from_slice(&publish.payload)?,
+            ))
+        }
+        TOPIC_WAKE => Ok(MqttIncomingMessage::Wake),
+        TOPIC_MODE_CHANGE => {
+            let mode: Mode = serde_yaml::from_slice(&publish.payload)?;
+            Ok(MqttIncomingMessage::ModeSet(mode))
+        }
+        TOPIC_TEMPERATURE_HISTORY_REQUEST => {
+            let range: Range = serde_json::from_slice(&publish.payload)?;
 
-                Ok(Event::IncomingMqttMessage(
-                    MqttIncomingMessage::TemperatureHistoryRequest(range),
-                ))
-            }
-            TOPIC_EXTERN_POWER_STATUS => Ok(Event::IncomingMqttMessage(
-                MqttIncomingMessage::ExternRelayAvailabilityChanged(self.payload == "online"),
-            )),
-            TOPIC_EXTERN_POWER_STATE_CHANGE => {
-                let power_state: IsPowerOn = if self.payload == "ON" { true } else { false };
-
-                Ok(Event::IncomingMqttMessage(
-                    MqttIncomingMessage::ExternRelayPowerStateChanged(power_state),
+            Ok(MqttIncomingMessage::TemperatureHistoryRequest(range))
+        }
+        TOPIC_MANUAL_BOILER_HEAT_LEVEL_REQUEST => {
+            let heat_level: f32 = serde_yaml::from_slice(&publish.payload)?;
+
+            Ok(MqttIncomingMessage::BoilerLevelSet(heat_level))
+        }
+        TOPIC_CONFIG_SET => {
+            let config_item: ConfigItem = serde_json::from_slice(&publish.payload)?;
+
+            if config_item.key.starts_with("ui_") {
+                info!("Setting config {:?}", config_item);
+
+                Ok(MqttIncomingMessage::ConfigSet(config_item))
+            } else {
+                Err(anyhow!(
+                    "Refusing to set a config entry that isn't prefixed with 'ui_'."
                 ))
             }
-            TOPIC_MANUAL_BOILER_HEAT_LEVEL_REQUEST => {
-                let heat_level: f32 = serde_yaml::from_slice(&self.payload)?;
+        }
+        TOPIC_MODELS_RELOAD => Ok(MqttIncomingMessage::ModelsReloadRequest),
+        TOPIC_SCHEDULE_CREATE => {
+            let schedule: NewSchedule = serde_json::from_slice(&publish.payload)?;
 
-                Ok(Event::IncomingMqttMessage(
-                    MqttIncomingMessage::BoilerLevelSet(heat_level),
-                ))
+            Ok(MqttIncomingMessage::ScheduleCreate(schedule))
+        }
+        TOPIC_SCHEDULE_DELETE => Ok(MqttIncomingMessage::ScheduleDelete(
+            serde_yaml::from_slice(&publish.payload)?,
+        )),
+        TOPIC_SCHEDULE_LIST => Ok(MqttIncomingMessage::ScheduleListRequest),
+        TOPIC_PROFILE_CREATE => {
+            let profile: ProfileSettings = serde_json::from_slice(&publish.payload)?;
+
+            Ok(MqttIncomingMessage::ProfileCreate(profile))
+        }
+        TOPIC_PROFILE_UPDATE => {
+            let profile: Profile = serde_json::from_slice(&publish.payload)?;
+
+            Ok(MqttIncomingMessage::ProfileUpdate(profile))
+        }
+        TOPIC_PROFILE_DELETE => Ok(MqttIncomingMessage::ProfileDelete(serde_yaml::from_slice(
+            &publish.payload,
+        )?)),
+        TOPIC_PROFILE_LIST => Ok(MqttIncomingMessage::ProfileListRequest),
+        TOPIC_PROFILE_ACTIVATE => Ok(MqttIncomingMessage::ProfileActivate(
+            serde_yaml::from_slice(&publish.payload)?,
+        )),
+        TOPIC_SHOT_HISTORY_REQUEST => {
+            let range: Range = serde_json::from_slice(&publish.payload)?;
+
+            Ok(MqttIncomingMessage::ShotHistoryRequest(range))
+        }
+        TOPIC_EVENT_HISTORY_REQUEST => {
+            let range: Range = serde_json::from_slice(&publish.payload)?;
+
+            Ok(MqttIncomingMessage::EventHistoryRequest(range))
+        }
+        TOPIC_STATE_REQUEST => {
+            let request: StateRequest = serde_json::from_slice(&publish.payload)?;
+
+            Ok(MqttIncomingMessage::StateRequest(request))
+        }
+        _ => Err(anyhow!(
+            "There is no incoming message for the topic {}",
+            topic
+        )),
+    }?;
+
+    Ok(Event::IncomingMqttMessage(message, client_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use rumqttc::v5::mqttbytes::{
+        v5::{ConnAck, ConnectReturnCode, PublishProperties},
+        Error as MqttBytesError,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::broadcast,
+    };
+
+    use crate::core::power_relay;
+
+    #[test]
+    fn the_last_will_marks_the_machine_offline() {
+        let options = mqtt_options("mqtt://localhost:1883?client_id=gesha", "kitchen/silvia/availability").unwrap();
+        let last_will = options.last_will().unwrap();
+
+        assert_eq!(last_will.topic, "kitchen/silvia/availability");
+        assert_eq!(last_will.message, AVAILABILITY_OFFLINE);
+        assert_eq!(last_will.qos, QoS::AtLeastOnce);
+        assert!(last_will.retain);
+    }
+
+    // One client connection to a fake broker, which acknowledges the connection and nothing else.
+    struct BrokerConnection {
+        stream: TcpStream,
+        buffer: BytesMut,
+    }
+
+    impl BrokerConnection {
+        async fn accept(listener: &TcpListener) -> BrokerConnection {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut connection = BrokerConnection {
+                stream,
+                buffer: BytesMut::new(),
+            };
+
+            assert!(matches!(connection.read().await, Packet::Connect(..)));
+
+            let mut buffer = BytesMut::new();
+            Packet::ConnAck(ConnAck {
+                session_present: false,
+                code: ConnectReturnCode::Success,
+                properties: None,
+            })
+            .write(&mut buffer)
+            .unwrap();
+            connection.stream.write_all(&buffer).await.unwrap();
+
+            connection
+        }
+
+        async fn read(&mut self) -> Packet {
+            loop {
+                match Packet::read(&mut self.buffer, None) {
+                    Ok(packet) => return packet,
+                    Err(MqttBytesError::InsufficientBytes(_)) => {
+                        assert!(self.stream.read_buf(&mut self.buffer).await.unwrap() > 0);
+                    }
+                    Err(err) => panic!("Failed to read a packet: {err:?}"),
+                }
             }
-            TOPIC_CONFIG_SET => {
-                let config_item: ConfigItem = serde_json::from_slice(&self.payload)?;
-
-                if config_item.key.starts_with("ui_") {
-                    info!("Setting config {:?}", config_item);
-
-                    Ok(Event::IncomingMqttMessage(MqttIncomingMessage::ConfigSet(
-                        config_item,
-                    )))
-                } else {
-                    Err(anyhow!(
-                        "Refusing to set a config entry that isn't prefixed with 'ui_'."
-                    ))
+        }
+
+        // The next message published on the topic, checking that nothing else is published as offline on the way.
+        async fn published(&mut self, topic: &str) -> Publish {
+            loop {
+                if let Packet::Publish(publish) = self.read().await {
+                    if publish.topic == topic {
+                        return publish;
+                    }
+
+                    assert_ne!(publish.payload, AVAILABILITY_OFFLINE, "{publish:?}");
                 }
             }
-            TOPIC_SHOT_HISTORY_REQUEST => {
-                let range: Range = serde_json::from_slice(&self.payload)?;
+        }
+    }
 
-                Ok(Event::IncomingMqttMessage(
-                    MqttIncomingMessage::ShotHistoryRequest(range),
-                ))
+    #[tokio::test]
+    async fn online_is_announced_on_every_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let config: Config = serde_yaml::from_str(&format!(
+            "mqttUrl: mqtt://127.0.0.1:{port}?client_id=gesha-test\nmqttTopicPrefix: kitchen"
+        ))
+        .unwrap();
+
+        let (tx, _rx) = broadcast::channel(100);
+        let power_relay = power_relay::new_power_relay(&config.power_relay, tx.clone()).unwrap();
+        let mut mqtt = Mqtt::new(&config, power_relay, tx).unwrap();
+
+        mqtt.start().await.unwrap();
+
+        let announcements = async {
+            // Dropping the connection makes the client reconnect.
+            for _ in 0..2 {
+                let mut connection = BrokerConnection::accept(&listener).await;
+                let publish = connection.published("kitchen/availability").await;
+
+                assert_eq!(publish.payload, AVAILABILITY_ONLINE);
+                assert!(publish.retain);
             }
-            _ => Err(anyhow!(
-                "There is no incoming message for the topic {}",
-                topic
-            )),
+        };
+
+        time::timeout(time::Duration::from_secs(10), announcements)
+            .await
+            .unwrap();
+
+        mqtt.cancel_token.cancel();
+    }
+
+    fn publish(topic: &str, payload: &'static str, client_id: Option<&str>) -> Publish {
+        let properties = client_id.map(|client_id| PublishProperties {
+            user_properties: vec![(USER_PROPERTY_CLIENT_ID.to_string(), client_id.to_string())],
+            ..Default::default()
+        });
+
+        Publish::new(topic, QoS::AtLeastOnce, payload, properties)
+    }
+
+    #[test]
+    fn incoming_topics_are_matched_without_the_prefix() {
+        let table = [
+            ("gesha", "gesha/mode/set"),
+            ("kitchen", "kitchen/mode/set"),
+            ("kitchen/silvia", "kitchen/silvia/mode/set"),
+        ];
+
+        for (topic_prefix, topic) in table {
+            let event = parse_incoming(topic_prefix, publish(topic, "active", Some("gesha-ui"))).unwrap();
+
+            assert!(
+                matches!(
+                    &event,
+                    Event::IncomingMqttMessage(MqttIncomingMessage::ModeSet(Mode::Active), Some(client_id))
+                        if client_id == "gesha-ui"
+                ),
+                "{topic}: {event:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn foreign_topics_are_rejected() {
+        let table = [
+            ("gesha", "kitchen/mode/set"),
+            ("gesha", "geshas/mode/set"),
+            ("gesha", "mode/set"),
+            ("kitchen/silvia", "kitchen/gaggia/mode/set"),
+            ("kitchen/silvia", "kitchen/mode/set"),
+        ];
+
+        for (topic_prefix, topic) in table {
+            assert!(parse_incoming(topic_prefix, publish(topic, "active", None)).is_err(), "{topic}");
+        }
+    }
+
+    #[test]
+    fn client_id_suffix() {
+        let table = [
+            ("mqtt://broker:1883", "mqtt://broker:1883?client_id=gesha-silvia"),
+            ("mqtt://broker:1883?keep_alive_secs=5", "mqtt://broker:1883?keep_alive_secs=5&client_id=gesha-silvia"),
+            ("mqtt://broker:1883?client_id=kitchen", "mqtt://broker:1883?client_id=kitchen-silvia"),
+            (
+                "mqtt://broker:1883?client_id=kitchen&keep_alive_secs=5",
+                "mqtt://broker:1883?client_id=kitchen-silvia&keep_alive_secs=5",
+            ),
+            (
+                "mqtt://broker:1883?keep_alive_secs=5&client_id=kitchen",
+                "mqtt://broker:1883?keep_alive_secs=5&client_id=kitchen-silvia",
+            ),
+        ];
+
+        for (uri, expected) in table {
+            assert_eq!(with_client_id_suffix(uri, "silvia"), expected, "{uri}");
         }
     }
 }
@@ -0,0 +1,100 @@
+use anyhow::Result;
+
+use super::{PowerRelay, RelayCommand};
+use crate::core::{mqtt::MqttIncomingMessage, state::IsPowerOn};
+
+// An ESPHome switch, using ESPHome's default topics.
+pub struct EsphomeRelay {
+    status_topic: String,
+    state_topic: String,
+    command_topic: String,
+}
+
+impl EsphomeRelay {
+    pub fn new(device: &str, switch: &str) -> Self {
+        EsphomeRelay {
+            status_topic: format!("{device}/status"),
+            state_topic: format!("{device}/switch/{switch}/state"),
+            command_topic: format!("{device}/switch/{switch}/command"),
+        }
+    }
+}
+
+impl PowerRelay for EsphomeRelay {
+    fn topics(&self) -> Vec<String> {
+        vec![self.status_topic.clone(), self.state_topic.clone()]
+    }
+
+    fn parse(&self, topic: &str, payload: &[u8]) -> Option<Result<MqttIncomingMessage>> {
+        if topic == self.status_topic {
+            Some(Ok(MqttIncomingMessage::ExternRelayAvailabilityChanged(
+                payload == b"online",
+            )))
+        } else if topic == self.state_topic {
+            Some(Ok(MqttIncomingMessage::ExternRelayPowerStateChanged(
+                payload == b"ON",
+            )))
+        } else {
+            None
+        }
+    }
+
+    fn set_power(&self, power_state: IsPowerOn) -> Result<Option<RelayCommand>> {
+        Ok(Some(RelayCommand {
+            topic: self.command_topic.clone(),
+            payload: String::from(if power_state { "ON" } else { "OFF" }),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::power_relay::tests::{assert_commands, assert_parses};
+
+    #[test]
+    fn commands_and_messages() {
+        let relay = EsphomeRelay::new("machine", "power");
+
+        assert_eq!(
+            relay.topics(),
+            ["machine/status", "machine/switch/power/state"]
+        );
+
+        assert_commands(
+            &relay,
+            &[
+                (true, "machine/switch/power/command", "ON"),
+                (false, "machine/switch/power/command", "OFF"),
+            ],
+        );
+
+        assert_parses(
+            &relay,
+            &[
+                (
+                    "machine/status",
+                    b"online",
+                    Some(MqttIncomingMessage::ExternRelayAvailabilityChanged(true)),
+                ),
+                (
+                    "machine/status",
+                    b"offline",
+                    Some(MqttIncomingMessage::ExternRelayAvailabilityChanged(false)),
+                ),
+                (
+                    "machine/switch/power/state",
+                    b"ON",
+                    Some(MqttIncomingMessage::ExternRelayPowerStateChanged(true)),
+                ),
+                (
+                    "machine/switch/power/state",
+                    b"OFF",
+                    Some(MqttIncomingMessage::ExternRelayPowerStateChanged(false)),
+                ),
+                ("machine/switch/other/state", b"ON", None),
+                ("machine/switch/power/command", b"ON", None),
+            ],
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use anyhow::Result;
+use tokio::sync::broadcast::Sender;
+
+use super::{PowerRelay, RelayCommand};
+use crate::core::{
+    mqtt::MqttIncomingMessage,
+    state::{Event, IsPowerOn},
+};
+
+// A relay wired to a GPIO pin, it's always available and reports its state as soon as it's switched.
+pub struct GpioRelay {
+    output: std::sync::Mutex<RelayOutput>,
+    event_tx: Sender<Event>,
+}
+
+impl GpioRelay {
+    pub fn new(pin: u8, active_low: bool, event_tx: Sender<Event>) -> Result<Self> {
+        let relay = GpioRelay {
+            output: std::sync::Mutex::new(RelayOutput::new(pin, active_low)?),
+            event_tx,
+        };
+
+        relay.send(MqttIncomingMessage::ExternRelayAvailabilityChanged(true))?;
+
+        Ok(relay)
+    }
+
+    fn send(&self, message: MqttIncomingMessage) -> Result<()> {
+        self.event_tx
+            .send(Event::IncomingMqttMessage(message, None))?;
+
+        Ok(())
+    }
+}
+
+impl PowerRelay for GpioRelay {
+    fn topics(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn parse(&self, _topic: &str, _payload: &[u8]) -> Option<Result<MqttIncomingMessage>> {
+        None
+    }
+
+    fn set_power(&self, power_state: IsPowerOn) -> Result<Option<RelayCommand>> {
+        self.output
+            .lock()
+            .map_err(|_| anyhow::anyhow!("The relay output lock is poisoned"))?
+            .set_power(power_state);
+
+        self.send(MqttIncomingMessage::ExternRelayPowerStateChanged(
+            power_state,
+        ))?;
+
+        Ok(None)
+    }
+}
+
+#[cfg(all(target_arch = "arm", target_os = "linux"))]
+struct RelayOutput {
+    pin: rppal::gpio::OutputPin,
+    active_low: bool,
+}
+
+#[cfg(all(target_arch = "arm", target_os = "linux"))]
+impl RelayOutput {
+    // The relay is switched off when gesha starts.
+    fn new(pin: u8, active_low: bool) -> Result<Self> {
+        let pin = rppal::gpio::Gpio::new()?.get(pin)?.into_output();
+
+        let mut output = RelayOutput { pin, active_low };
+
+        output.set_power(false);
+
+        Ok(output)
+    }
+
+    fn set_power(&mut self, power_state: IsPowerOn) {
+        if power_state != self.active_low {
+            self.pin.set_high();
+        } else {
+            self.pin.set_low();
+        }
+    }
+}
+
+#[cfg(not(all(target_arch = "arm", target_os = "linux")))]
+struct RelayOutput;
+
+#[cfg(not(all(target_arch = "arm", target_os = "linux")))]
+impl RelayOutput {
+    fn new(_pin: u8, _active_low: bool) -> Result<Self> {
+        Err(anyhow::anyhow!("GPIO is only available on ARM Linux"))
+    }
+
+    fn set_power(&mut self, _power_state: IsPowerOn) {}
+}
+
+// Off the Pi the pin is a no-op, so the relay can be built directly.
+#[cfg(all(test, not(all(target_arch = "arm", target_os = "linux"))))]
+mod tests {
+    use tokio::sync::broadcast;
+
+    use super::*;
+
+    #[test]
+    fn switching_reports_the_state() {
+        let (tx, mut rx) = broadcast::channel(10);
+        let relay = GpioRelay {
+            output: std::sync::Mutex::new(RelayOutput),
+            event_tx: tx,
+        };
+
+        assert!(relay.topics().is_empty());
+        assert!(relay.parse("gpio", b"ON").is_none());
+
+        for power_state in [true, false] {
+            assert!(relay.set_power(power_state).unwrap().is_none());
+
+            match rx.try_recv().unwrap() {
+                Event::IncomingMqttMessage(
+                    MqttIncomingMessage::ExternRelayPowerStateChanged(state),
+                    None,
+                ) => assert_eq!(state, power_state),
+                event => panic!("Unexpected event {event:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn the_pin_is_only_available_on_the_pi() {
+        let (tx, _rx) = broadcast::channel(10);
+
+        assert!(GpioRelay::new(17, false, tx).is_err());
+    }
+}
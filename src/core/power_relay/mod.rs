@@ -0,0 +1,90 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::sync::broadcast::Sender;
+
+use super::{
+    config::PowerRelayConfig,
+    mqtt::MqttIncomingMessage,
+    state::{Event, IsPowerOn},
+};
+
+mod esphome;
+mod gpio;
+mod shelly;
+mod tasmota;
+mod tracker;
+
+pub use esphome::EsphomeRelay;
+pub use gpio::GpioRelay;
+pub use shelly::ShellyRelay;
+pub use tasmota::TasmotaRelay;
+pub use tracker::{RelayCommandFailure, RelayCommandTracker};
+
+// The relay that switches the machine's power.
+// Drivers report the relay's availability and state with
+// MqttIncomingMessage::ExternRelayAvailabilityChanged and ExternRelayPowerStateChanged.
+pub trait PowerRelay: Send + Sync {
+    // The MQTT topics the relay publishes its availability and state on.
+    fn topics(&self) -> Vec<String>;
+
+    // Parses a message from the relay, None if the topic isn't one of the relay's.
+    fn parse(&self, topic: &str, payload: &[u8]) -> Option<Result<MqttIncomingMessage>>;
+
+    // Switches the relay, returning the message to publish for relays that are switched over MQTT.
+    fn set_power(&self, power_state: IsPowerOn) -> Result<Option<RelayCommand>>;
+}
+
+pub struct RelayCommand {
+    pub topic: String,
+    pub payload: String,
+}
+
+pub fn new_power_relay(
+    config: &PowerRelayConfig,
+    event_tx: Sender<Event>,
+) -> Result<Arc<dyn PowerRelay>> {
+    Ok(match config {
+        PowerRelayConfig::Esphome { device, switch } => Arc::new(EsphomeRelay::new(device, switch)),
+        PowerRelayConfig::Shelly {
+            topic_prefix,
+            switch_id,
+        } => Arc::new(ShellyRelay::new(topic_prefix, *switch_id)),
+        PowerRelayConfig::Tasmota { topic, relay } => Arc::new(TasmotaRelay::new(topic, *relay)),
+        PowerRelayConfig::Gpio { pin, active_low } => {
+            Arc::new(GpioRelay::new(*pin, *active_low, event_tx)?)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each (power state, topic, payload) is the command the relay should publish, in order.
+    pub(super) fn assert_commands(relay: &dyn PowerRelay, table: &[(IsPowerOn, &str, &str)]) {
+        for (power_state, topic, payload) in table {
+            let command = relay.set_power(*power_state).unwrap().unwrap();
+
+            assert_eq!(command.topic, *topic, "{power_state}");
+            assert_eq!(command.payload, *payload, "{power_state}");
+        }
+    }
+
+    // The message each (topic, payload) from the relay should be parsed to, None for other topics.
+    pub(super) fn assert_parses(
+        relay: &dyn PowerRelay,
+        table: &[(&str, &[u8], Option<MqttIncomingMessage>)],
+    ) {
+        for (topic, payload, expected) in table {
+            let parsed = relay.parse(topic, payload).map(|result| result.unwrap());
+
+            assert_eq!(
+                format!("{parsed:?}"),
+                format!("{expected:?}"),
+                "{topic} {}",
+                String::from_utf8_lossy(payload)
+            );
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::{PowerRelay, RelayCommand};
+use crate::core::{mqtt::MqttIncomingMessage, state::IsPowerOn};
+
+// The source of RPC requests, Shelly publishes its responses on {src}/rpc but we don't need them.
+const RPC_SOURCE: &str = "gesha";
+
+// A Shelly Gen2 switch using RPC over MQTT.
+// The device needs "Generic status update notifications" enabled so that it publishes the switch's status.
+pub struct ShellyRelay {
+    online_topic: String,
+    status_topic: String,
+    rpc_topic: String,
+    switch_id: u8,
+    request_id: AtomicU64,
+}
+
+#[derive(Deserialize)]
+struct SwitchStatus {
+    output: bool,
+}
+
+#[derive(Serialize)]
+struct SwitchSetRequest<'a> {
+    id: u64,
+    src: &'a str,
+    method: &'a str,
+    params: SwitchSetParams,
+}
+
+#[derive(Serialize)]
+struct SwitchSetParams {
+    id: u8,
+    on: bool,
+}
+
+impl ShellyRelay {
+    pub fn new(topic_prefix: &str, switch_id: u8) -> Self {
+        ShellyRelay {
+            online_topic: format!("{topic_prefix}/online"),
+            status_topic: format!("{topic_prefix}/status/switch:{switch_id}"),
+            rpc_topic: format!("{topic_prefix}/rpc"),
+            switch_id,
+            request_id: AtomicU64::new(1),
+        }
+    }
+}
+
+impl PowerRelay for ShellyRelay {
+    fn topics(&self) -> Vec<String> {
+        vec![self.online_topic.clone(), self.status_topic.clone()]
+    }
+
+    fn parse(&self, topic: &str, payload: &[u8]) -> Option<Result<MqttIncomingMessage>> {
+        if topic == self.online_topic {
+            Some(Ok(MqttIncomingMessage::ExternRelayAvailabilityChanged(
+                payload == b"true",
+            )))
+        } else if topic == self.status_topic {
+            Some(
+                serde_json::from_slice::<SwitchStatus>(payload)
+                    .map(|status| MqttIncomingMessage::ExternRelayPowerStateChanged(status.output))
+                    .map_err(|err| err.into()),
+            )
+        } else {
+            None
+        }
+    }
+
+    fn set_power(&self, power_state: IsPowerOn) -> Result<Option<RelayCommand>> {
+        let request = SwitchSetRequest {
+            id: self.request_id.fetch_add(1, Ordering::Relaxed),
+            src: RPC_SOURCE,
+            method: "Switch.Set",
+            params: SwitchSetParams {
+                id: self.switch_id,
+                on: power_state,
+            },
+        };
+
+        Ok(Some(RelayCommand {
+            topic: self.rpc_topic.clone(),
+            payload: serde_json::to_string(&request)?,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::power_relay::tests::{assert_commands, assert_parses};
+
+    #[test]
+    fn commands_and_messages() {
+        let relay = ShellyRelay::new("shelly", 1);
+
+        assert_eq!(relay.topics(), ["shelly/online", "shelly/status/switch:1"]);
+
+        // Each request has its own ID.
+        assert_commands(
+            &relay,
+            &[
+                (
+                    true,
+                    "shelly/rpc",
+                    r#"{"id":1,"src":"gesha","method":"Switch.Set","params":{"id":1,"on":true}}"#,
+                ),
+                (
+                    false,
+                    "shelly/rpc",
+                    r#"{"id":2,"src":"gesha","method":"Switch.Set","params":{"id":1,"on":false}}"#,
+                ),
+            ],
+        );
+
+        assert_parses(
+            &relay,
+            &[
+                (
+                    "shelly/online",
+                    b"true",
+                    Some(MqttIncomingMessage::ExternRelayAvailabilityChanged(true)),
+                ),
+                (
+                    "shelly/online",
+                    b"false",
+                    Some(MqttIncomingMessage::ExternRelayAvailabilityChanged(false)),
+                ),
+                (
+                    "shelly/status/switch:1",
+                    br#"{"id":1,"source":"WS_in","output":true,"apower":1200.5}"#,
+                    Some(MqttIncomingMessage::ExternRelayPowerStateChanged(true)),
+                ),
+                (
+                    "shelly/status/switch:1",
+                    br#"{"id":1,"output":false}"#,
+                    Some(MqttIncomingMessage::ExternRelayPowerStateChanged(false)),
+                ),
+                ("shelly/status/switch:0", br#"{"id":0,"output":true}"#, None),
+                ("shelly/rpc", b"{}", None),
+            ],
+        );
+    }
+
+    #[test]
+    fn invalid_statuses_are_errors() {
+        let relay = ShellyRelay::new("shelly", 0);
+
+        for payload in [&b"on"[..], br#"{"id":0}"#] {
+            assert!(matches!(
+                relay.parse("shelly/status/switch:0", payload),
+                Some(Err(_))
+            ));
+        }
+    }
+}
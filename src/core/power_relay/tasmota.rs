@@ -0,0 +1,124 @@
+use anyhow::Result;
+
+use super::{PowerRelay, RelayCommand};
+use crate::core::{mqtt::MqttIncomingMessage, state::IsPowerOn};
+
+// A Tasmota relay, using Tasmota's default full topic of %prefix%/%topic%/.
+pub struct TasmotaRelay {
+    lwt_topic: String,
+    state_topic: String,
+    command_topic: String,
+}
+
+impl TasmotaRelay {
+    // Devices with a single relay use POWER, the others POWER1, POWER2...
+    pub fn new(topic: &str, relay: Option<u8>) -> Self {
+        let power = match relay {
+            Some(relay) => format!("POWER{relay}"),
+            None => String::from("POWER"),
+        };
+
+        TasmotaRelay {
+            lwt_topic: format!("tele/{topic}/LWT"),
+            state_topic: format!("stat/{topic}/{power}"),
+            command_topic: format!("cmnd/{topic}/{power}"),
+        }
+    }
+}
+
+impl PowerRelay for TasmotaRelay {
+    fn topics(&self) -> Vec<String> {
+        vec![self.lwt_topic.clone(), self.state_topic.clone()]
+    }
+
+    fn parse(&self, topic: &str, payload: &[u8]) -> Option<Result<MqttIncomingMessage>> {
+        if topic == self.lwt_topic {
+            Some(Ok(MqttIncomingMessage::ExternRelayAvailabilityChanged(
+                payload == b"Online",
+            )))
+        } else if topic == self.state_topic {
+            Some(Ok(MqttIncomingMessage::ExternRelayPowerStateChanged(
+                payload == b"ON",
+            )))
+        } else {
+            None
+        }
+    }
+
+    fn set_power(&self, power_state: IsPowerOn) -> Result<Option<RelayCommand>> {
+        Ok(Some(RelayCommand {
+            topic: self.command_topic.clone(),
+            payload: String::from(if power_state { "ON" } else { "OFF" }),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::power_relay::tests::{assert_commands, assert_parses};
+
+    #[test]
+    fn commands_and_messages() {
+        let relay = TasmotaRelay::new("machine", None);
+
+        assert_eq!(relay.topics(), ["tele/machine/LWT", "stat/machine/POWER"]);
+
+        assert_commands(
+            &relay,
+            &[
+                (true, "cmnd/machine/POWER", "ON"),
+                (false, "cmnd/machine/POWER", "OFF"),
+            ],
+        );
+
+        assert_parses(
+            &relay,
+            &[
+                (
+                    "tele/machine/LWT",
+                    b"Online",
+                    Some(MqttIncomingMessage::ExternRelayAvailabilityChanged(true)),
+                ),
+                (
+                    "tele/machine/LWT",
+                    b"Offline",
+                    Some(MqttIncomingMessage::ExternRelayAvailabilityChanged(false)),
+                ),
+                (
+                    "stat/machine/POWER",
+                    b"ON",
+                    Some(MqttIncomingMessage::ExternRelayPowerStateChanged(true)),
+                ),
+                (
+                    "stat/machine/POWER",
+                    b"OFF",
+                    Some(MqttIncomingMessage::ExternRelayPowerStateChanged(false)),
+                ),
+                ("stat/machine/POWER1", b"ON", None),
+                ("stat/other/POWER", b"ON", None),
+            ],
+        );
+    }
+
+    #[test]
+    fn numbered_relays() {
+        let relay = TasmotaRelay::new("machine", Some(2));
+
+        assert_eq!(relay.topics(), ["tele/machine/LWT", "stat/machine/POWER2"]);
+
+        assert_commands(&relay, &[(true, "cmnd/machine/POWER2", "ON")]);
+
+        assert_parses(
+            &relay,
+            &[
+                (
+                    "stat/machine/POWER2",
+                    b"ON",
+                    Some(MqttIncomingMessage::ExternRelayPowerStateChanged(true)),
+                ),
+                ("stat/machine/POWER", b"ON", None),
+            ],
+        );
+    }
+}
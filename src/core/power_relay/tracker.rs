@@ -0,0 +1,242 @@
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use log::{error, warn};
+use serde::Serialize;
+use tokio::{
+    select,
+    sync::broadcast::{error::RecvError, Sender},
+    task::{self, JoinHandle},
+    time::{self, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::core::{
+    config::RelayCommandConfig,
+    mqtt::{MqttIncomingMessage, MqttOutgoingMessage},
+    state::{Event, IsPowerOn},
+    util,
+};
+
+// Sent as Event::RelayCommandFailed when the relay didn't confirm a command after every retry.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RelayCommandFailure {
+    pub timestamp: i64,
+    pub power_state: IsPowerOn,
+    pub attempts: u32,
+}
+
+struct PendingCommand {
+    power_state: IsPowerOn,
+    attempts: u32,
+    deadline: Instant,
+}
+
+// Waits for the relay to confirm each ExternRelayPowerStateSetCmd with ExternRelayPowerStateChanged,
+// resending the command when it doesn't. The wait doubles with each retry.
+pub struct RelayCommandTracker {
+    config: RelayCommandConfig,
+    event_tx: Sender<Event>,
+    cancel_token: CancellationToken,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl RelayCommandTracker {
+    pub fn new(config: &RelayCommandConfig, event_tx: Sender<Event>) -> Self {
+        RelayCommandTracker {
+            config: config.clone(),
+            event_tx,
+            cancel_token: CancellationToken::new(),
+            handle: None,
+        }
+    }
+
+    pub fn start(&mut self) {
+        let tx = self.event_tx.clone();
+        let mut rx = self.event_tx.subscribe();
+        let cancel_token = self.cancel_token.clone();
+
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let retries = self.config.retries;
+
+        self.handle = Some(task::spawn(async move {
+            let mut pending: Option<PendingCommand> = None;
+
+            loop {
+                let deadline = pending
+                    .as_ref()
+                    .map(|command| command.deadline)
+                    .unwrap_or_else(Instant::now);
+
+                select! {
+                    _ = time::sleep_until(deadline), if pending.is_some() => {
+                        let Some(command) = pending.take() else { continue };
+
+                        let event = if command.attempts > retries {
+                            error!(
+                                "The relay didn't switch {} after {} attempts",
+                                if command.power_state { "on" } else { "off" },
+                                command.attempts
+                            );
+
+                            Event::RelayCommandFailed(RelayCommandFailure {
+                                timestamp: util::get_unix_timestamp(SystemTime::now()).unwrap_or_default(),
+                                power_state: command.power_state,
+                                attempts: command.attempts,
+                            })
+                        } else {
+                            warn!(
+                                "The relay didn't confirm the command, retrying (attempt {})",
+                                command.attempts + 1
+                            );
+
+                            pending = Some(PendingCommand {
+                                power_state: command.power_state,
+                                attempts: command.attempts + 1,
+                                deadline: Instant::now() + timeout * 2u32.pow(command.attempts),
+                            });
+
+                            Event::OutgoingMqttMessage(MqttOutgoingMessage::ExternRelayPowerStateSetCmd(
+                                command.power_state,
+                            ))
+                        };
+
+                        if let Err(err) = tx.send(event) {
+                            error!("Failed to send event: {}", err);
+                        }
+                    }
+                    event = rx.recv() => match event {
+                        // Retries are sent on the bus too, so a command for the pending state isn't a new one.
+                        Ok(Event::OutgoingMqttMessage(MqttOutgoingMessage::ExternRelayPowerStateSetCmd(power_state)))
+                            if pending.as_ref().map(|command| command.power_state) != Some(power_state) =>
+                        {
+                            pending = Some(PendingCommand {
+                                power_state,
+                                attempts: 1,
+                                deadline: Instant::now() + timeout,
+                            });
+                        }
+                        Ok(Event::IncomingMqttMessage(MqttIncomingMessage::ExternRelayPowerStateChanged(power_state), _))
+                            if pending.as_ref().map(|command| command.power_state) == Some(power_state) =>
+                        {
+                            pending = None;
+                        }
+                        // The state goes idle when the relay is unavailable, there's nothing left to reconcile.
+                        Ok(Event::IncomingMqttMessage(MqttIncomingMessage::ExternRelayAvailabilityChanged(false), _)) => {
+                            pending = None;
+                        }
+                        Err(RecvError::Closed) => break,
+                        _ => {}
+                    },
+                    _ = cancel_token.cancelled() => {
+                        break;
+                    }
+                }
+            }
+        }));
+    }
+
+    pub async fn stop(&mut self) -> Result<()> {
+        self.cancel_token.cancel();
+
+        if let Some(handle) = self.handle.take() {
+            handle.await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast::{self, Receiver};
+
+    use super::*;
+
+    fn start_tracker() -> (RelayCommandTracker, Sender<Event>, Receiver<Event>) {
+        let (tx, rx) = broadcast::channel(100);
+        let config = RelayCommandConfig {
+            timeout_ms: 1_000,
+            retries: 3,
+        };
+
+        let mut tracker = RelayCommandTracker::new(&config, tx.clone());
+        tracker.start();
+
+        (tracker, tx, rx)
+    }
+
+    fn command(power_state: IsPowerOn) -> Event {
+        Event::OutgoingMqttMessage(MqttOutgoingMessage::ExternRelayPowerStateSetCmd(power_state))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_with_backoff_then_fails() {
+        let (mut tracker, tx, mut rx) = start_tracker();
+        let start = Instant::now();
+
+        tx.send(command(true)).unwrap();
+        assert!(matches!(rx.recv().await.unwrap(), Event::OutgoingMqttMessage(_)));
+
+        // The wait doubles after each retry: 1s, then 2s, then 4s, then 8s before giving up.
+        for retry_at in [1, 3, 7] {
+            let event = rx.recv().await.unwrap();
+
+            assert!(matches!(
+                event,
+                Event::OutgoingMqttMessage(MqttOutgoingMessage::ExternRelayPowerStateSetCmd(true))
+            ));
+            assert_eq!(start.elapsed(), Duration::from_secs(retry_at));
+        }
+
+        let Event::RelayCommandFailed(failure) = rx.recv().await.unwrap() else {
+            panic!("Expected the command to fail");
+        };
+
+        assert_eq!(start.elapsed(), Duration::from_secs(15));
+        assert!(failure.power_state);
+        assert_eq!(failure.attempts, 4);
+
+        // Nothing else is sent once it's failed.
+        assert!(time::timeout(Duration::from_secs(60), rx.recv()).await.is_err());
+
+        tracker.stop().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn confirmed_commands_are_not_retried() {
+        let (mut tracker, tx, mut rx) = start_tracker();
+
+        tx.send(command(true)).unwrap();
+        time::sleep(Duration::from_millis(500)).await;
+
+        // A confirmation of the other state doesn't count.
+        tx.send(Event::IncomingMqttMessage(
+            MqttIncomingMessage::ExternRelayPowerStateChanged(false),
+            None,
+        ))
+        .unwrap();
+
+        time::sleep(Duration::from_millis(1_000)).await;
+
+        tx.send(Event::IncomingMqttMessage(
+            MqttIncomingMessage::ExternRelayPowerStateChanged(true),
+            None,
+        ))
+        .unwrap();
+
+        // The original command and the one retry before it was confirmed, then nothing.
+        let mut commands = 0;
+
+        while let Ok(event) = time::timeout(Duration::from_secs(60), rx.recv()).await {
+            if matches!(event.unwrap(), Event::OutgoingMqttMessage(_)) {
+                commands += 1;
+            }
+        }
+
+        assert_eq!(commands, 2);
+
+        tracker.stop().await.unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::controller::{ControlMethod, ControllerParameters};
+
+use super::{
+    brew_curve::BrewCurve,
+    config::{TargetBounds, TargetTemperatureConfig},
+};
+
+// A named set of settings for a coffee, e.g. a light roast brewed hotter than a dark one.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub id: i64,
+    #[serde(flatten)]
+    pub settings: ProfileSettings,
+}
+
+// The payload of gesha/profile/create, the ID is assigned by the DB.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileSettings {
+    pub name: String,
+    pub brew_target_temp: f32,
+    pub steam_target_temp: f32,
+    pub control_method: ControlMethod,
+    #[serde(default)]
+    pub controller_parameters: ControllerParameters,
+    // Whether the machine is only ready once the grouphead is preheated, rather than when the boiler is at temperature.
+    pub require_preheat: bool,
+    pub target_shot_time_s: u64,
+    pub target_yield_g: f32,
+    // Replaces the brew target while a shot is being pulled, None holds the brew target.
+    #[serde(default)]
+    pub brew_curve: Option<BrewCurve>,
+}
+
+impl ProfileSettings {
+    // Profiles are checked against the target bounds when they're saved and again when they're activated,
+    // since the bounds can be changed in the config in between.
+    pub fn validate(&self, bounds: &TargetTemperatureConfig) -> Result<()> {
+        if self.name.trim().is_empty() {
+            return Err(anyhow!("The profile needs a name"));
+        }
+
+        validate_target("brew", self.brew_target_temp, &bounds.brew)?;
+        validate_target("steam", self.steam_target_temp, &bounds.steam)?;
+
+        if let Some(brew_curve) = &self.brew_curve {
+            brew_curve.validate(&bounds.brew)?;
+        }
+
+        if self.target_shot_time_s == 0 {
+            return Err(anyhow!("The target shot time must be greater than 0"));
+        }
+
+        if self.target_yield_g.is_nan() || self.target_yield_g <= 0.0 {
+            return Err(anyhow!("The target yield must be greater than 0"));
+        }
+
+        Ok(())
+    }
+}
+
+fn validate_target(mode: &str, temperature: f32, bounds: &TargetBounds) -> Result<()> {
+    if bounds.contains(temperature) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "The {mode} target {temperature}°C must be between {}°C and {}°C",
+            bounds.min,
+            bounds.max
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        db::{ConfigItem, Db, DB_KEY_ACTIVE_PROFILE},
+        mqtt::{MqttIncomingMessage, MqttOutgoingMessage},
+        state::{Event, State},
+        transition::tests::{db_path, state, state_at},
+    };
+
+    fn settings(name: &str) -> ProfileSettings {
+        ProfileSettings {
+            name: name.to_string(),
+            brew_target_temp: 93.0,
+            steam_target_temp: 135.0,
+            control_method: ControlMethod::PID,
+            controller_parameters: ControllerParameters::default(),
+            require_preheat: false,
+            target_shot_time_s: 30,
+            target_yield_g: 36.0,
+            brew_curve: None,
+        }
+    }
+
+    async fn command(state: &mut State, message: MqttIncomingMessage) -> Result<Vec<Event>> {
+        state.handle_event(&Event::IncomingMqttMessage(message, None)).await
+    }
+
+    #[test]
+    fn validation() {
+        let bounds = TargetTemperatureConfig::default();
+
+        type Change = fn(&mut ProfileSettings);
+
+        let table: [(&str, Change, Option<&str>); 8] = [
+            ("valid", |_| {}, None),
+            ("no name", |settings| settings.name = String::from("  "), Some("needs a name")),
+            ("brew too hot", |settings| settings.brew_target_temp = 110.0, Some("brew target")),
+            ("brew NaN", |settings| settings.brew_target_temp = f32::NAN, Some("brew target")),
+            ("steam too cold", |settings| settings.steam_target_temp = 100.0, Some("steam target")),
+            ("no shot time", |settings| settings.target_shot_time_s = 0, Some("target shot time")),
+            ("no yield", |settings| settings.target_yield_g = 0.0, Some("target yield")),
+            ("NaN yield", |settings| settings.target_yield_g = f32::NAN, Some("target yield")),
+        ];
+
+        for (case, change, error) in table {
+            let mut profile = settings("Light roast");
+            change(&mut profile);
+
+            match (profile.validate(&bounds), error) {
+                (Ok(()), None) => {}
+                (Err(err), Some(error)) => assert!(err.to_string().contains(error), "{case}: {err}"),
+                (result, _) => panic!("{case}: {result:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn profiles_are_created_updated_and_deleted() {
+        let (mut state, _rx) = state("profile-crud").await;
+
+        let events = command(&mut state, MqttIncomingMessage::ProfileCreate(settings("Light roast")))
+            .await
+            .unwrap();
+
+        assert!(events.iter().any(|event| matches!(
+            event,
+            Event::OutgoingMqttMessage(MqttOutgoingMessage::ProfilesUpdate(profiles)) if profiles.len() == 1
+        )));
+
+        // Invalid profiles aren't written.
+        let mut invalid = settings("Too hot");
+        invalid.brew_target_temp = 120.0;
+
+        assert!(command(&mut state, MqttIncomingMessage::ProfileCreate(invalid)).await.is_err());
+        assert_eq!(state.profiles.len(), 1);
+
+        let mut profile = state.profiles[0].clone();
+        profile.settings.name = String::from("Medium roast");
+
+        command(&mut state, MqttIncomingMessage::ProfileUpdate(profile.clone()))
+            .await
+            .unwrap();
+
+        assert_eq!(state.profiles[0].settings.name, "Medium roast");
+
+        command(&mut state, MqttIncomingMessage::ProfileDelete(profile.id))
+            .await
+            .unwrap();
+
+        assert!(state.profiles.is_empty());
+
+        state.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn activating_a_profile_applies_and_persists_it() {
+        let db_path = db_path("profile-activation");
+        let _ = std::fs::remove_file(&db_path);
+        let (mut state, _rx) = state_at(&db_path).await;
+
+        command(&mut state, MqttIncomingMessage::ProfileCreate(settings("Light roast")))
+            .await
+            .unwrap();
+
+        let id = state.profiles[0].id;
+
+        assert!(command(&mut state, MqttIncomingMessage::ProfileActivate(id + 1)).await.is_err());
+        assert!(state.active_profile.is_none());
+
+        let events = command(&mut state, MqttIncomingMessage::ProfileActivate(id))
+            .await
+            .unwrap();
+
+        assert_eq!(state.target_temperature, 93.0);
+        assert_eq!(state.target_temperature_steam, 135.0);
+        assert_eq!(state.control_method, ControlMethod::PID);
+        assert!(events.iter().any(|event| matches!(event, Event::ProfileActivated(profile) if profile.id == id)));
+
+        state.stop().await.unwrap();
+
+        // The profile is still active after a restart.
+        let (mut state, _rx) = state_at(&db_path).await;
+
+        assert_eq!(state.active_profile.as_ref().map(|profile| profile.id), Some(id));
+        assert_eq!(state.target_temperature, 93.0);
+
+        // Deleting the active profile keeps its settings.
+        let events = command(&mut state, MqttIncomingMessage::ProfileDelete(id))
+            .await
+            .unwrap();
+
+        assert!(state.active_profile.is_none());
+        assert_eq!(state.target_temperature, 93.0);
+        assert!(events.iter().any(|event| matches!(
+            event,
+            Event::OutgoingMqttMessage(MqttOutgoingMessage::ActiveProfileUpdate(None))
+        )));
+
+        state.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_corrupt_active_profile_is_ignored() {
+        let db_path = db_path("profile-corrupt");
+        let _ = std::fs::remove_file(&db_path);
+
+        Db::new(db_path.to_str().unwrap())
+            .await
+            .unwrap()
+            .write_config(&ConfigItem {
+                key: DB_KEY_ACTIVE_PROFILE.to_string(),
+                value: String::from("not a profile"),
+            })
+            .await
+            .unwrap();
+
+        let (mut state, _rx) = state_at(&db_path).await;
+
+        assert!(state.active_profile.is_none());
+
+        state.stop().await.unwrap();
+    }
+}
@@ -0,0 +1,532 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, TimeZone};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::broadcast::{error::RecvError, Sender},
+    task::{self, JoinHandle},
+    time,
+};
+use tokio_util::sync::CancellationToken;
+
+use super::{
+    state::{Event, Mode},
+    transition::TransitionTrigger,
+};
+
+// The schedules are checked at least this often, so that changes to the system clock
+// (e.g. NTP syncing after boot) are picked up.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+// A schedule that was due longer ago than this is skipped, e.g. when the clock jumps forward,
+// rather than turning the machine on at an unexpected time.
+const MAX_LATENESS_MINUTES: i64 = 5;
+
+// Long enough to find the next 29th of February.
+const MAX_LOOKAHEAD_DAYS: usize = 366 * 4 + 1;
+
+const WEEKDAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+const MONTH_NAMES: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Schedule {
+    pub id: i64,
+    pub name: String,
+    // A five field cron expression in local time, e.g. "45 6 * * mon-fri" for 06:45 on weekdays.
+    pub cron: String,
+    pub mode: Mode,
+}
+
+// The payload of gesha/schedule/create, the ID is assigned by the DB.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NewSchedule {
+    pub name: String,
+    pub cron: String,
+    pub mode: Mode,
+}
+
+// minute hour day-of-month month day-of-week, each field is a bit set of the values it matches.
+// Fields can be *, a value, a range (1-5), a step (*/15, 0-30/10) or a comma separated list of them.
+// Months and days of the week can be given by name, and Sunday is either 0 or 7.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CronExpression {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    // Like cron, a day matches either field if both are restricted.
+    days_of_month_restricted: bool,
+    days_of_week_restricted: bool,
+}
+
+impl FromStr for CronExpression {
+    type Err = anyhow::Error;
+
+    fn from_str(expression: &str) -> Result<Self> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+
+        let [minutes, hours, days_of_month, months, days_of_week] = fields[..] else {
+            return Err(anyhow!(
+                "Expected 5 fields in the cron expression \"{expression}\", got {}",
+                fields.len()
+            ));
+        };
+
+        let mut days_of_week_set = parse_field(days_of_week, 0, 7, &WEEKDAY_NAMES)?;
+
+        if days_of_week_set & (1 << 7) != 0 {
+            days_of_week_set |= 1;
+        }
+
+        Ok(CronExpression {
+            minutes: parse_field(minutes, 0, 59, &[])?,
+            hours: parse_field(hours, 0, 23, &[])?,
+            days_of_month: parse_field(days_of_month, 1, 31, &[])?,
+            months: parse_field(months, 1, 12, &MONTH_NAMES)?,
+            days_of_week: days_of_week_set,
+            days_of_month_restricted: !days_of_month.starts_with('*'),
+            days_of_week_restricted: !days_of_week.starts_with('*'),
+        })
+    }
+}
+
+impl CronExpression {
+    // The first time the expression matches after `after`, in the same time zone.
+    // Times skipped when the clocks go forward run when the clocks have gone forward,
+    // and times repeated when the clocks go back only run the first time.
+    pub fn next_due<Tz: TimeZone>(&self, after: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let timezone = after.timezone();
+        let mut date = after.date_naive();
+
+        for _ in 0..MAX_LOOKAHEAD_DAYS {
+            if self.matches_date(&date) {
+                // A skipped time can be moved after a later time on the same day, so the earliest is taken.
+                let due = (0..24)
+                    .filter(|hour| contains(self.hours, *hour))
+                    .flat_map(|hour| {
+                        (0..60)
+                            .filter(|minute| contains(self.minutes, *minute))
+                            .filter_map(move |minute| date.and_hms_opt(hour, minute, 0))
+                    })
+                    .filter_map(|local| resolve_local(&timezone, &local))
+                    .filter(|time| time > after)
+                    .min();
+
+                if due.is_some() {
+                    return due;
+                }
+            }
+
+            date = date.succ_opt()?;
+        }
+
+        None
+    }
+
+    fn matches_date(&self, date: &NaiveDate) -> bool {
+        let day_of_month = contains(self.days_of_month, date.day());
+        let day_of_week = contains(self.days_of_week, date.weekday().num_days_from_sunday());
+
+        let day = if self.days_of_month_restricted && self.days_of_week_restricted {
+            day_of_month || day_of_week
+        } else {
+            day_of_month && day_of_week
+        };
+
+        day && contains(self.months, date.month())
+    }
+}
+
+// The schedules that were due after `from` and at or before `to`,
+// the scheduler's previous and current check of the clock.
+pub fn due_schedules<'a, Tz: TimeZone>(
+    schedules: &'a [(Schedule, CronExpression)],
+    from: &DateTime<Tz>,
+    to: &DateTime<Tz>,
+) -> Vec<&'a Schedule> {
+    let earliest = to.clone() - chrono::Duration::minutes(MAX_LATENESS_MINUTES);
+
+    schedules
+        .iter()
+        .filter(|(_, expression)| {
+            expression
+                .next_due(from)
+                .is_some_and(|due| due <= *to && due >= earliest)
+        })
+        .map(|(schedule, _)| schedule)
+        .collect()
+}
+
+fn resolve_local<Tz: TimeZone>(timezone: &Tz, local: &NaiveDateTime) -> Option<DateTime<Tz>> {
+    timezone.from_local_datetime(local).earliest().or_else(|| {
+        timezone
+            .from_local_datetime(&(*local + chrono::Duration::hours(1)))
+            .earliest()
+    })
+}
+
+fn contains(set: u64, value: u32) -> bool {
+    set & (1 << value) != 0
+}
+
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64> {
+    let mut set = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>()?),
+            None => (part, 1),
+        };
+
+        if step == 0 {
+            return Err(anyhow!("The step in \"{part}\" must be greater than 0"));
+        }
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (
+                parse_value(start, min, names)?,
+                parse_value(end, min, names)?,
+            )
+        } else {
+            let value = parse_value(range, min, names)?;
+
+            // "5/15" is every 15 from 5.
+            if step > 1 {
+                (value, max)
+            } else {
+                (value, value)
+            }
+        };
+
+        if start < min || end > max || start > end {
+            return Err(anyhow!(
+                "\"{part}\" is out of range, expected values from {min} to {max}"
+            ));
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+
+    Ok(set)
+}
+
+fn parse_value(value: &str, min: u32, names: &[&str]) -> Result<u32> {
+    let lowercase = value.to_lowercase();
+
+    match names.iter().position(|name| *name == lowercase) {
+        Some(index) => Ok(min + index as u32),
+        None => value
+            .parse::<u32>()
+            .map_err(|_| anyhow!("\"{value}\" is not a valid cron value")),
+    }
+}
+
+fn parse_schedules(schedules: Vec<Schedule>) -> Vec<(Schedule, CronExpression)> {
+    schedules
+        .into_iter()
+        .filter_map(|schedule| match CronExpression::from_str(&schedule.cron) {
+            Ok(expression) => Some((schedule, expression)),
+            Err(err) => {
+                error!("Ignoring schedule {}: {}", schedule.id, err);
+                None
+            }
+        })
+        .collect()
+}
+
+// Where the scheduler gets the local time from, usually Local::now.
+pub type Clock = Arc<dyn Fn() -> DateTime<Local> + Send + Sync>;
+
+// Requests a mode transition whenever a schedule is due, in local time.
+pub struct Scheduler {
+    schedules: Vec<Schedule>,
+    event_tx: Sender<Event>,
+    clock: Clock,
+    cancel_token: CancellationToken,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Scheduler {
+    pub fn new(schedules: Vec<Schedule>, event_tx: Sender<Event>, clock: Clock) -> Self {
+        Scheduler {
+            schedules,
+            event_tx,
+            clock,
+            cancel_token: CancellationToken::new(),
+            handle: None,
+        }
+    }
+
+    pub fn start(&mut self) {
+        let tx = self.event_tx.clone();
+        let mut rx = self.event_tx.subscribe();
+        let cancel_token = self.cancel_token.clone();
+        let mut schedules = parse_schedules(self.schedules.clone());
+        let clock = self.clock.clone();
+
+        self.handle = Some(task::spawn(async move {
+            let mut last_checked = clock();
+
+            loop {
+                let now = clock();
+
+                for schedule in due_schedules(&schedules, &last_checked, &now) {
+                    info!("Schedule \"{}\" is due, requesting {:?}", schedule.name, schedule.mode);
+
+                    if let Err(err) = tx.send(Event::ModeTransitionRequest {
+                        mode: schedule.mode.clone(),
+                        trigger: TransitionTrigger::Schedule,
+                    }) {
+                        error!("Failed to send event: {}", err);
+                    }
+                }
+
+                let sleep = schedules
+                    .iter()
+                    .filter_map(|(_, expression)| expression.next_due(&now))
+                    .min()
+                    .and_then(|due| (due - now).to_std().ok())
+                    .map_or(MAX_SLEEP, |sleep| sleep.min(MAX_SLEEP));
+
+                last_checked = now;
+
+                let deadline = time::Instant::now() + sleep;
+
+                loop {
+                    select! {
+                        _ = time::sleep_until(deadline) => break,
+                        event = rx.recv() => match event {
+                            Ok(Event::SchedulesChanged(new_schedules)) => {
+                                schedules = parse_schedules(new_schedules);
+                                break;
+                            }
+                            Err(RecvError::Closed) => return,
+                            _ => {}
+                        },
+                        _ = cancel_token.cancelled() => return,
+                    }
+                }
+            }
+        }));
+    }
+
+    pub async fn stop(&mut self) -> Result<()> {
+        self.cancel_token.cancel();
+
+        if let Some(handle) = self.handle.take() {
+            handle.await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::FixedOffset;
+    use chrono_tz::Europe::London;
+    use tokio::sync::broadcast;
+
+    use super::*;
+
+    fn cron(expression: &str) -> CronExpression {
+        CronExpression::from_str(expression).unwrap()
+    }
+
+    fn utc_plus_one(day: u32, hour: u32, minute: u32) -> DateTime<FixedOffset> {
+        FixedOffset::east_opt(3600)
+            .unwrap()
+            .with_ymd_and_hms(2026, 10, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_fields() {
+        let expression = cron("*/15 0-6/2 1,15 jan,JUL sun");
+
+        assert_eq!(expression.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(expression.hours, 1 | 1 << 2 | 1 << 4 | 1 << 6);
+        assert_eq!(expression.days_of_month, 1 << 1 | 1 << 15);
+        assert_eq!(expression.months, 1 << 1 | 1 << 7);
+        assert_eq!(expression.days_of_week, 1);
+        assert!(expression.days_of_month_restricted);
+        assert!(expression.days_of_week_restricted);
+
+        let expression = cron("5/20 6 * * mon-fri");
+
+        assert_eq!(expression.minutes, 1 << 5 | 1 << 25 | 1 << 45);
+        assert_eq!(expression.days_of_week, 0b111110);
+        assert!(!expression.days_of_month_restricted);
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for expression in [
+            "0 7 * *",
+            "0 7 * * * *",
+            "60 7 * * *",
+            "0 24 * * *",
+            "0 7 0 * *",
+            "0 7 * 13 *",
+            "0 7 * * 8",
+            "*/0 7 * * *",
+            "30-10 7 * * *",
+            "0 7 * foo *",
+            "0 7 * * someday",
+        ] {
+            assert!(CronExpression::from_str(expression).is_err(), "{expression}");
+        }
+    }
+
+    #[test]
+    fn next_due_on_weekdays() {
+        let expression = cron("45 6 * * mon-fri");
+
+        // Friday morning, after it was due.
+        assert_eq!(expression.next_due(&utc_plus_one(16, 7, 0)), Some(utc_plus_one(19, 6, 45)));
+        // Monday morning, before it's due.
+        assert_eq!(expression.next_due(&utc_plus_one(19, 6, 0)), Some(utc_plus_one(19, 6, 45)));
+        // Exactly when it's due, so the next is tomorrow.
+        assert_eq!(expression.next_due(&utc_plus_one(19, 6, 45)), Some(utc_plus_one(20, 6, 45)));
+    }
+
+    #[test]
+    fn sunday_is_zero_or_seven() {
+        let after = utc_plus_one(19, 0, 0);
+
+        assert_eq!(cron("0 7 * * 0").next_due(&after), Some(utc_plus_one(25, 7, 0)));
+        assert_eq!(cron("0 7 * * 7").next_due(&after), Some(utc_plus_one(25, 7, 0)));
+        assert_eq!(cron("0 7 * * 5-7").next_due(&after), Some(utc_plus_one(23, 7, 0)));
+    }
+
+    #[test]
+    fn day_of_month_or_day_of_week() {
+        // Either the 13th or a Friday when both are restricted.
+        let expression = cron("0 7 13 * fri");
+
+        assert_eq!(expression.next_due(&utc_plus_one(19, 0, 0)), Some(utc_plus_one(23, 7, 0)));
+        assert_eq!(expression.next_due(&utc_plus_one(24, 0, 0)), Some(utc_plus_one(30, 7, 0)));
+
+        // Only the 13th when the day of the week isn't restricted.
+        let thirteenth = FixedOffset::east_opt(3600)
+            .unwrap()
+            .with_ymd_and_hms(2026, 11, 13, 7, 0, 0)
+            .unwrap();
+
+        assert_eq!(cron("0 7 13 * *").next_due(&utc_plus_one(19, 0, 0)), Some(thirteenth));
+    }
+
+    #[test]
+    fn times_skipped_by_the_clocks_going_forward_run_after() {
+        // The clocks go from 01:00 to 02:00, so 01:30 doesn't exist.
+        let after = London.with_ymd_and_hms(2026, 3, 29, 0, 0, 0).unwrap();
+        let due = cron("30 1 * * *").next_due(&after).unwrap();
+
+        assert_eq!(due, London.with_ymd_and_hms(2026, 3, 29, 2, 30, 0).unwrap());
+        assert_eq!(due.naive_utc().to_string(), "2026-03-29 01:30:00");
+    }
+
+    #[test]
+    fn times_repeated_by_the_clocks_going_back_run_once() {
+        // The clocks go from 02:00 back to 01:00, so 01:30 happens twice.
+        let expression = cron("30 1 * * *");
+        let after = London.with_ymd_and_hms(2026, 10, 25, 0, 0, 0).unwrap();
+        let due = expression.next_due(&after).unwrap();
+
+        assert_eq!(due.naive_utc().to_string(), "2026-10-25 00:30:00");
+
+        let next = expression.next_due(&due).unwrap();
+
+        assert_eq!(next.naive_utc().to_string(), "2026-10-26 01:30:00");
+    }
+
+    #[test]
+    fn due_schedules_between_checks() {
+        let schedule = Schedule {
+            id: 1,
+            name: String::from("Weekday mornings"),
+            cron: String::from("45 6 * * mon-fri"),
+            mode: Mode::Active,
+        };
+
+        let schedules = parse_schedules(vec![schedule]);
+        let due = |from, to| due_schedules(&schedules, &utc_plus_one(19, 6, from), &utc_plus_one(19, 6, to)).len();
+
+        assert_eq!(due(44, 45), 1);
+        assert_eq!(due(45, 46), 0);
+        assert_eq!(due(30, 44), 0);
+        // The scheduler slept through it, but not for too long.
+        assert_eq!(due(30, 45 + MAX_LATENESS_MINUTES as u32), 1);
+        // The clock jumped forward too far, so it's skipped.
+        assert_eq!(due(30, 46 + MAX_LATENESS_MINUTES as u32), 0);
+    }
+
+    #[test]
+    fn invalid_schedules_are_ignored() {
+        let schedules = parse_schedules(vec![
+            Schedule {
+                id: 1,
+                name: String::from("Invalid"),
+                cron: String::from("every morning"),
+                mode: Mode::Active,
+            },
+            Schedule {
+                id: 2,
+                name: String::from("Valid"),
+                cron: String::from("0 7 * * *"),
+                mode: Mode::Active,
+            },
+        ]);
+
+        assert_eq!(schedules.len(), 1);
+        assert_eq!(schedules[0].0.id, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scheduler_requests_the_mode_when_due() {
+        let (tx, mut rx) = broadcast::channel(10);
+
+        // The clock follows tokio's paused time, which jumps ahead whenever the scheduler sleeps.
+        let start = Local.with_ymd_and_hms(2026, 1, 5, 6, 44, 30).unwrap();
+        let started_at = time::Instant::now();
+        let clock: Clock = Arc::new(move || start + (time::Instant::now() - started_at));
+
+        let mut scheduler = Scheduler::new(
+            vec![Schedule {
+                id: 1,
+                name: String::from("Weekday mornings"),
+                cron: String::from("45 6 * * mon-fri"),
+                mode: Mode::Active,
+            }],
+            tx,
+            clock.clone(),
+        );
+
+        scheduler.start();
+
+        let event = rx.recv().await.unwrap();
+
+        assert!(matches!(
+            event,
+            Event::ModeTransitionRequest {
+                mode: Mode::Active,
+                trigger: TransitionTrigger::Schedule,
+            }
+        ));
+        assert_eq!(clock(), Local.with_ymd_and_hms(2026, 1, 5, 6, 45, 0).unwrap());
+
+        scheduler.stop().await.unwrap();
+    }
+}
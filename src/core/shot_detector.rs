@@ -0,0 +1,591 @@
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use super::{
+    config::ShotDetectionConfig,
+    db::{Db, Shot},
+    mqtt::Range,
+    thermal_model::ThermalModel,
+};
+
+// When a shot is pulled the boiler is refilled with cold water from the reservoir,
+// so the boiler temperature drops much faster than it does when the heater is simply off.
+// The slope of the boiler temperature is fitted over this window to smooth out the 0.25 °C resolution of the thermocouples.
+const SLOPE_WINDOW_MS: i64 = 3_000;
+
+// The heater reacts to the drop and can hide it well before the shot ends.
+// When the thermal model is known, the slope it expects from the heat applied is subtracted,
+// which needs the heat levels going back as far as the model's lag.
+const HEAT_LEVEL_HISTORY_MS: i64 = 90_000;
+
+// A drop has to last this long and lose this much heat before it's considered a shot,
+// which filters out sensor noise and the thermal model being slightly off.
+const MIN_SHOT_DURATION_MS: i64 = 5_000;
+const MIN_SHOT_DROP_C: f32 = 2.0;
+
+// The drop can briefly level off mid-shot as the heater catches up,
+// so a shot only ends once the drop has stopped for this long.
+const END_DELAY_MS: i64 = 5_000;
+
+// Drops longer than this aren't a shot, e.g. the boiler being refilled after steaming.
+const MAX_SHOT_DURATION_MS: i64 = 90_000;
+
+// Typical shot characteristics, used to score how much a drop looks like a shot.
+const EXPECTED_BOILER_DROP_C: f32 = 5.0;
+const EXPECTED_GROUPHEAD_RISE_C: f32 = 1.0;
+const TYPICAL_SHOT_DURATION_MS: (i64, i64) = (15_000, 45_000);
+
+// The measurements either side of each recorded shot that are replayed when evaluating the detector.
+const EVALUATION_MARGIN_MS: i64 = 15 * 60 * 1_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShotDetectorEvent {
+    // The drop has lasted long enough to be a shot.
+    Started { start_time: i64 },
+    Ended {
+        start_time: i64,
+        end_time: i64,
+        // 0.0 - 1.0, how closely the drop resembles a shot.
+        confidence: f32,
+    },
+}
+
+struct Candidate {
+    start_time: i64,
+    last_time: i64,
+    // The temperature lost to the drop, relative to what the thermal model expected if it's known.
+    drop_c: f32,
+    start_grouphead_temp: f32,
+    max_grouphead_temp: f32,
+    // When the drop stopped, if it hasn't resumed since.
+    stopped_at: Option<i64>,
+    started: bool,
+}
+
+pub struct ShotDetector {
+    // The rate of boiler temperature drop in °C/s that starts a shot, lower values are more sensitive.
+    sensitivity: f32,
+    samples: VecDeque<(i64, f32)>,
+    heat_levels: VecDeque<(i64, f32)>,
+    thermal_model: Option<ThermalModel>,
+    candidate: Option<Candidate>,
+}
+
+impl ShotDetector {
+    pub fn new(config: &ShotDetectionConfig, thermal_model: Option<ThermalModel>) -> Self {
+        ShotDetector {
+            sensitivity: config.sensitivity,
+            samples: VecDeque::new(),
+            heat_levels: VecDeque::new(),
+            thermal_model,
+            candidate: None,
+        }
+    }
+
+    pub fn set_thermal_model(&mut self, thermal_model: ThermalModel) {
+        self.thermal_model = Some(thermal_model);
+    }
+
+    // Forgets the current drop, e.g. when a shot has been started or ended manually.
+    pub fn reset(&mut self) {
+        self.candidate = None;
+    }
+
+    // Ends the current shot early, e.g. when it's ended manually, and returns how confident the detector was in it.
+    pub fn end(&mut self, time: i64) -> Option<f32> {
+        let candidate = self.candidate.take()?;
+
+        if !candidate.started {
+            return None;
+        }
+
+        let end_time = candidate.stopped_at.unwrap_or(time);
+
+        Some(candidate.confidence(end_time - candidate.start_time))
+    }
+
+    pub fn push(
+        &mut self,
+        time: i64,
+        boiler_temp: f32,
+        grouphead_temp: f32,
+        heat_level: f32,
+    ) -> Option<ShotDetectorEvent> {
+        self.samples.push_back((time, boiler_temp));
+        self.heat_levels.push_back((time, heat_level));
+
+        while let Some((sample_time, _)) = self.samples.front() {
+            if time - sample_time > SLOPE_WINDOW_MS {
+                self.samples.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        while let Some((sample_time, _)) = self.heat_levels.front() {
+            if time - sample_time > HEAT_LEVEL_HISTORY_MS {
+                self.heat_levels.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        let slope = self.slope()? - self.expected_slope(time, boiler_temp).unwrap_or(0.0);
+
+        match self.candidate.as_mut() {
+            None => {
+                if slope <= -self.sensitivity {
+                    // The drop began at the start of the slope window.
+                    let (start_time, start_boiler_temp) = *self.samples.front()?;
+
+                    self.candidate = Some(Candidate {
+                        start_time,
+                        last_time: time,
+                        drop_c: (start_boiler_temp - boiler_temp).max(0.0),
+                        start_grouphead_temp: grouphead_temp,
+                        max_grouphead_temp: grouphead_temp,
+                        stopped_at: None,
+                        started: false,
+                    });
+                }
+
+                None
+            }
+            Some(candidate) => {
+                let dt = (time - candidate.last_time) as f32 / 1000.0;
+
+                candidate.last_time = time;
+                candidate.max_grouphead_temp = candidate.max_grouphead_temp.max(grouphead_temp);
+
+                // Half the sensitivity is used to end the drop, so it doesn't flap around the threshold.
+                if slope > -self.sensitivity / 2.0 {
+                    candidate.stopped_at.get_or_insert(time);
+                } else {
+                    candidate.stopped_at = None;
+                    candidate.drop_c -= slope * dt;
+                }
+
+                let end_time = candidate.stopped_at.unwrap_or(time);
+                let duration = end_time - candidate.start_time;
+
+                if time - end_time >= END_DELAY_MS || duration > MAX_SHOT_DURATION_MS {
+                    let candidate = self.candidate.take()?;
+
+                    if !candidate.started {
+                        return None;
+                    }
+
+                    return Some(ShotDetectorEvent::Ended {
+                        start_time: candidate.start_time,
+                        end_time,
+                        confidence: if duration > MAX_SHOT_DURATION_MS {
+                            0.0
+                        } else {
+                            candidate.confidence(duration)
+                        },
+                    });
+                }
+
+                if !candidate.started
+                    && candidate.stopped_at.is_none()
+                    && duration >= MIN_SHOT_DURATION_MS
+                    && candidate.drop_c >= MIN_SHOT_DROP_C
+                {
+                    candidate.started = true;
+
+                    return Some(ShotDetectorEvent::Started {
+                        start_time: candidate.start_time,
+                    });
+                }
+
+                None
+            }
+        }
+    }
+
+    // The slope the thermal model expects from the heat applied a lag ago, over the same window as the measured slope.
+    fn expected_slope(&self, time: i64, boiler_temp: f32) -> Option<f32> {
+        let thermal_model = self.thermal_model.as_ref()?;
+
+        let lag_ms = (thermal_model.lag_s * 1000.0) as i64;
+        let (from, to) = (time - lag_ms - SLOPE_WINDOW_MS, time - lag_ms);
+
+        let (oldest_time, _) = self.heat_levels.front()?;
+
+        // Not enough history yet, e.g. just after starting.
+        if *oldest_time > from {
+            return None;
+        }
+
+        let (sum, count) = self
+            .heat_levels
+            .iter()
+            .filter(|(sample_time, _)| *sample_time >= from && *sample_time <= to)
+            .fold((0.0, 0), |(sum, count), (_, heat_level)| (sum + heat_level, count + 1));
+
+        if count == 0 {
+            return None;
+        }
+
+        Some(
+            thermal_model.heater_gain * sum / count as f32
+                - thermal_model.loss_coefficient * (boiler_temp - thermal_model.ambient_temp),
+        )
+    }
+
+    // The least squares slope of the boiler temperature over the window, in °C/s.
+    fn slope(&self) -> Option<f32> {
+        let (first_time, _) = *self.samples.front()?;
+        let (last_time, _) = *self.samples.back()?;
+
+        // Wait for the window to fill up before making any decisions.
+        if last_time - first_time < SLOPE_WINDOW_MS / 2 {
+            return None;
+        }
+
+        let n = self.samples.len() as f64;
+        let (sum_t, sum_temp) = self
+            .samples
+            .iter()
+            .fold((0.0, 0.0), |(sum_t, sum_temp), (time, temp)| {
+                (sum_t + (time - first_time) as f64 / 1000.0, sum_temp + *temp as f64)
+            });
+        let (mean_t, mean_temp) = (sum_t / n, sum_temp / n);
+
+        let (covariance, variance) =
+            self.samples
+                .iter()
+                .fold((0.0, 0.0), |(covariance, variance), (time, temp)| {
+                    let t = (time - first_time) as f64 / 1000.0 - mean_t;
+                    (covariance + t * (*temp as f64 - mean_temp), variance + t * t)
+                });
+
+        if variance == 0.0 {
+            return None;
+        }
+
+        Some((covariance / variance) as f32)
+    }
+}
+
+impl Candidate {
+    fn confidence(&self, duration: i64) -> f32 {
+        let drop_score = (self.drop_c / EXPECTED_BOILER_DROP_C).clamp(0.0, 1.0);
+
+        // Hot water flowing through the group warms it up.
+        let grouphead_score = ((self.max_grouphead_temp - self.start_grouphead_temp)
+            / EXPECTED_GROUPHEAD_RISE_C)
+            .clamp(0.0, 1.0);
+
+        let (typical_min, typical_max) = TYPICAL_SHOT_DURATION_MS;
+        let duration_score = if duration < typical_min {
+            (duration - MIN_SHOT_DURATION_MS) as f32 / (typical_min - MIN_SHOT_DURATION_MS) as f32
+        } else if duration > typical_max {
+            (MAX_SHOT_DURATION_MS - duration) as f32 / (MAX_SHOT_DURATION_MS - typical_max) as f32
+        } else {
+            1.0
+        };
+
+        0.6 * drop_score + 0.2 * grouphead_score + 0.2 * duration_score.clamp(0.0, 1.0)
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ShotDetectionEvaluation {
+    pub recorded_shots: usize,
+    pub detected_shots: usize,
+    pub false_detections: usize,
+    pub missed_shots: usize,
+    pub precision: f32,
+    pub recall: f32,
+    // The mean absolute difference between the recorded and detected start and end times, in seconds.
+    pub mean_start_error_s: Option<f32>,
+    pub mean_end_error_s: Option<f32>,
+}
+
+// Replays the measurements around the manually recorded shots through the detector,
+// and compares the detected shots with the recorded ones.
+pub async fn evaluate(db: &Db, config: &ShotDetectionConfig) -> Result<ShotDetectionEvaluation> {
+    let recorded_shots: Vec<Shot> = db
+        .read_shots(&Range {
+            id: String::new(),
+            from: 0,
+            to: i64::MAX,
+            limit: None,
+            bucket_size: None,
+        })
+        .await?
+        .into_iter()
+        .filter(|shot| !shot.detected)
+        .collect();
+
+    // Shots pulled close together share a window, so the detector sees them as it would have live.
+    let mut windows: Vec<(i64, i64)> = vec![];
+
+    let mut shot_times: Vec<(i64, i64)> = recorded_shots
+        .iter()
+        .map(|shot| (shot.start_time, shot.end_time))
+        .collect();
+
+    shot_times.sort();
+
+    for (start_time, end_time) in shot_times.iter() {
+        let (from, to) = (
+            start_time - EVALUATION_MARGIN_MS,
+            end_time + EVALUATION_MARGIN_MS,
+        );
+
+        match windows.last_mut() {
+            Some((_, window_to)) if from <= *window_to => *window_to = to,
+            _ => windows.push((from, to)),
+        }
+    }
+
+    let mut detections: Vec<(i64, i64)> = vec![];
+
+    for (from, to) in windows {
+        let measurements = db
+            .read_measurements(&Range {
+                id: String::new(),
+                from,
+                to,
+                limit: None,
+                bucket_size: None,
+            })
+            .await?;
+
+        let thermal_model = db.read_thermal_model_at(from).await?;
+        let mut detector = ShotDetector::new(config, thermal_model);
+
+        for measurement in measurements.iter() {
+            // The detector doesn't run while steaming.
+            if measurement.steam {
+                detector.reset();
+                continue;
+            }
+
+            if let Some(ShotDetectorEvent::Ended {
+                start_time,
+                end_time,
+                confidence,
+            }) = detector.push(
+                measurement.time,
+                measurement.boiler_temp_c,
+                measurement.grouphead_temp_c,
+                measurement.heat_level.unwrap_or(0.0),
+            ) {
+                if confidence >= config.min_confidence {
+                    detections.push((start_time, end_time));
+                }
+            }
+        }
+    }
+
+    let mut evaluation = ShotDetectionEvaluation {
+        recorded_shots: shot_times.len(),
+        ..Default::default()
+    };
+
+    let mut start_errors: Vec<f32> = vec![];
+    let mut end_errors: Vec<f32> = vec![];
+
+    for (detected_start, detected_end) in detections.iter() {
+        let recorded_shot = shot_times
+            .iter()
+            .find(|(start_time, end_time)| detected_start < end_time && detected_end > start_time);
+
+        match recorded_shot {
+            Some((start_time, end_time)) => {
+                evaluation.detected_shots += 1;
+                start_errors.push((detected_start - start_time).abs() as f32 / 1000.0);
+                end_errors.push((detected_end - end_time).abs() as f32 / 1000.0);
+            }
+            None => evaluation.false_detections += 1,
+        }
+    }
+
+    // A shot could be detected as two drops, which shouldn't count as two detected shots.
+    let recorded_detected = shot_times
+        .iter()
+        .filter(|(start_time, end_time)| {
+            detections
+                .iter()
+                .any(|(detected_start, detected_end)| detected_start < end_time && detected_end > start_time)
+        })
+        .count();
+
+    evaluation.missed_shots = evaluation.recorded_shots - recorded_detected;
+
+    if !detections.is_empty() {
+        evaluation.precision = evaluation.detected_shots as f32 / detections.len() as f32;
+    }
+
+    if evaluation.recorded_shots > 0 {
+        evaluation.recall = recorded_detected as f32 / evaluation.recorded_shots as f32;
+    }
+
+    let mean = |errors: &Vec<f32>| {
+        (!errors.is_empty()).then(|| errors.iter().sum::<f32>() / errors.len() as f32)
+    };
+
+    evaluation.mean_start_error_s = mean(&start_errors);
+    evaluation.mean_end_error_s = mean(&end_errors);
+
+    Ok(evaluation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::db::Measurement;
+
+    const SAMPLE_INTERVAL_MS: i64 = 100;
+
+    // The traces drop at 0.2 °C/s, so the default sensitivity would miss them.
+    fn config() -> ShotDetectionConfig {
+        ShotDetectionConfig {
+            sensitivity: 0.15,
+            ..Default::default()
+        }
+    }
+
+    // A boiler temperature trace made of (duration in ms, slope in °C/s) segments, starting at 95 °C,
+    // with the grouphead warming by 1 °C whenever the boiler drops faster than 0.1 °C/s.
+    fn trace(start_time: i64, segments: &[(i64, f32)]) -> Vec<(i64, f32, f32)> {
+        let mut samples = vec![];
+        let (mut time, mut boiler_temp, mut grouphead_temp) = (start_time, 95.0, 80.0);
+
+        for (duration, slope) in segments {
+            let segment_end = time + duration;
+
+            while time < segment_end {
+                samples.push((time, boiler_temp, grouphead_temp));
+
+                let dt = SAMPLE_INTERVAL_MS as f32 / 1000.0;
+                boiler_temp += slope * dt;
+
+                if *slope < -0.1 {
+                    grouphead_temp = (grouphead_temp + dt / 10.0).min(81.0);
+                }
+
+                time += SAMPLE_INTERVAL_MS;
+            }
+        }
+
+        samples
+    }
+
+    fn detect(samples: &[(i64, f32, f32)]) -> Vec<ShotDetectorEvent> {
+        let mut detector = ShotDetector::new(&config(), None);
+
+        samples
+            .iter()
+            .filter_map(|(time, boiler_temp, grouphead_temp)| {
+                detector.push(*time, *boiler_temp, *grouphead_temp, 0.0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_shot_is_started_and_ended_with_high_confidence() {
+        let events = detect(&trace(0, &[(30_000, 0.0), (25_000, -0.2), (30_000, 0.0)]));
+
+        let [ShotDetectorEvent::Started { start_time }, ShotDetectorEvent::Ended {
+            start_time: end_start_time,
+            end_time,
+            confidence,
+        }] = events.as_slice()
+        else {
+            panic!("Expected a started and an ended event, got {events:?}");
+        };
+
+        assert_eq!(start_time, end_start_time);
+        assert!((start_time - 30_000).abs() <= SLOPE_WINDOW_MS, "{start_time}");
+        assert!((end_time - 55_000).abs() <= SLOPE_WINDOW_MS, "{end_time}");
+        assert!(*confidence > 0.9, "{confidence}");
+    }
+
+    #[test]
+    fn a_slow_coast_down_is_not_a_shot() {
+        let events = detect(&trace(0, &[(30_000, 0.0), (300_000, -0.02), (30_000, 0.0)]));
+
+        assert_eq!(events, vec![]);
+    }
+
+    #[test]
+    fn drops_longer_than_a_shot_have_no_confidence() {
+        let events = detect(&trace(0, &[(30_000, 0.0), (MAX_SHOT_DURATION_MS + 10_000, -0.2)]));
+
+        // The drop carries on after it's ended, so it's picked up again as a new candidate.
+        assert!(matches!(events.first(), Some(ShotDetectorEvent::Started { .. })));
+        assert!(
+            matches!(events.get(1), Some(ShotDetectorEvent::Ended { confidence, .. }) if *confidence == 0.0),
+            "{events:?}"
+        );
+    }
+
+    #[test]
+    fn a_drop_that_levels_off_briefly_is_one_shot() {
+        let events = detect(&trace(
+            0,
+            &[(30_000, 0.0), (12_500, -0.2), (3_000, 0.0), (12_500, -0.2), (30_000, 0.0)],
+        ));
+
+        assert_eq!(events.len(), 2, "{events:?}");
+        assert!(matches!(events[0], ShotDetectorEvent::Started { .. }));
+        assert!(
+            matches!(events[1], ShotDetectorEvent::Ended { end_time, .. } if (end_time - 58_000).abs() <= SLOPE_WINDOW_MS),
+            "{events:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn evaluate_compares_detected_and_recorded_shots() {
+        let db_path = std::env::temp_dir().join(format!("gesha-shot-evaluation-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&db_path);
+
+        let mut db = Db::new(db_path.to_str().unwrap()).await.unwrap();
+
+        // A shot that shows up in the trace, and one an hour later that doesn't.
+        let hour_ms = 60 * 60 * 1_000;
+        let samples = trace(0, &[(120_000, 0.0), (25_000, -0.2), (120_000, 0.0)])
+            .into_iter()
+            .chain(trace(hour_ms, &[(265_000, 0.0)]));
+
+        for (time, boiler_temp, grouphead_temp) in samples {
+            db.write_measurement_queue(Measurement {
+                time,
+                target_temp_c: 95.0,
+                boiler_temp_c: boiler_temp,
+                grouphead_temp_c: grouphead_temp,
+                thermofilter_temp_c: None,
+                power: true,
+                heat_level: Some(0.0),
+                pull: false,
+                steam: false,
+            })
+            .await
+            .unwrap();
+        }
+
+        db.write_shot(120_000, 145_000, None, None).await.unwrap();
+        db.write_shot(hour_ms + 120_000, hour_ms + 145_000, None, None)
+            .await
+            .unwrap();
+
+        let evaluation = evaluate(&db, &config()).await.unwrap();
+
+        assert_eq!(evaluation.recorded_shots, 2);
+        assert_eq!(evaluation.detected_shots, 1);
+        assert_eq!(evaluation.false_detections, 0);
+        assert_eq!(evaluation.missed_shots, 1);
+        assert_eq!(evaluation.precision, 1.0);
+        assert_eq!(evaluation.recall, 0.5);
+        assert!(evaluation.mean_start_error_s.unwrap() <= 3.0);
+
+        let _ = std::fs::remove_file(&db_path);
+    }
+}
@@ -0,0 +1,348 @@
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use log::{error, info};
+use serde::Serialize;
+use tokio::{
+    select,
+    sync::broadcast::{error::RecvError, Sender},
+    task::{self, JoinHandle},
+    time,
+};
+use tokio_util::sync::CancellationToken;
+
+use super::{
+    config::ShotTimerConfig,
+    mqtt::MqttOutgoingMessage,
+    profile::Profile,
+    state::{Event, Mode},
+    transition::TransitionTrigger,
+    util,
+};
+
+// How often the timer is published while a shot is being pulled.
+const PUBLISH_INTERVAL: Duration = Duration::from_millis(100);
+
+// Published on gesha/shot/timer while a shot is being pulled, and once more when it ends.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ShotTimerUpdate {
+    pub start_time: i64,
+    pub elapsed_ms: i64,
+    pub target_ms: i64,
+    // The active profile's target yield.
+    pub target_yield_g: Option<f32>,
+    // None if shots aren't limited.
+    pub max_ms: Option<i64>,
+    pub running: bool,
+}
+
+// Cuts the pump when a shot is ended automatically, the output is high while the pump is off.
+#[cfg(all(target_arch = "arm", target_os = "linux"))]
+struct PumpOffOutput(rppal::gpio::OutputPin);
+
+#[cfg(all(target_arch = "arm", target_os = "linux"))]
+impl PumpOffOutput {
+    fn new(pin: u8) -> Result<Self> {
+        let mut output_pin = rppal::gpio::Gpio::new()?.get(pin)?.into_output();
+
+        output_pin.set_low();
+
+        Ok(PumpOffOutput(output_pin))
+    }
+
+    fn set_pump_off(&mut self, pump_off: bool) {
+        if pump_off {
+            self.0.set_high();
+        } else {
+            self.0.set_low();
+        }
+    }
+}
+
+#[cfg(not(all(target_arch = "arm", target_os = "linux")))]
+struct PumpOffOutput;
+
+#[cfg(not(all(target_arch = "arm", target_os = "linux")))]
+impl PumpOffOutput {
+    fn new(_pin: u8) -> Result<Self> {
+        Err(anyhow::anyhow!("GPIO is only available on ARM Linux"))
+    }
+
+    fn set_pump_off(&mut self, _pump_off: bool) {}
+}
+
+// Times shots from Event::ShotStarted to Event::ShotEnded,
+// and ends them by returning to active mode when they reach the maximum duration.
+// The active profile's target shot time takes precedence over the config's.
+pub struct ShotTimer {
+    config: ShotTimerConfig,
+    profile: Option<Profile>,
+    event_tx: Sender<Event>,
+    cancel_token: CancellationToken,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ShotTimer {
+    pub fn new(config: &ShotTimerConfig, profile: Option<&Profile>, event_tx: Sender<Event>) -> Self {
+        ShotTimer {
+            config: config.clone(),
+            profile: profile.cloned(),
+            event_tx,
+            cancel_token: CancellationToken::new(),
+            handle: None,
+        }
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+        let mut pump_off_output = self
+            .config
+            .pump_off_pin
+            .map(PumpOffOutput::new)
+            .transpose()?;
+
+        let tx = self.event_tx.clone();
+        let mut rx = self.event_tx.subscribe();
+        let cancel_token = self.cancel_token.clone();
+
+        let config_target_ms = self.config.target_s as i64 * 1000;
+        let profile_targets = move |profile: Option<&Profile>| match profile {
+            Some(profile) => (
+                profile.settings.target_shot_time_s as i64 * 1000,
+                Some(profile.settings.target_yield_g),
+            ),
+            None => (config_target_ms, None),
+        };
+        let (mut target_ms, mut target_yield_g) = profile_targets(self.profile.as_ref());
+        let max_ms = (self.config.max_s > 0).then(|| self.config.max_s as i64 * 1000);
+        let pump_off_hold = Duration::from_secs(self.config.pump_off_hold_s);
+
+        self.handle = Some(task::spawn(async move {
+            let mut interval = time::interval(PUBLISH_INTERVAL);
+            let mut start_time: Option<i64> = None;
+            let mut is_auto_ended = false;
+            let mut pump_on_at: Option<time::Instant> = None;
+
+            let send = |event: Event| {
+                if let Err(err) = tx.send(event) {
+                    error!("Failed to send event: {}", err);
+                }
+            };
+
+            let timer_update = |start_time: i64, target_ms: i64, target_yield_g: Option<f32>, running: bool| {
+                let now = util::get_unix_timestamp(SystemTime::now()).unwrap_or(start_time);
+
+                ShotTimerUpdate {
+                    start_time,
+                    elapsed_ms: now - start_time,
+                    target_ms,
+                    target_yield_g,
+                    max_ms,
+                    running,
+                }
+            };
+
+            loop {
+                select! {
+                    _ = interval.tick() => {
+                        if let (Some(output), Some(at)) = (pump_off_output.as_mut(), pump_on_at) {
+                            if time::Instant::now() >= at {
+                                output.set_pump_off(false);
+                                pump_on_at = None;
+                            }
+                        }
+
+                        let Some(start_time) = start_time else {
+                            continue;
+                        };
+
+                        let update = timer_update(start_time, target_ms, target_yield_g, true);
+                        let is_over_max = max_ms.is_some_and(|max_ms| update.elapsed_ms >= max_ms);
+
+                        send(Event::OutgoingMqttMessage(MqttOutgoingMessage::ShotTimerUpdate(update)));
+
+                        if is_over_max && !is_auto_ended {
+                            info!("The shot reached the maximum duration, ending it");
+
+                            is_auto_ended = true;
+
+                            if let Some(output) = pump_off_output.as_mut() {
+                                output.set_pump_off(true);
+                                pump_on_at = Some(time::Instant::now() + pump_off_hold);
+                            }
+
+                            send(Event::ModeTransitionRequest {
+                                mode: Mode::Active,
+                                trigger: TransitionTrigger::MaxShotDuration,
+                            });
+                        }
+                    }
+                    event = rx.recv() => match event {
+                        Ok(Event::ShotStarted(shot_start_time)) => {
+                            start_time = Some(shot_start_time);
+                            is_auto_ended = false;
+                        }
+                        Ok(Event::ShotEnded) => {
+                            if let Some(start_time) = start_time.take() {
+                                send(Event::OutgoingMqttMessage(MqttOutgoingMessage::ShotTimerUpdate(
+                                    timer_update(start_time, target_ms, target_yield_g, false),
+                                )));
+                            }
+                        }
+                        Ok(Event::ProfileActivated(profile)) => {
+                            (target_ms, target_yield_g) = profile_targets(Some(&profile));
+                        }
+                        Err(RecvError::Closed) => break,
+                        _ => {}
+                    },
+                    _ = cancel_token.cancelled() => {
+                        break;
+                    }
+                }
+            }
+
+            if let Some(output) = pump_off_output.as_mut() {
+                output.set_pump_off(false);
+            }
+        }));
+
+        Ok(())
+    }
+
+    pub async fn stop(&mut self) -> Result<()> {
+        self.cancel_token.cancel();
+
+        if let Some(handle) = self.handle.take() {
+            handle.await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast::{self, Receiver};
+
+    use super::*;
+
+    fn now() -> i64 {
+        util::get_unix_timestamp(SystemTime::now()).unwrap()
+    }
+
+    // The events the timer sent, leaving out the ones the test sent it.
+    fn sent_events(rx: &mut Receiver<Event>) -> Vec<Event> {
+        let mut events = vec![];
+
+        while let Ok(event) = rx.try_recv() {
+            if !matches!(event, Event::ShotStarted(_) | Event::ShotEnded) {
+                events.push(event);
+            }
+        }
+
+        events
+    }
+
+    fn auto_ends(events: &[Event]) -> usize {
+        events
+            .iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    Event::ModeTransitionRequest {
+                        mode: Mode::Active,
+                        trigger: TransitionTrigger::MaxShotDuration,
+                    }
+                )
+            })
+            .count()
+    }
+
+    fn updates(events: &[Event]) -> Vec<&ShotTimerUpdate> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                Event::OutgoingMqttMessage(MqttOutgoingMessage::ShotTimerUpdate(update)) => Some(update),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn shot_timer() -> (ShotTimer, Sender<Event>, Receiver<Event>) {
+        let (tx, rx) = broadcast::channel(1000);
+        let mut shot_timer = ShotTimer::new(&ShotTimerConfig::default(), None, tx.clone());
+
+        shot_timer.start().unwrap();
+
+        (shot_timer, tx, rx)
+    }
+
+    // The elapsed time is measured with the system clock, so shots are started in the past rather than waited out.
+    #[tokio::test(start_paused = true)]
+    async fn the_maximum_duration_ends_the_shot_once() {
+        let (mut shot_timer, tx, mut rx) = shot_timer();
+
+        tx.send(Event::ShotStarted(now() - 61_000)).unwrap();
+        time::sleep(Duration::from_secs(1)).await;
+
+        let events = sent_events(&mut rx);
+
+        assert_eq!(auto_ends(&events), 1);
+        assert!(updates(&events).iter().all(|update| update.running && update.max_ms == Some(60_000)));
+
+        // The transition back to active ends the shot.
+        tx.send(Event::ShotEnded).unwrap();
+        time::sleep(Duration::from_secs(1)).await;
+
+        let events = sent_events(&mut rx);
+
+        assert_eq!(auto_ends(&events), 0);
+        assert!(matches!(updates(&events).last(), Some(update) if !update.running));
+        assert_eq!(updates(&events).iter().filter(|update| !update.running).count(), 1);
+
+        shot_timer.stop().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reaching_the_target_doesnt_end_the_shot() {
+        let (mut shot_timer, tx, mut rx) = shot_timer();
+
+        tx.send(Event::ShotStarted(now() - 30_000)).unwrap();
+        time::sleep(Duration::from_secs(1)).await;
+
+        let events = sent_events(&mut rx);
+        let updates = updates(&events);
+
+        assert_eq!(auto_ends(&events), 0);
+        assert!(!updates.is_empty());
+        assert!(updates
+            .iter()
+            .all(|update| update.running && update.target_ms == 28_000 && update.elapsed_ms >= update.target_ms));
+
+        shot_timer.stop().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn nothing_is_ended_after_a_manual_end() {
+        let (mut shot_timer, tx, mut rx) = shot_timer();
+
+        tx.send(Event::ShotStarted(now())).unwrap();
+        time::sleep(Duration::from_millis(500)).await;
+
+        tx.send(Event::ShotEnded).unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+
+        let events = sent_events(&mut rx);
+
+        assert_eq!(auto_ends(&events), 0);
+        assert!(matches!(updates(&events).last(), Some(update) if !update.running));
+
+        // A late end, e.g. from the shot detector, doesn't publish the timer again.
+        tx.send(Event::ShotEnded).unwrap();
+        time::sleep(Duration::from_secs(120)).await;
+
+        assert!(sent_events(&mut rx).is_empty());
+
+        shot_timer.stop().await.unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use log::error;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::broadcast::{error::RecvError, Sender},
+    task::{self, JoinHandle},
+    time::{self, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::controller::{ControlMethod, ControllerParameters};
+
+use super::{
+    mqtt::MqttOutgoingMessage,
+    state::{Event, IsPowerOn, Mode},
+};
+
+// Incremented when a field is removed or changes meaning, adding a field doesn't change the version.
+pub const STATE_SNAPSHOT_VERSION: u32 = 1;
+
+// The snapshot is published once it's stopped changing for this long...
+const DEBOUNCE: Duration = Duration::from_millis(250);
+// ...or when it's been changing for this long, since the measurements change it continuously.
+const MAX_DEBOUNCE: Duration = Duration::from_secs(1);
+
+// Everything a client needs to show the machine's state,
+// published on gesha/state and in response to gesha/state/command.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StateSnapshot {
+    pub version: u32,
+    pub mode: Mode,
+    pub power_relay_available: bool,
+    pub power_state: IsPowerOn,
+    pub control_method: ControlMethod,
+    pub controller_parameters: ControllerParameters,
+    pub boiler_level: f32,
+    pub measurement: Option<MeasurementSnapshot>,
+    // The error from the last failed read, None once the sensors are read again.
+    pub sensor_error: Option<String>,
+    // The target the boiler is currently being held at.
+    pub target_temperature: f32,
+    pub targets: TargetsSnapshot,
+    pub shot: Option<ShotSnapshot>,
+    pub active_profile_id: Option<i64>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MeasurementSnapshot {
+    pub timestamp: i64,
+    pub boiler_temp: f32,
+    pub grouphead_temp: f32,
+    pub thermofilter_temp: Option<f32>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TargetsSnapshot {
+    pub brew: f32,
+    pub steam: f32,
+    pub standby: f32,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ShotSnapshot {
+    pub start_time: i64,
+    // Whether the shot was detected from the temperature, rather than started in brew mode.
+    pub detected: bool,
+}
+
+// The payload of gesha/state/command, the snapshot is published on gesha/state/{id}.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StateRequest {
+    pub id: String,
+}
+
+// Publishes Event::StateSnapshotChanged on gesha/state, debounced.
+pub struct StatePublisher {
+    event_tx: Sender<Event>,
+    cancel_token: CancellationToken,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StatePublisher {
+    pub fn new(event_tx: Sender<Event>) -> Self {
+        StatePublisher {
+            event_tx,
+            cancel_token: CancellationToken::new(),
+            handle: None,
+        }
+    }
+
+    pub fn start(&mut self) {
+        let tx = self.event_tx.clone();
+        let mut rx = self.event_tx.subscribe();
+        let cancel_token = self.cancel_token.clone();
+
+        self.handle = Some(task::spawn(async move {
+            let mut pending: Option<StateSnapshot> = None;
+            let mut pending_since = Instant::now();
+            let mut publish_at = Instant::now();
+
+            loop {
+                select! {
+                    _ = time::sleep_until(publish_at), if pending.is_some() => {
+                        if let Some(snapshot) = pending.take() {
+                            if let Err(err) = tx.send(Event::OutgoingMqttMessage(
+                                MqttOutgoingMessage::StateUpdate(snapshot),
+                            )) {
+                                error!("Failed to send event: {}", err);
+                            }
+                        }
+                    }
+                    event = rx.recv() => match event {
+                        Ok(Event::StateSnapshotChanged(snapshot)) => {
+                            let now = Instant::now();
+
+                            if pending.is_none() {
+                                pending_since = now;
+                            }
+
+                            pending = Some(snapshot);
+                            publish_at = (now + DEBOUNCE).min(pending_since + MAX_DEBOUNCE);
+                        }
+                        Err(RecvError::Closed) => break,
+                        _ => {}
+                    },
+                    _ = cancel_token.cancelled() => {
+                        break;
+                    }
+                }
+            }
+        }));
+    }
+
+    pub async fn stop(&mut self) -> Result<()> {
+        self.cancel_token.cancel();
+
+        if let Some(handle) = self.handle.take() {
+            handle.await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::core::{
+        mqtt::{MqttIncomingMessage, MqttOutgoingMessage},
+        state::TemperatureMeasurement,
+        transition::tests::state,
+    };
+
+    fn snapshots(events: &[Event]) -> Vec<&StateSnapshot> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                Event::StateSnapshotChanged(snapshot) => Some(snapshot),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn state_request(id: &str) -> Event {
+        Event::IncomingMqttMessage(
+            MqttIncomingMessage::StateRequest(StateRequest { id: id.to_string() }),
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn a_snapshot_is_only_sent_when_it_changes() {
+        let (mut state, _rx) = state("snapshot-changes").await;
+
+        // The first event always sends one, nothing has been sent yet.
+        let events = state.handle_event(&state_request("a")).await.unwrap();
+        assert_eq!(snapshots(&events).len(), 1);
+
+        let events = state.handle_event(&state_request("b")).await.unwrap();
+        assert!(snapshots(&events).is_empty());
+
+        let measurement = Event::TemperatureChanged(TemperatureMeasurement {
+            boiler_temp: 90.0,
+            grouphead_temp: 80.0,
+            thermofilter_temp: None,
+            timestamp: SystemTime::now(),
+        });
+
+        let events = state.handle_event(&measurement).await.unwrap();
+        let changed = snapshots(&events);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(
+            changed[0].measurement.as_ref().map(|m| m.boiler_temp),
+            Some(90.0)
+        );
+
+        let events = state.handle_event(&measurement).await.unwrap();
+        assert!(snapshots(&events).is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_state_request_returns_every_field() {
+        let (mut state, _rx) = state("snapshot-request").await;
+
+        let events = state.handle_event(&state_request("client")).await.unwrap();
+
+        let (id, snapshot) = events
+            .iter()
+            .find_map(|event| match event {
+                Event::OutgoingMqttMessage(MqttOutgoingMessage::StateResponse(id, snapshot)) => {
+                    Some((id, snapshot))
+                }
+                _ => None,
+            })
+            .unwrap();
+
+        assert_eq!(id, "client");
+        assert_eq!(snapshot, &state.snapshot().unwrap());
+
+        let json = serde_json::to_value(snapshot).unwrap();
+        let mut fields: Vec<_> = json.as_object().unwrap().keys().cloned().collect();
+        fields.sort();
+
+        assert_eq!(
+            fields,
+            [
+                "activeProfileId",
+                "boilerLevel",
+                "controlMethod",
+                "controllerParameters",
+                "measurement",
+                "mode",
+                "powerRelayAvailable",
+                "powerState",
+                "sensorError",
+                "shot",
+                "targetTemperature",
+                "targets",
+                "version",
+            ]
+        );
+        assert_eq!(json["version"], STATE_SNAPSHOT_VERSION);
+        assert_eq!(
+            json["targets"]
+                .as_object()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            ["brew", "standby", "steam"]
+        );
+    }
+}
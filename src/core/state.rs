@@ -1,19 +1,46 @@
-use std::time::{Duration, SystemTime};
+use std::{
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use anyhow::{anyhow, Result};
-use log::{error, info};
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
 use tokio::sync::broadcast::Sender;
 
 use crate::{
-    controller::ControlMethod,
-    core::db::{DB_KEY_CONTROL_METHOD, DB_KEY_TARGET_TEMPERATURE},
+    controller::{
+        ControlMethod, ControllerDegradation, ControllerParameters, ControllerTelemetrySample,
+        MAX_BOILER_TEMP_C,
+    },
+    core::db::{
+        DB_KEY_ACTIVE_PROFILE, DB_KEY_CONTROL_METHOD, DB_KEY_STANDBY_TARGET_TEMPERATURE,
+        DB_KEY_STEAM_TARGET_TEMPERATURE, DB_KEY_TARGET_TEMPERATURE,
+    },
     models,
 };
 
 use super::{
-    db::{ConfigItem, Db, Measurement},
-    mqtt::{MqttIncomingMessage, MqttOutgoingMessage, ValueChange},
+    auto_off::{AutoOffEvent, AutoOffTimer},
+    brew_curve::BrewCurveRun,
+    config::{Config, ModelsConfig, ShotDetectionConfig, TargetBounds, TargetTemperatureConfig},
+    db::{ConfigItem, Db, Measurement, DB_PATH},
+    eta::{EtaSample, EtaTracker},
+    event_log,
+    power_relay::RelayCommandFailure,
+    profile::Profile,
+    schedule::{CronExpression, Schedule},
+    mqtt::{
+        MqttIncomingMessage, MqttOutgoingMessage, PredictionChange, TargetTemperatureRejection,
+        ValueChange,
+    },
+    shot_detector::{ShotDetector, ShotDetectorEvent},
+    snapshot::{
+        MeasurementSnapshot, ShotSnapshot, StateSnapshot, TargetsSnapshot, STATE_SNAPSHOT_VERSION,
+    },
+    thermal_model::{ThermalModel, ThermalModelEstimator},
+    transition::{find_transition, Action, Guard, ModeTransition, TransitionTrigger},
     util,
 };
 
@@ -26,34 +53,128 @@ pub struct State {
     pub current_temperature: Option<TemperatureMeasurement>,
     pub target_temperature: f32,
     pub target_temperature_steam: f32,
+    pub target_temperature_standby: f32,
     pub shot_state: Shot,
+    // Set while a shot follows the active profile's brew curve.
+    brew_curve_run: Option<BrewCurveRun>,
+    pub thermal_model: Option<ThermalModel>,
+    pub schedules: Vec<Schedule>,
+    pub profiles: Vec<Profile>,
+    pub active_profile: Option<Profile>,
+    pub controller_parameters: ControllerParameters,
+    temperature_read_error: Option<String>,
+    target_bounds: TargetTemperatureConfig,
     db: Db,
-    model: models::PredictiveModels,
+    model: Arc<models::PredictiveModels>,
+    models_config: ModelsConfig,
+    extraction_temp_interval: models::ResidualInterval,
+    extraction_temp_residual_time: i64,
+    persist_controller_telemetry: bool,
+    thermal_model_estimator: Option<ThermalModelEstimator>,
+    thermal_model_interval: Duration,
+    shot_detector: Option<ShotDetector>,
+    // When and how confidently the detector ended an auto brewed shot, it's written when brew mode is left.
+    detected_shot_end: Option<(i64, f32)>,
+    shot_detection_config: ShotDetectionConfig,
+    eta_tracker: EtaTracker,
+    auto_off_timer: Option<AutoOffTimer>,
+    last_snapshot: Option<StateSnapshot>,
 }
 
+// Consecutive measurements are nearly identical, so the extraction temperature model's residuals
+// are only sampled this often, which keeps them representative of more than the last few seconds.
+const EXTRACTION_TEMP_RESIDUAL_INTERVAL_MS: i64 = 5_000;
+
+// The thermofilter is measured every 100ms while the machine is on, this many rows is enough
+// to seed every residual when the interval is seeded from the DB.
+const THERMOFILTER_SEED_ROWS: i64 = models::MAX_RESIDUALS as i64 * EXTRACTION_TEMP_RESIDUAL_INTERVAL_MS / 100;
+
+// The sensors are considered unhealthy if there hasn't been a measurement for this long,
+// the poller sends one at least every second.
+const SENSOR_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub enum Shot {
     NotPulling,
     PullStarted(i64),
+    // The machine was put into brew mode because a shot was detected from the temperature.
+    PullDetected(i64),
 }
 
 impl State {
-    pub async fn new(event_tx: Sender<Event>) -> Result<State> {
-        let mut db = Db::new("/opt/gesha/var/db/gesha.db").await?;
+    pub async fn new(
+        event_tx: Sender<Event>,
+        config: &Config,
+        model: Arc<models::PredictiveModels>,
+    ) -> Result<State> {
+        let mut db = Db::new(config.db_path.as_deref().unwrap_or(DB_PATH)).await?;
 
         db.start_measurement_writer_interval(Duration::from_secs(60));
 
         let configs = (&db).read_config().await?;
 
-        let target_temperature: f32 = configs
-            .get(DB_KEY_TARGET_TEMPERATURE)
-            .map(|s| serde_plain::from_str(s).unwrap())
-            .unwrap_or(95.0);
+        let target_temperature = read_target_temperature(
+            configs.get(DB_KEY_TARGET_TEMPERATURE),
+            95.0,
+            &config.targets.brew,
+        );
+        let target_temperature_steam = read_target_temperature(
+            configs.get(DB_KEY_STEAM_TARGET_TEMPERATURE),
+            130.0,
+            &config.targets.steam,
+        );
+        let target_temperature_standby = read_target_temperature(
+            configs.get(DB_KEY_STANDBY_TARGET_TEMPERATURE),
+            70.0,
+            &config.targets.standby,
+        );
         let control_method: ControlMethod = configs
             .get(DB_KEY_CONTROL_METHOD)
             .map(|s| serde_plain::from_str(s).unwrap())
             .unwrap_or(ControlMethod::None);
 
-        let state = State {
+        // The last estimate is used as a starting point, so the model doesn't need to be re-learned after a restart.
+        let thermal_model = db.read_thermal_model_at(i64::MAX).await?;
+
+        let thermal_model_estimator = if config.thermal_model.interval_s > 0 {
+            Some(ThermalModelEstimator::new(
+                &config.thermal_model,
+                thermal_model.as_ref(),
+            ))
+        } else {
+            None
+        };
+
+        let schedules = db.read_schedules().await?;
+
+        // The active profile's targets and control method are already in the config,
+        // they were written when it was activated.
+        let profiles = db.read_profiles().await?;
+        let active_profile_id: Option<i64> = configs
+            .get(DB_KEY_ACTIVE_PROFILE)
+            .and_then(|s| match serde_plain::from_str(s) {
+                Ok(id) => Some(id),
+                Err(err) => {
+                    warn!("The stored active profile {s:?} is invalid, no profile is active: {err}");
+                    None
+                }
+            });
+        let active_profile = profiles
+            .iter()
+            .find(|profile| Some(profile.id) == active_profile_id)
+            .cloned();
+
+        let mut eta_tracker = EtaTracker::new();
+
+        if let Some(profile) = &active_profile {
+            eta_tracker.set_require_preheat(profile.settings.require_preheat);
+        }
+
+        let shot_detector = config
+            .shot_detection
+            .enabled
+            .then(|| ShotDetector::new(&config.shot_detection, thermal_model.clone()));
+
+        let mut state = State {
             mode: Mode::Idle,
             control_method,
             power_relay_available: true,
@@ -61,34 +182,111 @@ impl State {
             boiler_state: 0.0,
             current_temperature: None,
             target_temperature,
-            target_temperature_steam: 130.0,
+            target_temperature_steam,
+            target_temperature_standby,
             shot_state: Shot::NotPulling,
+            brew_curve_run: None,
+            thermal_model,
+            schedules,
+            controller_parameters: active_profile
+                .as_ref()
+                .map(|profile| profile.settings.controller_parameters)
+                .unwrap_or_default(),
+            profiles,
+            active_profile,
+            temperature_read_error: None,
+            target_bounds: config.targets.clone(),
             db,
-            model: models::PredictiveModels::new()?,
+            model,
+            models_config: config.models.clone(),
+            extraction_temp_interval: models::ResidualInterval::new(),
+            extraction_temp_residual_time: 0,
+            persist_controller_telemetry: config.controller_telemetry.persist,
+            thermal_model_estimator,
+            thermal_model_interval: Duration::from_secs(config.thermal_model.interval_s),
+            shot_detector,
+            detected_shot_end: None,
+            shot_detection_config: config.shot_detection.clone(),
+            eta_tracker,
+            auto_off_timer: AutoOffTimer::new(
+                &config.auto_off,
+                util::get_unix_timestamp(SystemTime::now()).unwrap_or_default(),
+            ),
+            last_snapshot: None,
         };
 
-        for event in vec![
+        state.seed_extraction_temp_interval().await?;
+
+        let mut events = vec![
             Event::OutgoingMqttMessage(MqttOutgoingMessage::ModeUpdate(state.mode.clone())),
             Event::OutgoingMqttMessage(MqttOutgoingMessage::ControlMethodUpdate(
-                state.control_method.clone(),
+                state.control_method,
             )),
             Event::OutgoingMqttMessage(MqttOutgoingMessage::TargetTemperatureUpdate(
                 state.target_temperature,
             )),
-        ] {
+            Event::OutgoingMqttMessage(MqttOutgoingMessage::ModeTargetTemperatureUpdate(
+                TargetMode::Brew,
+                state.target_temperature,
+            )),
+            Event::OutgoingMqttMessage(MqttOutgoingMessage::ModeTargetTemperatureUpdate(
+                TargetMode::Steam,
+                state.target_temperature_steam,
+            )),
+            Event::OutgoingMqttMessage(MqttOutgoingMessage::ModeTargetTemperatureUpdate(
+                TargetMode::Standby,
+                state.target_temperature_standby,
+            )),
+            Event::OutgoingMqttMessage(MqttOutgoingMessage::ModelsUpdate(
+                state.model.info.clone(),
+            )),
+            Event::OutgoingMqttMessage(MqttOutgoingMessage::SchedulesUpdate(
+                state.schedules.clone(),
+            )),
+            Event::OutgoingMqttMessage(MqttOutgoingMessage::ProfilesUpdate(
+                state.profiles.clone(),
+            )),
+            Event::OutgoingMqttMessage(MqttOutgoingMessage::ActiveProfileUpdate(
+                state.active_profile.clone(),
+            )),
+            // Clears a degradation and warning retained from before a restart.
+            Event::OutgoingMqttMessage(MqttOutgoingMessage::ControllerDegradationUpdate(None)),
+            Event::OutgoingMqttMessage(MqttOutgoingMessage::AutoOffWarningUpdate(None)),
+        ];
+
+        if let Some(thermal_model) = &state.thermal_model {
+            events.push(Event::OutgoingMqttMessage(
+                MqttOutgoingMessage::ThermalModelUpdate(thermal_model.clone()),
+            ));
+        }
+
+        for event in events {
             event_tx.send(event)?;
         }
 
         Ok(state)
     }
 
-    async fn set_target_temperature(&mut self, target_temperature: f32) -> Result<ConfigItem> {
-        self.target_temperature = target_temperature;
-        let value: String = serde_plain::to_string::<f32>(&target_temperature)?;
+    // The target isn't range checked, see `target_bounds`.
+    async fn set_mode_target_temperature(
+        &mut self,
+        mode: TargetMode,
+        target_temperature: f32,
+    ) -> Result<ConfigItem> {
+        let (key, target) = match mode {
+            TargetMode::Brew => (DB_KEY_TARGET_TEMPERATURE, &mut self.target_temperature),
+            TargetMode::Steam => (DB_KEY_STEAM_TARGET_TEMPERATURE, &mut self.target_temperature_steam),
+            TargetMode::Standby => (
+                DB_KEY_STANDBY_TARGET_TEMPERATURE,
+                &mut self.target_temperature_standby,
+            ),
+        };
+
+        *target = target_temperature;
 
         let config_item = ConfigItem {
-            key: DB_KEY_TARGET_TEMPERATURE.to_string(),
-            value,
+            key: key.to_string(),
+            value: serde_plain::to_string::<f32>(&target_temperature)?,
         };
 
         self.db.write_config(&config_item).await?;
@@ -96,8 +294,38 @@ impl State {
         Ok(config_item)
     }
 
+    fn target_bounds(&self, mode: TargetMode) -> &TargetBounds {
+        match mode {
+            TargetMode::Brew => &self.target_bounds.brew,
+            TargetMode::Steam => &self.target_bounds.steam,
+            TargetMode::Standby => &self.target_bounds.standby,
+        }
+    }
+
+    // Whose target the boiler is being held at in the current mode.
+    fn target_mode(&self) -> TargetMode {
+        TargetMode::from(&self.mode)
+    }
+
+    // The temperature the boiler is being held at in the current mode.
+    fn mode_target_temperature(&self) -> f32 {
+        if let Some(setpoint) = self.brew_curve_run.as_ref().and_then(|run| run.setpoint()) {
+            return setpoint;
+        }
+
+        self.target_temperature_for(self.target_mode())
+    }
+
+    fn target_temperature_for(&self, target_mode: TargetMode) -> f32 {
+        match target_mode {
+            TargetMode::Brew => self.target_temperature,
+            TargetMode::Steam => self.target_temperature_steam,
+            TargetMode::Standby => self.target_temperature_standby,
+        }
+    }
+
     async fn set_control_method(&mut self, control_method: &ControlMethod) -> Result<ConfigItem> {
-        self.control_method = control_method.clone();
+        self.control_method = *control_method;
         let config_item = ConfigItem {
             key: DB_KEY_CONTROL_METHOD.to_string(),
             value: serde_plain::to_string::<ControlMethod>(control_method)?,
@@ -108,28 +336,105 @@ impl State {
         Ok(config_item)
     }
 
+    // Reloads the predictive models from the configured paths, e.g. after retraining.
+    pub async fn reload_models(&mut self) -> Result<Vec<Event>> {
+        let model = Arc::new(models::PredictiveModels::load(&self.models_config)?);
+
+        info!("Reloaded models: {:?}", model.info);
+
+        self.model = model.clone();
+
+        // The residuals of the previous model say nothing about the new one.
+        self.seed_extraction_temp_interval().await?;
+
+        Ok(vec![
+            Event::OutgoingMqttMessage(MqttOutgoingMessage::ModelsUpdate(model.info.clone())),
+            Event::ModelsChanged(model),
+        ])
+    }
+
     pub async fn handle_event(&mut self, event: &Event) -> Result<Vec<Event>> {
+        let mut events = self.apply_event(event).await?;
+
+        let snapshot = self.snapshot()?;
+
+        if self.last_snapshot.as_ref() != Some(&snapshot) {
+            self.last_snapshot = Some(snapshot.clone());
+            events.push(Event::StateSnapshotChanged(snapshot));
+        }
+
+        Ok(events)
+    }
+
+    pub fn snapshot(&self) -> Result<StateSnapshot> {
+        let measurement = match &self.current_temperature {
+            Some(temp) => Some(MeasurementSnapshot {
+                timestamp: util::get_unix_timestamp(temp.timestamp)?,
+                boiler_temp: temp.boiler_temp,
+                grouphead_temp: temp.grouphead_temp,
+                thermofilter_temp: temp.thermofilter_temp,
+            }),
+            None => None,
+        };
+
+        let shot = match self.shot_state {
+            Shot::NotPulling => None,
+            Shot::PullStarted(start_time) => Some(ShotSnapshot {
+                start_time,
+                detected: false,
+            }),
+            Shot::PullDetected(start_time) => Some(ShotSnapshot {
+                start_time,
+                detected: true,
+            }),
+        };
+
+        Ok(StateSnapshot {
+            version: STATE_SNAPSHOT_VERSION,
+            mode: self.mode.clone(),
+            power_relay_available: self.power_relay_available,
+            power_state: self.power_state,
+            control_method: self.control_method,
+            controller_parameters: self.controller_parameters,
+            boiler_level: self.boiler_state,
+            measurement,
+            sensor_error: self.temperature_read_error.clone(),
+            target_temperature: self.mode_target_temperature(),
+            targets: TargetsSnapshot {
+                brew: self.target_temperature,
+                steam: self.target_temperature_steam,
+                standby: self.target_temperature_standby,
+            },
+            shot,
+            active_profile_id: self.active_profile_id(),
+        })
+    }
+
+    async fn apply_event(&mut self, event: &Event) -> Result<Vec<Event>> {
+        // Any command counts as activity, but the relay reporting its state doesn't.
+        if let Event::IncomingMqttMessage(message, _) = event {
+            if !matches!(
+                message,
+                MqttIncomingMessage::ExternRelayAvailabilityChanged(_)
+                    | MqttIncomingMessage::ExternRelayPowerStateChanged(_)
+            ) {
+                self.record_activity()?;
+            }
+        }
+
         return match event {
-            Event::IncomingMqttMessage(message) => match message {
+            Event::IncomingMqttMessage(message, client_id) => match message {
                 MqttIncomingMessage::ExternRelayAvailabilityChanged(relay_is_available) => {
                     self.power_relay_available = *relay_is_available;
 
                     if !relay_is_available && self.mode != Mode::Idle {
-                        let mut events: Vec<Event> = vec![
-                            Event::ModeChanged(Mode::Idle),
-                            Event::PowerStateChanged(false),
-                        ];
-
-                        if self.mode == Mode::Brew {
-                            self.toggle_brew_mode().await?;
-                        }
-
-                        if self.mode == Mode::Steam {
-                            self.add_steam_mode_events(false, &mut events);
-                        }
-
                         self.power_state = false;
-                        self.mode = Mode::Idle;
+
+                        let mut events = vec![Event::PowerStateChanged(false)];
+                        events.extend(
+                            self.transition(Mode::Idle, TransitionTrigger::RelayUnavailable, None)
+                                .await?,
+                        );
 
                         return Ok(events);
                     }
@@ -141,90 +446,27 @@ impl State {
                         return Ok(vec![]);
                     }
 
-                    let mut events: Vec<Event> = vec![];
+                    self.power_state = *new_power_state;
 
-                    if *new_power_state == true {
-                        if self.mode == Mode::Idle {
-                            self.mode = Mode::Active;
-                            events.push(Event::ModeChanged(Mode::Active));
-                        }
+                    let mut events = vec![Event::PowerStateChanged(*new_power_state)];
 
-                        self.power_state = true;
-                        events.push(Event::PowerStateChanged(true));
-                    } else {
-                        self.power_state = false;
-                        events.push(Event::PowerStateChanged(false));
-
-                        if self.mode != Mode::Idle {
-                            self.mode = Mode::Idle;
-                            events.push(Event::ModeChanged(Mode::Idle));
-                        }
+                    if *new_power_state && self.mode == Mode::Idle {
+                        events.extend(
+                            self.transition(Mode::Active, TransitionTrigger::RelayPowerOn, None)
+                                .await?,
+                        );
+                    } else if !*new_power_state && self.mode != Mode::Idle {
+                        events.extend(
+                            self.transition(Mode::Idle, TransitionTrigger::RelayPowerOff, None)
+                                .await?,
+                        );
                     }
 
                     Ok(events)
                 }
                 MqttIncomingMessage::ModeSet(new_mode) => {
-                    let current_mode = self.mode.clone();
-
-                    if current_mode != *new_mode {
-                        self.mode = new_mode.clone();
-                        let mut events: Vec<Event> = vec![Event::ModeChanged(new_mode.clone())];
-
-                        match (current_mode, new_mode) {
-                            // Handle transitions to Active mode
-                            (Mode::Idle, Mode::Active) => {
-                                self.add_power_mode_events(true, &mut events);
-                            }
-                            (Mode::Brew, Mode::Active) => {
-                                self.toggle_brew_mode().await?;
-                            }
-                            (Mode::Steam, Mode::Active) => {
-                                self.add_steam_mode_events(false, &mut events);
-                            }
-
-                            // Handle transitions to Idle mode
-                            (Mode::Active, Mode::Idle) => {
-                                self.add_power_mode_events(false, &mut events);
-                            }
-                            (Mode::Brew, Mode::Idle) => {
-                                self.add_power_mode_events(false, &mut events);
-                                self.toggle_brew_mode().await?;
-                            }
-                            (Mode::Steam, Mode::Idle) => {
-                                self.add_steam_mode_events(false, &mut events);
-                                self.add_power_mode_events(false, &mut events);
-                            }
-
-                            // Handle transitions to Brew mode
-                            (Mode::Active, Mode::Brew) => {
-                                self.toggle_brew_mode().await?;
-                            }
-
-                            // Handle transitions to Steam mode
-                            (Mode::Idle, Mode::Steam) => {
-                                self.add_power_mode_events(true, &mut events);
-                                self.add_steam_mode_events(true, &mut events);
-                            }
-                            (Mode::Active, Mode::Steam) => {
-                                self.add_steam_mode_events(true, &mut events);
-                            }
-                            (Mode::Brew, Mode::Steam) => {
-                                self.toggle_brew_mode().await?;
-                                self.add_steam_mode_events(true, &mut events);
-                            }
-                            _ => {
-                                return Err(anyhow!(
-                                    "Moving from mode {:?} to {:?} is not supported",
-                                    self.mode,
-                                    new_mode
-                                ))
-                            }
-                        }
-
-                        Ok(events)
-                    } else {
-                        Ok(vec![])
-                    }
+                    self.transition(new_mode.clone(), TransitionTrigger::User, client_id.clone())
+                        .await
                 }
                 MqttIncomingMessage::ControlMethodSet(control_method) => {
                     if self.mode == Mode::Steam {
@@ -232,20 +474,62 @@ impl State {
                     }
 
                     let config_item = self.set_control_method(control_method).await?;
-                    self.control_method = control_method.clone();
+                    self.control_method = *control_method;
 
                     Ok(vec![
-                        Event::ControlMethodChanged(control_method.clone()),
+                        Event::ControlMethodChanged(*control_method),
                         Event::OutgoingMqttMessage(MqttOutgoingMessage::ConfigUpdate(config_item)),
                     ])
                 }
-                MqttIncomingMessage::TemperatureTargetSet(new_target_temp) => {
-                    let config_item = self.set_target_temperature(*new_target_temp).await?;
+                MqttIncomingMessage::TemperatureTargetSet(mode, new_target_temp) => {
+                    let bounds = *self.target_bounds(*mode);
 
-                    Ok(vec![
-                        Event::TargetTemperatureChanged(*new_target_temp),
+                    if !bounds.contains(*new_target_temp) {
+                        info!("Rejected the {mode:?} target temperature {new_target_temp}");
+
+                        return Ok(vec![Event::OutgoingMqttMessage(
+                            MqttOutgoingMessage::TargetTemperatureRejected(
+                                TargetTemperatureRejection {
+                                    mode: *mode,
+                                    temperature: *new_target_temp,
+                                    min: bounds.min,
+                                    max: bounds.max,
+                                    reason: format!(
+                                        "The target must be between {}°C and {}°C",
+                                        bounds.min, bounds.max
+                                    ),
+                                },
+                            ),
+                        )]);
+                    }
+
+                    let config_item = self
+                        .set_mode_target_temperature(*mode, *new_target_temp)
+                        .await?;
+
+                    let mut events = vec![
+                        Event::OutgoingMqttMessage(MqttOutgoingMessage::ModeTargetTemperatureUpdate(
+                            *mode,
+                            *new_target_temp,
+                        )),
                         Event::OutgoingMqttMessage(MqttOutgoingMessage::ConfigUpdate(config_item)),
-                    ])
+                    ];
+
+                    // Other modes' targets are applied when the mode is entered,
+                    // and a brew curve keeps control of the target until the shot ends.
+                    if self.target_mode() == *mode && self.brew_curve_run.is_none() {
+                        events.push(Event::TargetTemperatureChanged(*new_target_temp));
+                    }
+
+                    Ok(events)
+                }
+                // Only standby is woken up, a hint shouldn't turn the machine on.
+                MqttIncomingMessage::Wake => {
+                    if self.mode != Mode::Standby {
+                        return Ok(vec![]);
+                    }
+
+                    self.transition(Mode::Active, TransitionTrigger::Wake, None).await
                 }
                 MqttIncomingMessage::TemperatureHistoryRequest(range) => {
                     let result = self.db.read_measurements(range).await?;
@@ -276,7 +560,99 @@ impl State {
                         MqttOutgoingMessage::ShotHistoryResponse(range.id.clone(), json_result),
                     )])
                 }
+                MqttIncomingMessage::EventHistoryRequest(range) => {
+                    let entries = self.db.read_event_log(range).await?;
+                    let json_result = serde_json::to_string(&entries)?;
+
+                    Ok(vec![Event::OutgoingMqttMessage(
+                        MqttOutgoingMessage::EventHistoryResponse(range.id.clone(), json_result),
+                    )])
+                }
+                MqttIncomingMessage::StateRequest(request) => Ok(vec![Event::OutgoingMqttMessage(
+                    MqttOutgoingMessage::StateResponse(request.id.clone(), self.snapshot()?),
+                )]),
+                MqttIncomingMessage::ModelsReloadRequest => self.reload_models().await,
+                MqttIncomingMessage::ScheduleCreate(schedule) => {
+                    CronExpression::from_str(&schedule.cron)?;
+
+                    let id = self.db.write_schedule(schedule).await?;
+
+                    info!("Created schedule {id}: {:?}", schedule);
+
+                    self.update_schedules().await
+                }
+                MqttIncomingMessage::ScheduleDelete(id) => {
+                    self.db.delete_schedule(*id).await?;
+
+                    info!("Deleted schedule {id}");
+
+                    self.update_schedules().await
+                }
+                MqttIncomingMessage::ScheduleListRequest => Ok(vec![Event::OutgoingMqttMessage(
+                    MqttOutgoingMessage::SchedulesUpdate(self.schedules.clone()),
+                )]),
+                MqttIncomingMessage::ProfileCreate(settings) => {
+                    settings.validate(&self.target_bounds)?;
+
+                    let id = self.db.write_profile(settings).await?;
+
+                    info!("Created profile {id}: {:?}", settings);
+
+                    self.update_profiles().await
+                }
+                MqttIncomingMessage::ProfileUpdate(profile) => {
+                    profile.settings.validate(&self.target_bounds)?;
+
+                    self.db.update_profile(profile).await?;
+
+                    info!("Updated profile {}: {:?}", profile.id, profile.settings);
+
+                    let mut events = self.update_profiles().await?;
+
+                    // Changes to the active profile take effect straight away.
+                    if self.active_profile.as_ref().is_some_and(|active| active.id == profile.id) {
+                        events.extend(self.activate_profile(profile.id).await?);
+                    }
+
+                    Ok(events)
+                }
+                MqttIncomingMessage::ProfileDelete(id) => {
+                    self.db.delete_profile(*id).await?;
+
+                    info!("Deleted profile {id}");
+
+                    let mut events = self.update_profiles().await?;
+
+                    // The profile's settings are kept, there's just no longer a profile to attribute shots to.
+                    if self.active_profile.as_ref().is_some_and(|active| active.id == *id) {
+                        self.active_profile = None;
+
+                        events.push(Event::OutgoingMqttMessage(
+                            MqttOutgoingMessage::ActiveProfileUpdate(None),
+                        ));
+                    }
+
+                    Ok(events)
+                }
+                MqttIncomingMessage::ProfileListRequest => Ok(vec![Event::OutgoingMqttMessage(
+                    MqttOutgoingMessage::ProfilesUpdate(self.profiles.clone()),
+                )]),
+                MqttIncomingMessage::ProfileActivate(id) => self.activate_profile(*id).await,
                 MqttIncomingMessage::ConfigSet(config_item) => {
+                    // Targets are range checked, so they can't be written directly.
+                    if [
+                        DB_KEY_TARGET_TEMPERATURE,
+                        DB_KEY_STEAM_TARGET_TEMPERATURE,
+                        DB_KEY_STANDBY_TARGET_TEMPERATURE,
+                    ]
+                    .contains(&config_item.key.as_str())
+                    {
+                        return Err(anyhow!(
+                            "{} can only be set on gesha/temperature/target/{{mode}}/set",
+                            config_item.key
+                        ));
+                    }
+
                     self.db.write_config(&config_item).await?;
 
                     if config_item.key.starts_with("ui_") {
@@ -329,11 +705,25 @@ impl State {
                     if let Ok(extraction_temp_pred) =
                         self.model.predict_extraction_temperature(temp.grouphead_temp, temp.boiler_temp)
                     {
+                        if let Some(thermofilter_temp) = temp.thermofilter_temp {
+                            if timestamp - self.extraction_temp_residual_time
+                                >= EXTRACTION_TEMP_RESIDUAL_INTERVAL_MS
+                            {
+                                self.extraction_temp_interval
+                                    .push(thermofilter_temp, extraction_temp_pred);
+                                self.extraction_temp_residual_time = timestamp;
+                            }
+                        }
+
+                        let interval = self.extraction_temp_interval.interval(extraction_temp_pred);
+
                         change_events.push(Event::OutgoingMqttMessage(
-                            MqttOutgoingMessage::TemperatureUpdate(
+                            MqttOutgoingMessage::PredictedTemperatureUpdate(
                                 "thermofilter_predicted".to_string(),
-                                ValueChange {
+                                PredictionChange {
                                     value: extraction_temp_pred,
+                                    lower: interval.map(|(lower, _)| lower),
+                                    upper: interval.map(|(_, upper)| upper),
                                     timestamp,
                                 },
                             ),
@@ -343,11 +733,7 @@ impl State {
                     self.db
                         .write_measurement_queue(Measurement {
                             time: timestamp,
-                            target_temp_c: if self.mode == Mode::Steam {
-                                self.target_temperature_steam
-                            } else {
-                                self.target_temperature
-                            },
+                            target_temp_c: self.mode_target_temperature(),
                             boiler_temp_c: temp.boiler_temp,
                             grouphead_temp_c: temp.grouphead_temp,
                             thermofilter_temp_c: temp.thermofilter_temp,
@@ -360,18 +746,68 @@ impl State {
                 }
 
                 self.current_temperature = Some(temp.clone());
+                self.temperature_read_error = None;
+
+                if temp.boiler_temp >= MAX_BOILER_TEMP_C && self.mode != Mode::Idle {
+                    error!("The boiler is at {} °C, turning the machine off", temp.boiler_temp);
+
+                    change_events.extend(
+                        self.transition(Mode::Idle, TransitionTrigger::SafetyTripped, None)
+                            .await?,
+                    );
+                }
+
+                if let Some(thermal_model) = self.update_thermal_model(temp, timestamp).await? {
+                    change_events.push(Event::ThermalModelChanged(thermal_model));
+                }
+
+                change_events.extend(self.update_brew_curve(timestamp));
+                change_events.extend(self.detect_shot(temp, timestamp).await);
+                change_events.extend(self.update_eta(temp, timestamp).await);
+                change_events.extend(self.update_auto_off(timestamp).await?);
 
                 Ok(change_events)
             }
             Event::TemperatureReadError(message) => {
                 error!("Temperature read error: {message}");
+                self.temperature_read_error = Some(message.clone());
                 Ok(vec![])
             }
+            Event::ModeTransitionRequest { mode, trigger } => {
+                self.transition(mode.clone(), *trigger, None).await
+            }
+            Event::RelayCommandFailed(failure) => {
+                let mut events = vec![Event::OutgoingMqttMessage(
+                    MqttOutgoingMessage::RelayCommandFailedUpdate(failure.clone()),
+                )];
+
+                // Put the mode back in line with the relay's last confirmed state.
+                if failure.power_state && !self.power_state && self.mode != Mode::Idle {
+                    events.extend(
+                        self.transition(Mode::Idle, TransitionTrigger::RelayCommandFailed, None)
+                            .await?,
+                    );
+                } else if !failure.power_state && self.power_state && self.mode == Mode::Idle {
+                    events.extend(
+                        self.transition(Mode::Active, TransitionTrigger::RelayCommandFailed, None)
+                            .await?,
+                    );
+                }
+
+                Ok(events)
+            }
             Event::BoilerHeatLevelChanged(heat_level) => {
                 self.boiler_state = *heat_level;
 
                 Ok(vec![])
             }
+            Event::ControllerTelemetryChanged(sample) => {
+                if self.persist_controller_telemetry {
+                    self.db.write_controller_telemetry_queue(sample.clone()).await?;
+                }
+
+                Ok(vec![])
+            }
             _ => {
                 // All events except outgoing MQTT messages are handled by the state,
                 // but some events are used to notify the rest fo the system of state changes,
@@ -383,6 +819,314 @@ impl State {
         };
     }
 
+    async fn update_schedules(&mut self) -> Result<Vec<Event>> {
+        self.schedules = self.db.read_schedules().await?;
+
+        Ok(vec![
+            Event::SchedulesChanged(self.schedules.clone()),
+            Event::OutgoingMqttMessage(MqttOutgoingMessage::SchedulesUpdate(
+                self.schedules.clone(),
+            )),
+        ])
+    }
+
+    async fn update_profiles(&mut self) -> Result<Vec<Event>> {
+        self.profiles = self.db.read_profiles().await?;
+
+        Ok(vec![Event::OutgoingMqttMessage(
+            MqttOutgoingMessage::ProfilesUpdate(self.profiles.clone()),
+        )])
+    }
+
+    // The profile is validated and written to the DB before any of it is applied,
+    // so a profile is either applied in full or not at all.
+    async fn activate_profile(&mut self, id: i64) -> Result<Vec<Event>> {
+        let profile = self
+            .profiles
+            .iter()
+            .find(|profile| profile.id == id)
+            .cloned()
+            .ok_or_else(|| anyhow!("There is no profile with the ID {id}"))?;
+
+        let settings = &profile.settings;
+
+        settings.validate(&self.target_bounds)?;
+
+        let config_items = vec![
+            ConfigItem {
+                key: DB_KEY_TARGET_TEMPERATURE.to_string(),
+                value: serde_plain::to_string::<f32>(&settings.brew_target_temp)?,
+            },
+            ConfigItem {
+                key: DB_KEY_STEAM_TARGET_TEMPERATURE.to_string(),
+                value: serde_plain::to_string::<f32>(&settings.steam_target_temp)?,
+            },
+            ConfigItem {
+                key: DB_KEY_CONTROL_METHOD.to_string(),
+                value: serde_plain::to_string::<ControlMethod>(&settings.control_method)?,
+            },
+            ConfigItem {
+                key: DB_KEY_ACTIVE_PROFILE.to_string(),
+                value: serde_plain::to_string::<i64>(&id)?,
+            },
+        ];
+
+        self.db.write_configs(&config_items).await?;
+
+        self.target_temperature = settings.brew_target_temp;
+        self.target_temperature_steam = settings.steam_target_temp;
+        self.control_method = settings.control_method;
+        self.controller_parameters = settings.controller_parameters;
+        self.eta_tracker.set_require_preheat(settings.require_preheat);
+        self.active_profile = Some(profile.clone());
+
+        info!("Activated profile {id} \"{}\"", settings.name);
+
+        let mut events = vec![
+            Event::OutgoingMqttMessage(MqttOutgoingMessage::ModeTargetTemperatureUpdate(
+                TargetMode::Brew,
+                self.target_temperature,
+            )),
+            Event::OutgoingMqttMessage(MqttOutgoingMessage::ModeTargetTemperatureUpdate(
+                TargetMode::Steam,
+                self.target_temperature_steam,
+            )),
+        ];
+
+        events.extend(
+            config_items
+                .into_iter()
+                .map(|config_item| Event::OutgoingMqttMessage(MqttOutgoingMessage::ConfigUpdate(config_item))),
+        );
+
+        events.push(Event::ControllerParametersChanged(self.controller_parameters));
+
+        // Steam mode overrides the control method, the profile's is restored when leaving it.
+        if self.mode != Mode::Steam {
+            events.push(Event::ControlMethodChanged(self.control_method));
+        }
+
+        if self.target_mode() != TargetMode::Standby {
+            events.push(Event::TargetTemperatureChanged(self.mode_target_temperature()));
+        }
+
+        events.extend(vec![
+            Event::ProfileActivated(profile.clone()),
+            Event::OutgoingMqttMessage(MqttOutgoingMessage::ActiveProfileUpdate(Some(profile))),
+        ]);
+
+        Ok(events)
+    }
+
+    // Seeds the extraction temperature's prediction interval from the thermofilter measurements in the DB,
+    // so the interval is available without the thermofilter attached.
+    async fn seed_extraction_temp_interval(&mut self) -> Result<()> {
+        let measurements = self
+            .db
+            .read_thermofilter_measurements(
+                EXTRACTION_TEMP_RESIDUAL_INTERVAL_MS,
+                models::MAX_RESIDUALS as i64,
+                THERMOFILTER_SEED_ROWS,
+            )
+            .await?;
+
+        self.extraction_temp_interval.clear();
+
+        for measurement in measurements.iter() {
+            match self.model.predict_extraction_temperature(
+                measurement.grouphead_temp_c,
+                measurement.boiler_temp_c,
+            ) {
+                Ok(prediction) => self
+                    .extraction_temp_interval
+                    .push(measurement.thermofilter_temp_c, prediction),
+                Err(err) => error!("Failed to predict the extraction temperature for {}: {}", measurement.time, err),
+            }
+        }
+
+        info!(
+            "Seeded the extraction temperature prediction interval with {} measurements",
+            measurements.len()
+        );
+
+        Ok(())
+    }
+
+    // Fits the measurement to the boiler's thermal model,
+    // returns a new estimate at most once per interval.
+    async fn update_thermal_model(
+        &mut self,
+        temp: &TemperatureMeasurement,
+        timestamp: i64,
+    ) -> Result<Option<ThermalModel>> {
+        let Some(estimator) = self.thermal_model_estimator.as_mut() else {
+            return Ok(None);
+        };
+
+        // Water is drawn from the boiler when brewing or steaming, which the model doesn't account for.
+        let excluded = self.mode == Mode::Brew || self.mode == Mode::Steam;
+
+        if !estimator.push(temp.timestamp, temp.boiler_temp, self.boiler_state, excluded) {
+            return Ok(None);
+        }
+
+        let is_due = self.thermal_model.as_ref().map_or(true, |thermal_model| {
+            timestamp - thermal_model.time >= self.thermal_model_interval.as_millis() as i64
+        });
+
+        if !is_due {
+            return Ok(None);
+        }
+
+        let Some(thermal_model) = estimator.estimate(timestamp) else {
+            return Ok(None);
+        };
+
+        self.db.write_thermal_model(&thermal_model).await?;
+        self.thermal_model = Some(thermal_model.clone());
+
+        if let Some(shot_detector) = self.shot_detector.as_mut() {
+            shot_detector.set_thermal_model(thermal_model.clone());
+        }
+
+        Ok(Some(thermal_model))
+    }
+
+    async fn update_eta(&mut self, temp: &TemperatureMeasurement, timestamp: i64) -> Vec<Event> {
+        // In standby the ETA is for waking up to the brew temperature.
+        let target_temp = if self.mode == Mode::Standby {
+            self.target_temperature
+        } else {
+            self.mode_target_temperature()
+        };
+
+        let (eta, record) = self.eta_tracker.update(&EtaSample {
+            time: timestamp,
+            boiler_temp: temp.boiler_temp,
+            grouphead_temp: temp.grouphead_temp,
+            heat_level: self.boiler_state,
+            target_temp,
+            mode: if self.power_state { &self.mode } else { &Mode::Idle },
+            thermal_model: self.thermal_model.as_ref(),
+        });
+
+        if let Some(record) = record {
+            info!("Recorded ETA: {:?}", record);
+
+            if let Err(err) = self.db.write_eta_record(&record).await {
+                error!("Error writing ETA to DB: {}", err);
+            }
+        }
+
+        eta.map(|eta| vec![Event::OutgoingMqttMessage(MqttOutgoingMessage::EtaUpdate(eta))])
+            .unwrap_or_default()
+    }
+
+    // Looks for the temperature drop of a shot being pulled, and records any shots it finds.
+    // If auto brew is enabled the machine is put into brew mode for the duration of the shot.
+    async fn detect_shot(&mut self, temp: &TemperatureMeasurement, timestamp: i64) -> Vec<Event> {
+        let Some(shot_detector) = self.shot_detector.as_mut() else {
+            return vec![];
+        };
+
+        // Shots can't be pulled when the machine is off, and steaming has a similar temperature drop.
+        // Shots that were started manually are already being recorded.
+        let is_detecting = self.power_state
+            && matches!(
+                (&self.mode, &self.shot_state),
+                (Mode::Active, _) | (Mode::Brew, Shot::PullDetected(_))
+            );
+
+        if !is_detecting {
+            shot_detector.reset();
+            return vec![];
+        }
+
+        match shot_detector.push(
+            timestamp,
+            temp.boiler_temp,
+            temp.grouphead_temp,
+            self.boiler_state,
+        ) {
+            Some(ShotDetectorEvent::Started { start_time }) => {
+                info!("Detected a shot starting at {start_time}");
+
+                if !self.shot_detection_config.auto_brew {
+                    return vec![];
+                }
+
+                // The shot is already being recorded from the detected start time.
+                self.shot_state = Shot::PullDetected(start_time);
+
+                let events = self
+                    .transition(Mode::Brew, TransitionTrigger::ShotDetected, None)
+                    .await
+                    .unwrap_or_else(|err| {
+                        error!("Error moving to brew mode for a detected shot: {}", err);
+                        vec![]
+                    });
+
+                if self.mode != Mode::Brew {
+                    self.shot_state = Shot::NotPulling;
+                }
+
+                events
+            }
+            Some(ShotDetectorEvent::Ended {
+                start_time,
+                end_time,
+                confidence,
+            }) => {
+                let is_confident = confidence >= self.shot_detection_config.min_confidence;
+
+                if !is_confident {
+                    info!("Discarded a detected shot from {start_time} to {end_time}, confidence {confidence}");
+                }
+
+                if let Shot::PullDetected(_) = self.shot_state {
+                    // The shot is written with its confidence when brew mode is left, unless it's discarded.
+                    if is_confident {
+                        self.detected_shot_end = Some((end_time, confidence));
+                    } else {
+                        self.shot_state = Shot::NotPulling;
+                    }
+
+                    return self
+                        .transition(Mode::Active, TransitionTrigger::ShotDetectionEnded, None)
+                        .await
+                        .unwrap_or_else(|err| {
+                            error!("Error leaving brew mode for a detected shot: {}", err);
+                            vec![]
+                        });
+                }
+
+                if !is_confident {
+                    return vec![];
+                }
+
+                if let Err(err) = self.record_activity() {
+                    error!("Error resetting the auto off timer: {}", err);
+                }
+
+                match self
+                    .db
+                    .write_shot(start_time, end_time, Some(confidence), self.active_profile_id())
+                    .await
+                {
+                    Ok(_) => {
+                        info!("Detected shot written to DB, confidence {confidence}");
+                    }
+                    Err(err) => {
+                        error!("Error writing detected shot to DB: {}", err);
+                    }
+                }
+
+                vec![]
+            }
+            None => vec![],
+        }
+    }
+
     fn add_steam_mode_events(&self, steam_mode_enabled: bool, events: &mut Vec<Event>) {
         if steam_mode_enabled {
             // Manually override the control method and target temperature when moving to steam mode
@@ -393,13 +1137,13 @@ impl State {
         } else {
             // Reset the control method and target temperature
             events.extend(vec![
-                Event::ControlMethodChanged(self.control_method.clone()),
+                Event::ControlMethodChanged(self.control_method),
                 Event::TargetTemperatureChanged(self.target_temperature),
             ]);
         }
     }
 
-    // This adds the event that triggers the external Shelly relay -
+    // This adds the event that triggers the power relay -
     // if the relay switches state and successfully responds then
     // we'll get an IncomingMqttMessage::ExternRelayPowerStateChanged as a callback.
     fn add_power_mode_events(&mut self, power_state: IsPowerOn, events: &mut Vec<Event>) {
@@ -408,30 +1152,291 @@ impl State {
         ))
     }
 
-    async fn toggle_brew_mode(&mut self) -> Result<()> {
+    // Moves to a new mode if the transition table allows it and its guards hold, running the transition's actions.
+    // The outcome is published on gesha/mode/transition with the requester's client id,
+    // so a rejected request is reported back to the requester.
+    async fn transition(
+        &mut self,
+        to: Mode,
+        trigger: TransitionTrigger,
+        requester: Option<String>,
+    ) -> Result<Vec<Event>> {
+        let from = self.mode.clone();
+
+        if from == to {
+            return Ok(vec![]);
+        }
+
+        let mut transition = ModeTransition {
+            timestamp: util::get_unix_timestamp(SystemTime::now())?,
+            from: from.clone(),
+            to: to.clone(),
+            trigger,
+            requester,
+            accepted: false,
+            reason: None,
+            guard: None,
+        };
+
+        let Some(definition) = find_transition(&from, &to) else {
+            transition.reason = Some(format!("Moving from mode {:?} to {:?} is not supported", from, to));
+            info!("Rejected mode transition: {:?}", transition);
+
+            return Ok(vec![Event::OutgoingMqttMessage(
+                MqttOutgoingMessage::ModeTransitionUpdate(transition),
+            )]);
+        };
+
+        if let Some(guard) = definition.guards.iter().find(|guard| !self.is_guard_satisfied(guard)) {
+            transition.reason = Some(guard.reason().to_string());
+            transition.guard = Some(*guard);
+            info!("Rejected mode transition: {:?}", transition);
+
+            return Ok(vec![Event::OutgoingMqttMessage(
+                MqttOutgoingMessage::ModeTransitionUpdate(transition),
+            )]);
+        }
+
+        // The mode only changes once every action has run, so a failing action leaves the machine where it was.
+        let mut events = vec![Event::ModeChanged(to.clone())];
+
+        for action in definition.actions {
+            match action {
+                Action::PowerOn if !trigger.is_relay() => self.add_power_mode_events(true, &mut events),
+                Action::PowerOff if !trigger.is_relay() => self.add_power_mode_events(false, &mut events),
+                Action::PowerOn | Action::PowerOff => {}
+                Action::StartShot => {
+                    let start_time = self.start_shot()?;
+
+                    events.push(Event::ShotStarted(start_time));
+                    events.extend(self.start_brew_curve(start_time)?);
+                }
+                Action::EndShot => {
+                    events.extend(self.end_shot(&to).await?);
+                    events.push(Event::ShotEnded);
+                }
+                Action::EnterSteam => self.add_steam_mode_events(true, &mut events),
+                Action::ExitSteam => self.add_steam_mode_events(false, &mut events),
+                Action::EnterStandby => events.push(Event::TargetTemperatureChanged(
+                    self.target_temperature_standby,
+                )),
+                Action::ExitStandby => {
+                    events.push(Event::TargetTemperatureChanged(self.target_temperature))
+                }
+            }
+        }
+
+        // However the shot ended, e.g. manually or at the maximum duration, it's been recorded,
+        // so the detector starts over rather than recording the same drop again.
+        if from == Mode::Brew {
+            if let Some(shot_detector) = self.shot_detector.as_mut() {
+                shot_detector.reset();
+            }
+        }
+
+        self.record_activity()?;
+
+        self.mode = to;
+        transition.accepted = true;
+        events.push(Event::OutgoingMqttMessage(
+            MqttOutgoingMessage::ModeTransitionUpdate(transition),
+        ));
+
+        Ok(events)
+    }
+
+    fn record_activity(&mut self) -> Result<()> {
+        if let Some(auto_off_timer) = self.auto_off_timer.as_mut() {
+            auto_off_timer.reset(util::get_unix_timestamp(SystemTime::now())?);
+        }
+
+        Ok(())
+    }
+
+    // Warns before turning the machine off after the auto off timeout, and then turns it off.
+    async fn update_auto_off(&mut self, timestamp: i64) -> Result<Vec<Event>> {
+        let is_running = matches!(self.mode, Mode::Active | Mode::Steam);
+
+        let Some(auto_off_event) = self
+            .auto_off_timer
+            .as_mut()
+            .and_then(|auto_off_timer| auto_off_timer.update(timestamp, is_running))
+        else {
+            return Ok(vec![]);
+        };
+
+        match auto_off_event {
+            AutoOffEvent::Warning(warning) => {
+                info!("The machine will be turned off at {} unless it's used", warning.off_at);
+
+                Ok(vec![Event::OutgoingMqttMessage(
+                    MqttOutgoingMessage::AutoOffWarningUpdate(Some(warning)),
+                )])
+            }
+            AutoOffEvent::Cleared => Ok(vec![Event::OutgoingMqttMessage(
+                MqttOutgoingMessage::AutoOffWarningUpdate(None),
+            )]),
+            AutoOffEvent::TimedOut => {
+                info!("Turning the machine off after the auto off timeout");
+
+                let mut events = self.transition(Mode::Idle, TransitionTrigger::Inactivity, None).await?;
+                events.push(Event::OutgoingMqttMessage(
+                    MqttOutgoingMessage::AutoOffWarningUpdate(None),
+                ));
+
+                Ok(events)
+            }
+        }
+    }
+
+    fn is_guard_satisfied(&self, guard: &Guard) -> bool {
+        match guard {
+            Guard::RelayAvailable => self.power_relay_available,
+            // There are no measurements before the poller's first reading, or without thermocouples.
+            Guard::SensorsHealthy => {
+                self.temperature_read_error.is_none()
+                    && self.current_temperature.as_ref().map_or(true, |temp| {
+                        temp.timestamp.elapsed().unwrap_or_default() < SENSOR_TIMEOUT
+                    })
+            }
+            Guard::SafetyNotTripped => self
+                .current_temperature
+                .as_ref()
+                .map_or(true, |temp| temp.boiler_temp < MAX_BOILER_TEMP_C),
+        }
+    }
+
+    // Returns the shot's start time.
+    fn start_shot(&mut self) -> Result<i64> {
+        // There is no behavioural change when moving from active to brew,
+        // we just need to keep track of when the pull started.
+        // A detected shot has already been given its start time.
         match self.shot_state {
             Shot::NotPulling => {
-                // There is no behavioural change when moving from active to brew,
-                // we just need to keep track of when the pull started.
-                self.shot_state = Shot::PullStarted(util::get_unix_timestamp(SystemTime::now())?);
+                let start_time = util::get_unix_timestamp(SystemTime::now())?;
+                self.shot_state = Shot::PullStarted(start_time);
+
+                Ok(start_time)
             }
-            Shot::PullStarted(start_time) => {
-                self.shot_state = Shot::NotPulling;
+            Shot::PullStarted(start_time) | Shot::PullDetected(start_time) => Ok(start_time),
+        }
+    }
 
-                let end_time = util::get_unix_timestamp(SystemTime::now())?;
+    fn start_brew_curve(&mut self, start_time: i64) -> Result<Vec<Event>> {
+        let Some(brew_curve) = self
+            .active_profile
+            .as_ref()
+            .and_then(|profile| profile.settings.brew_curve.clone())
+        else {
+            return Ok(vec![]);
+        };
 
-                match self.db.write_shot(start_time, end_time).await {
-                    Ok(_) => {
-                        info!("Shot written to DB");
-                    }
-                    Err(err) => {
-                        error!("Error writing shot to DB: {}", err);
-                    }
-                }
+        let now = util::get_unix_timestamp(SystemTime::now())?;
+        let mut brew_curve_run = BrewCurveRun::new(brew_curve, start_time);
+
+        // The mode isn't brew until the transition's actions have run, so this doesn't go through update_brew_curve.
+        let events = brew_curve_run
+            .update(now)
+            .map(|setpoint| vec![Event::TargetTemperatureChanged(setpoint)])
+            .unwrap_or_default();
+
+        self.brew_curve_run = Some(brew_curve_run);
+
+        Ok(events)
+    }
+
+    fn update_brew_curve(&mut self, time: i64) -> Vec<Event> {
+        if self.mode != Mode::Brew {
+            return vec![];
+        }
+
+        self.brew_curve_run
+            .as_mut()
+            .and_then(|run| run.update(time))
+            .map(|setpoint| vec![Event::TargetTemperatureChanged(setpoint)])
+            .unwrap_or_default()
+    }
+
+    // Returns the events that restore the target of the mode being moved to if the shot followed a brew curve.
+    async fn end_shot(&mut self, to: &Mode) -> Result<Vec<Event>> {
+        let brew_curve_run = self.brew_curve_run.take();
+
+        let events = match brew_curve_run {
+            Some(_) => vec![Event::TargetTemperatureChanged(
+                self.target_temperature_for(TargetMode::from(to)),
+            )],
+            None => vec![],
+        };
+
+        let now = util::get_unix_timestamp(SystemTime::now())?;
+
+        // A detected shot keeps the end time and confidence the detector gave it,
+        // or is scored up to now if it was ended before the detector noticed.
+        let (start_time, end_time, confidence) = match self.shot_state {
+            Shot::PullStarted(start_time) => (start_time, now, None),
+            Shot::PullDetected(start_time) => match self.detected_shot_end.take() {
+                Some((end_time, confidence)) => (start_time, end_time, Some(confidence)),
+                None => (
+                    start_time,
+                    now,
+                    self.shot_detector
+                        .as_mut()
+                        .and_then(|shot_detector| shot_detector.end(now))
+                        .or(Some(0.0)),
+                ),
+            },
+            Shot::NotPulling => return Ok(events),
+        };
+
+        self.shot_state = Shot::NotPulling;
+
+        match self
+            .db
+            .write_shot(start_time, end_time, confidence, self.active_profile_id())
+            .await
+        {
+            Ok(_) => {
+                info!("Shot written to DB");
+            }
+            Err(err) => {
+                error!("Error writing shot to DB: {}", err);
+                return Ok(events);
             }
         }
 
-        Ok(())
+        // The setpoints are stored against the shot, so they're only written if it was.
+        if let Some(brew_curve_run) = brew_curve_run {
+            let setpoints = brew_curve_run.into_trace();
+
+            if let Err(err) = self.db.write_shot_setpoints(start_time, setpoints).await {
+                error!("Error writing shot setpoints to DB: {}", err);
+            }
+        }
+
+        Ok(events)
+    }
+
+    // Writes the event to the event table, if it's one that's logged.
+    pub async fn record_event(&self, event: &Event) -> Result<()> {
+        let Some((source, kind, payload)) = event_log::describe(event)? else {
+            return Ok(());
+        };
+
+        self.db
+            .write_event_log_entry(util::get_unix_timestamp(SystemTime::now())?, &source, &kind, &payload)
+            .await
+    }
+
+    pub async fn record_error(&self, event: &Event, err: &anyhow::Error) -> Result<()> {
+        let (source, kind, payload) = event_log::describe_error(event, err)?;
+
+        self.db
+            .write_event_log_entry(util::get_unix_timestamp(SystemTime::now())?, &source, &kind, &payload)
+            .await
+    }
+
+    fn active_profile_id(&self) -> Option<i64> {
+        self.active_profile.as_ref().map(|profile| profile.id)
     }
 
     pub async fn stop(&mut self) -> Result<()> {
@@ -440,6 +1445,24 @@ impl State {
     }
 }
 
+// A stored target that's no longer in range, e.g. after the bounds were changed in the config,
+// is clamped rather than heating the boiler to it.
+fn read_target_temperature(value: Option<&String>, default: f32, bounds: &TargetBounds) -> f32 {
+    let target: f32 = value
+        .map(|s| serde_plain::from_str(s).unwrap())
+        .unwrap_or(default);
+
+    if bounds.contains(target) {
+        return target;
+    }
+
+    let clamped = if target.is_nan() { default } else { target }.clamp(bounds.min, bounds.max);
+
+    warn!("The stored target temperature {target} is out of range, using {clamped}");
+
+    clamped
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub enum Mode {
@@ -447,9 +1470,30 @@ pub enum Mode {
     Active,
     Brew,
     Steam,
+    // The boiler is held at a lower temperature between uses.
+    Standby,
     Offline,
 }
 
+// The modes that have their own target temperature, the others use the brew target.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum TargetMode {
+    Brew,
+    Steam,
+    Standby,
+}
+
+impl From<&Mode> for TargetMode {
+    fn from(mode: &Mode) -> Self {
+        match mode {
+            Mode::Steam => TargetMode::Steam,
+            Mode::Standby => TargetMode::Standby,
+            _ => TargetMode::Brew,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TemperatureMeasurement {
     pub boiler_temp: f32,
@@ -460,18 +1504,40 @@ pub struct TemperatureMeasurement {
 
 pub type IsPowerOn = bool;
 
-#[derive(Clone, Debug)]
+// Serialized for the event log, as {"kind": ..., "payload": ...}.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", content = "payload", rename_all = "camelCase")]
 pub enum Event {
     TemperatureChanged(TemperatureMeasurement),
     TemperatureReadError(String),
     ModeChanged(Mode),
+    // Asks the state to change mode, the outcome is published on gesha/mode/transition.
+    ModeTransitionRequest {
+        mode: Mode,
+        trigger: TransitionTrigger,
+    },
     PowerStateChanged(IsPowerOn),
+    // The relay didn't confirm a power state command, see RelayCommandTracker.
+    RelayCommandFailed(RelayCommandFailure),
     ControlMethodChanged(ControlMethod),
+    ControllerParametersChanged(ControllerParameters),
     ManualBoilerHeatLevelRequest(f32),
     TargetTemperatureChanged(f32),
 
     BoilerHeatLevelChanged(f32),
+    ControllerTelemetryChanged(ControllerTelemetrySample),
+    ControllerDegradationChanged(Option<ControllerDegradation>),
+    ModelsChanged(#[serde(skip)] Arc<models::PredictiveModels>),
+    // The start time of a shot, when brew mode is entered or a shot is detected.
+    ShotStarted(i64),
+    ShotEnded,
+    ThermalModelChanged(ThermalModel),
+    SchedulesChanged(Vec<Schedule>),
+    ProfileActivated(Profile),
+    // Sent after handling an event that changed the state, see StatePublisher.
+    StateSnapshotChanged(StateSnapshot),
 
-    IncomingMqttMessage(MqttIncomingMessage),
+    // The client ID the message was sent with, see USER_PROPERTY_CLIENT_ID.
+    IncomingMqttMessage(MqttIncomingMessage, Option<String>),
     OutgoingMqttMessage(MqttOutgoingMessage),
 }
@@ -0,0 +1,380 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::config::ThermalModelConfig;
+
+// The boiler is modelled as dT/dt = heater_gain * u(t - lag) - loss_coefficient * (T - ambient_temp),
+// where u is the heat level (0.0 - 1.0) and T is the boiler temperature.
+//
+// The thermocouples have a resolution of 0.25 °C, which makes the temperature change between 100ms
+// samples meaningless, so measurements are averaged into windows before they're fitted.
+// See models/thermal_loss for the offline analysis.
+const WINDOW: Duration = Duration::from_secs(10);
+
+// Each candidate lag is fitted separately, the one with the smallest prediction error wins.
+// models/thermal_lag found the boiler takes around 30 seconds to respond to the heating element.
+const LAG_CANDIDATES: usize = 7;
+
+// Windows further apart than this are not contiguous, e.g. the machine was turned off.
+const MAX_WINDOW_GAP: Duration = Duration::from_secs(30);
+
+// The number of windows that need to be fitted before an estimate is made.
+const MIN_SAMPLES: i64 = 30;
+
+// The covariance is only inflated by the forgetting factor while it's below this trace,
+// otherwise it winds up while the boiler sits at a steady temperature and the estimates become erratic.
+const MAX_COVARIANCE_TRACE: f64 = 1e4;
+
+const INITIAL_COVARIANCE: f64 = 1e3;
+const SEEDED_COVARIANCE: f64 = 1.0;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct ThermalModel {
+    pub time: i64,
+    // The rate of temperature increase at full heat, in °C/s.
+    pub heater_gain: f32,
+    // The proportion of the difference to ambient temperature lost per second.
+    pub loss_coefficient: f32,
+    pub ambient_temp: f32,
+    // How long after heat is applied the boiler temperature responds, in seconds.
+    pub lag_s: f32,
+    // The mean squared error of the fitted temperature change, in (°C/s)^2.
+    pub error_variance: f32,
+    pub samples: i64,
+}
+
+impl ThermalModel {
+    // The predicted boiler temperature after `duration` at a constant heat level,
+    // ignoring the lag, which has already elapsed if the heat level has been constant.
+    pub fn predict_boiler_temp(&self, boiler_temp: f32, heat_level: f32, duration: Duration) -> f32 {
+        if self.loss_coefficient <= 0.0 {
+            return boiler_temp + self.heater_gain * heat_level * duration.as_secs_f32();
+        }
+
+        let steady_state_temp =
+            self.ambient_temp + self.heater_gain * heat_level / self.loss_coefficient;
+
+        steady_state_temp
+            + (boiler_temp - steady_state_temp)
+                * (-self.loss_coefficient * duration.as_secs_f32()).exp()
+    }
+}
+
+// Recursive least squares fit of dT/dt = θ0 * u - θ1 * T + θ2, for a single lag.
+// θ2 is loss_coefficient * ambient_temp, which keeps the problem linear.
+struct Rls {
+    theta: [f64; 3],
+    covariance: [[f64; 3]; 3],
+    // An exponentially weighted mean of the squared prediction errors.
+    error_variance: f64,
+}
+
+impl Rls {
+    fn new(seed: Option<&ThermalModel>, error_variance: f64) -> Self {
+        let (theta, covariance) = match seed {
+            Some(model) => (
+                [
+                    model.heater_gain as f64,
+                    model.loss_coefficient as f64,
+                    (model.loss_coefficient * model.ambient_temp) as f64,
+                ],
+                SEEDED_COVARIANCE,
+            ),
+            None => ([0.0; 3], INITIAL_COVARIANCE),
+        };
+
+        Rls {
+            theta,
+            covariance: [
+                [covariance, 0.0, 0.0],
+                [0.0, covariance, 0.0],
+                [0.0, 0.0, covariance],
+            ],
+            error_variance,
+        }
+    }
+
+    fn update(&mut self, phi: [f64; 3], y: f64, forgetting_factor: f64) {
+        let p_phi: [f64; 3] = [0, 1, 2].map(|i| (0..3).map(|j| self.covariance[i][j] * phi[j]).sum());
+        let denominator = forgetting_factor + (0..3).map(|i| phi[i] * p_phi[i]).sum::<f64>();
+        let gain = p_phi.map(|value| value / denominator);
+
+        let error = y - (0..3).map(|i| phi[i] * self.theta[i]).sum::<f64>();
+
+        for (theta, gain) in self.theta.iter_mut().zip(gain) {
+            *theta += gain * error;
+        }
+
+        let trace: f64 = (0..3).map(|i| self.covariance[i][i]).sum();
+        let inflation = if trace < MAX_COVARIANCE_TRACE {
+            forgetting_factor
+        } else {
+            1.0
+        };
+
+        // P = (P - K φᵀ P) / λ, P is symmetric so φᵀ P = (P φ)ᵀ
+        for (row, gain) in self.covariance.iter_mut().zip(gain) {
+            for (value, p_phi) in row.iter_mut().zip(p_phi) {
+                *value = (*value - gain * p_phi) / inflation;
+            }
+        }
+
+        self.error_variance =
+            forgetting_factor * self.error_variance + (1.0 - forgetting_factor) * error * error;
+    }
+}
+
+struct Window {
+    start: SystemTime,
+    boiler_temp_sum: f64,
+    heat_level_sum: f64,
+    count: u32,
+}
+
+impl Window {
+    fn new(start: SystemTime) -> Self {
+        Window {
+            start,
+            boiler_temp_sum: 0.0,
+            heat_level_sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+pub struct ThermalModelEstimator {
+    forgetting_factor: f64,
+    window: Option<Window>,
+    // The mean boiler temperature of the previous window, if it was contiguous with the current one.
+    previous_boiler_temp: Option<f64>,
+    // The mean heat level of recent windows, most recent last.
+    heat_levels: VecDeque<f64>,
+    candidates: Vec<Rls>,
+    samples: i64,
+}
+
+impl ThermalModelEstimator {
+    pub fn new(config: &ThermalModelConfig, seed: Option<&ThermalModel>) -> Self {
+        ThermalModelEstimator {
+            forgetting_factor: config.forgetting_factor,
+            window: None,
+            previous_boiler_temp: None,
+            heat_levels: VecDeque::with_capacity(LAG_CANDIDATES + 1),
+            candidates: (0..LAG_CANDIDATES)
+                .map(|lag| match seed {
+                    // The other lags start with a worse error, so the persisted lag is kept
+                    // until one of them fits noticeably better.
+                    Some(model) if lag_s(lag) == model.lag_s => {
+                        Rls::new(seed, model.error_variance as f64)
+                    }
+                    Some(model) => Rls::new(seed, 2.0 * model.error_variance as f64),
+                    None => Rls::new(None, 0.0),
+                })
+                .collect(),
+            samples: seed.map_or(0, |model| model.samples),
+        }
+    }
+
+    // Adds a measurement, returns true if a window was fitted.
+    // Measurements where water is drawn from the boiler (e.g. brewing or steaming) should be excluded,
+    // since the model doesn't account for the cold water coming in.
+    pub fn push(
+        &mut self,
+        timestamp: SystemTime,
+        boiler_temp: f32,
+        heat_level: f32,
+        excluded: bool,
+    ) -> bool {
+        let mut fitted = false;
+
+        if let Some(window) = self.window.as_ref() {
+            let elapsed = timestamp.duration_since(window.start).unwrap_or_default();
+
+            if elapsed >= MAX_WINDOW_GAP {
+                self.reset();
+                self.window = None;
+            } else if elapsed >= WINDOW {
+                fitted = self.close_window(elapsed);
+                self.window = None;
+            }
+        }
+
+        if excluded {
+            self.reset();
+            self.window = None;
+            return fitted;
+        }
+
+        let window = self.window.get_or_insert_with(|| Window::new(timestamp));
+
+        window.boiler_temp_sum += boiler_temp as f64;
+        window.heat_level_sum += heat_level as f64;
+        window.count += 1;
+
+        fitted
+    }
+
+    fn close_window(&mut self, elapsed: Duration) -> bool {
+        let Some(window) = self.window.as_ref() else {
+            return false;
+        };
+
+        let boiler_temp = window.boiler_temp_sum / window.count as f64;
+
+        if self.heat_levels.len() == LAG_CANDIDATES {
+            self.heat_levels.pop_front();
+        }
+
+        self.heat_levels
+            .push_back(window.heat_level_sum / window.count as f64);
+
+        let mut fitted = false;
+
+        if let Some(previous_boiler_temp) = self.previous_boiler_temp {
+            if self.heat_levels.len() == LAG_CANDIDATES {
+                let y = (boiler_temp - previous_boiler_temp) / elapsed.as_secs_f64();
+                let mean_boiler_temp = (boiler_temp + previous_boiler_temp) / 2.0;
+
+                for (lag, candidate) in self.candidates.iter_mut().enumerate() {
+                    // The most recent heat level is this window's, i.e. no lag.
+                    let u = self.heat_levels[LAG_CANDIDATES - 1 - lag];
+
+                    candidate.update([u, -mean_boiler_temp, 1.0], y, self.forgetting_factor);
+                }
+
+                self.samples += 1;
+                fitted = true;
+            }
+        }
+
+        self.previous_boiler_temp = Some(boiler_temp);
+
+        fitted
+    }
+
+    // Starts a new run of contiguous windows, the fitted parameters are kept.
+    fn reset(&mut self) {
+        self.previous_boiler_temp = None;
+        self.heat_levels.clear();
+    }
+
+    pub fn estimate(&self, time: i64) -> Option<ThermalModel> {
+        if self.samples < MIN_SAMPLES {
+            return None;
+        }
+
+        let (lag, candidate) = self
+            .candidates
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.error_variance.total_cmp(&b.error_variance))?;
+
+        let [heater_gain, loss_coefficient, loss_ambient] = candidate.theta;
+
+        // The ambient temperature can't be separated from the loss if the loss is negligible.
+        let ambient_temp = if loss_coefficient.abs() > f64::EPSILON {
+            loss_ambient / loss_coefficient
+        } else {
+            0.0
+        };
+
+        Some(ThermalModel {
+            time,
+            heater_gain: heater_gain as f32,
+            loss_coefficient: loss_coefficient as f32,
+            ambient_temp: ambient_temp as f32,
+            lag_s: lag_s(lag),
+            error_variance: candidate.error_variance as f32,
+            samples: self.samples,
+        })
+    }
+}
+
+fn lag_s(lag: usize) -> f32 {
+    (lag as u64 * WINDOW.as_secs()) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEATER_GAIN: f64 = 0.4;
+    const LOSS_COEFFICIENT: f64 = 0.002;
+    const AMBIENT_TEMP: f64 = 20.0;
+
+    // Heat levels that change often enough to excite the model, but repeat so the test is deterministic.
+    fn heat_level(step: usize) -> f64 {
+        [0.0, 1.0, 0.3, 0.7, 0.0, 0.5, 1.0, 0.2][(step / 3) % 8]
+    }
+
+    #[test]
+    fn rls_recovers_first_order_coefficients() {
+        let mut rls = Rls::new(None, 0.0);
+        let mut boiler_temp = AMBIENT_TEMP;
+
+        for step in 0..200 {
+            let u = heat_level(step);
+            let dt_dt = HEATER_GAIN * u - LOSS_COEFFICIENT * (boiler_temp - AMBIENT_TEMP);
+
+            rls.update([u, -boiler_temp, 1.0], dt_dt, 1.0);
+
+            boiler_temp += dt_dt * 10.0;
+        }
+
+        let [heater_gain, loss_coefficient, loss_ambient] = rls.theta;
+
+        assert!((heater_gain - HEATER_GAIN).abs() < 1e-4, "{heater_gain}");
+        assert!((loss_coefficient - LOSS_COEFFICIENT).abs() < 1e-5, "{loss_coefficient}");
+        assert!((loss_ambient / loss_coefficient - AMBIENT_TEMP).abs() < 0.1, "{loss_ambient}");
+        assert!(rls.error_variance < 1e-8);
+    }
+
+    #[test]
+    fn estimator_recovers_the_model_and_lag_from_measurements() {
+        let mut estimator = ThermalModelEstimator::new(&ThermalModelConfig::default(), None);
+        let sample_interval = Duration::from_millis(100);
+        let mut boiler_temp = 60.0;
+
+        // The difference between the mean temperatures of two windows is mostly down to the heat
+        // applied around their boundary, so a 35 second lag lines up with the window 40 seconds back.
+        let mut heat_levels = VecDeque::from(vec![0.0; 350]);
+
+        for sample in 0..60_000 {
+            // The heat level changes every minute, like a slowly varying controller.
+            let heat_level = heat_level(sample / 200);
+            heat_levels.push_back(heat_level);
+            let lagged_heat_level = heat_levels.pop_front().unwrap();
+
+            let timestamp = SystemTime::UNIX_EPOCH + sample_interval * sample as u32;
+            estimator.push(timestamp, boiler_temp as f32, heat_level as f32, false);
+
+            boiler_temp += (HEATER_GAIN * lagged_heat_level
+                - LOSS_COEFFICIENT * (boiler_temp - AMBIENT_TEMP))
+                * sample_interval.as_secs_f64();
+        }
+
+        let model = estimator.estimate(0).unwrap();
+
+        assert_eq!(model.lag_s, 40.0, "{model:?}");
+        assert!((model.heater_gain as f64 - HEATER_GAIN).abs() < 0.04, "{model:?}");
+        assert!((model.loss_coefficient as f64 - LOSS_COEFFICIENT).abs() < 5e-4, "{model:?}");
+        assert!((model.ambient_temp as f64 - AMBIENT_TEMP).abs() < 10.0, "{model:?}");
+    }
+
+    #[test]
+    fn no_estimate_until_enough_windows_are_fitted() {
+        let mut estimator = ThermalModelEstimator::new(&ThermalModelConfig::default(), None);
+
+        for second in 0..100 {
+            let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(second);
+            estimator.push(timestamp, 90.0, 0.5, false);
+        }
+
+        assert_eq!(estimator.estimate(0), None);
+    }
+}
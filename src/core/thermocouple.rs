@@ -173,11 +173,19 @@ impl ThermocouplePoller {
 
                             temperature_samples.clear();
                         } else {
-                            let boiler_temp = boiler.read().unwrap_or(0.0);
-                            let grouphead_temp = grouphead.read().unwrap_or(0.0);
+                            // The thermofilter is only attached for calibration, so it's not an error if it can't be read.
                             let thermofilter_temp = thermofilter.as_mut().map(|thermofilter| thermofilter.read().unwrap_or(0.0));
 
-                            temperature_samples.push((boiler_temp, grouphead_temp, thermofilter_temp));
+                            match (boiler.read(), grouphead.read()) {
+                                (Ok(boiler_temp), Ok(grouphead_temp)) => {
+                                    temperature_samples.push((boiler_temp, grouphead_temp, thermofilter_temp));
+                                }
+                                (Err(err), _) | (_, Err(err)) => {
+                                    if let Err(err) = poller_tx.send(StateEvent::TemperatureReadError(err.to_string())) {
+                                        error!("Error sending temperature read error event: {}", err);
+                                    }
+                                }
+                            }
                         }
                     }
                 }
@@ -195,6 +203,7 @@ impl ThermocouplePoller {
             Mode::Active => 100,
             Mode::Brew => 100,
             Mode::Steam => 100,
+            Mode::Standby => 100,
             Mode::Offline => 0,
         })
     }
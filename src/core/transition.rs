@@ -0,0 +1,417 @@
+use serde::{Deserialize, Serialize};
+
+use super::state::Mode;
+
+// What caused a mode transition to be requested.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum TransitionTrigger {
+    // Requested on gesha/mode/set, e.g. from the UI.
+    User,
+    // The relay was switched on or off outside of gesha, e.g. with its own button.
+    RelayPowerOn,
+    RelayPowerOff,
+    RelayUnavailable,
+    // The relay didn't confirm a command, so the mode is rolled back.
+    RelayCommandFailed,
+    ShotDetected,
+    ShotDetectionEnded,
+    Schedule,
+    // Nothing happened for the auto off timeout.
+    Inactivity,
+    // The shot reached the maximum duration.
+    MaxShotDuration,
+    // A hint on gesha/wake that the machine is about to be used, e.g. from an alarm clock.
+    Wake,
+    // The boiler reached the maximum temperature.
+    SafetyTripped,
+}
+
+impl TransitionTrigger {
+    // The relay has already switched when it triggers a transition, so it doesn't need to be told to.
+    pub fn is_relay(&self) -> bool {
+        matches!(
+            self,
+            TransitionTrigger::RelayPowerOn
+                | TransitionTrigger::RelayPowerOff
+                | TransitionTrigger::RelayUnavailable
+                | TransitionTrigger::RelayCommandFailed
+        )
+    }
+}
+
+// A condition that has to hold for a transition to be allowed.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Guard {
+    RelayAvailable,
+    SensorsHealthy,
+    SafetyNotTripped,
+}
+
+impl Guard {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Guard::RelayAvailable => "The power relay is unavailable",
+            Guard::SensorsHealthy => "The temperature sensors are not reporting",
+            Guard::SafetyNotTripped => "The boiler is above the maximum temperature",
+        }
+    }
+}
+
+// A side effect of a transition, run in order once the mode has changed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    PowerOn,
+    PowerOff,
+    StartShot,
+    EndShot,
+    EnterSteam,
+    ExitSteam,
+    EnterStandby,
+    ExitStandby,
+}
+
+pub struct Transition {
+    pub from: Mode,
+    pub to: Mode,
+    pub guards: &'static [Guard],
+    pub actions: &'static [Action],
+}
+
+// Heating the boiler needs working sensors and a boiler below the maximum temperature,
+// turning the machine on additionally needs the relay.
+const HEAT: &[Guard] = &[Guard::SensorsHealthy, Guard::SafetyNotTripped];
+const POWER_ON_AND_HEAT: &[Guard] = &[
+    Guard::RelayAvailable,
+    Guard::SensorsHealthy,
+    Guard::SafetyNotTripped,
+];
+
+// Every supported transition, anything else (including to and from Offline) is rejected.
+// Turning the machine off is never guarded.
+pub const TRANSITIONS: &[Transition] = &[
+    Transition {
+        from: Mode::Idle,
+        to: Mode::Active,
+        guards: POWER_ON_AND_HEAT,
+        actions: &[Action::PowerOn],
+    },
+    Transition {
+        from: Mode::Idle,
+        to: Mode::Steam,
+        guards: POWER_ON_AND_HEAT,
+        actions: &[Action::PowerOn, Action::EnterSteam],
+    },
+    Transition {
+        from: Mode::Idle,
+        to: Mode::Standby,
+        guards: POWER_ON_AND_HEAT,
+        actions: &[Action::PowerOn, Action::EnterStandby],
+    },
+    Transition {
+        from: Mode::Active,
+        to: Mode::Idle,
+        guards: &[],
+        actions: &[Action::PowerOff],
+    },
+    Transition {
+        from: Mode::Active,
+        to: Mode::Brew,
+        guards: HEAT,
+        actions: &[Action::StartShot],
+    },
+    Transition {
+        from: Mode::Active,
+        to: Mode::Steam,
+        guards: HEAT,
+        actions: &[Action::EnterSteam],
+    },
+    Transition {
+        from: Mode::Active,
+        to: Mode::Standby,
+        guards: HEAT,
+        actions: &[Action::EnterStandby],
+    },
+    Transition {
+        from: Mode::Brew,
+        to: Mode::Idle,
+        guards: &[],
+        actions: &[Action::PowerOff, Action::EndShot],
+    },
+    Transition {
+        from: Mode::Brew,
+        to: Mode::Active,
+        guards: &[],
+        actions: &[Action::EndShot],
+    },
+    Transition {
+        from: Mode::Brew,
+        to: Mode::Steam,
+        guards: HEAT,
+        actions: &[Action::EndShot, Action::EnterSteam],
+    },
+    Transition {
+        from: Mode::Steam,
+        to: Mode::Idle,
+        guards: &[],
+        actions: &[Action::ExitSteam, Action::PowerOff],
+    },
+    Transition {
+        from: Mode::Steam,
+        to: Mode::Active,
+        guards: &[],
+        actions: &[Action::ExitSteam],
+    },
+    // Standby has to be woken up before brewing, so the boiler is at the brew temperature.
+    Transition {
+        from: Mode::Standby,
+        to: Mode::Idle,
+        guards: &[],
+        actions: &[Action::ExitStandby, Action::PowerOff],
+    },
+    Transition {
+        from: Mode::Standby,
+        to: Mode::Active,
+        guards: HEAT,
+        actions: &[Action::ExitStandby],
+    },
+    Transition {
+        from: Mode::Standby,
+        to: Mode::Steam,
+        guards: HEAT,
+        actions: &[Action::ExitStandby, Action::EnterSteam],
+    },
+];
+
+pub fn find_transition(from: &Mode, to: &Mode) -> Option<&'static Transition> {
+    TRANSITIONS
+        .iter()
+        .find(|transition| transition.from == *from && transition.to == *to)
+}
+
+// Published on gesha/mode/transition for every requested transition, whether or not it was allowed.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ModeTransition {
+    pub timestamp: i64,
+    pub from: Mode,
+    pub to: Mode,
+    pub trigger: TransitionTrigger,
+    // The client id of the MQTT client that asked for the transition, if it came from one.
+    pub requester: Option<String>,
+    pub accepted: bool,
+    // Why the transition was rejected.
+    pub reason: Option<String>,
+    // The guard that rejected the transition, if any.
+    pub guard: Option<Guard>,
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    use tokio::sync::broadcast;
+
+    use super::*;
+    use crate::{
+        core::{
+            config::Config,
+            mqtt::{MqttIncomingMessage, MqttOutgoingMessage},
+            state::{Event, State},
+        },
+        models::PredictiveModels,
+    };
+
+    const MODES: [Mode; 6] = [
+        Mode::Idle,
+        Mode::Active,
+        Mode::Brew,
+        Mode::Steam,
+        Mode::Standby,
+        Mode::Offline,
+    ];
+
+    #[test]
+    fn supported_transitions() {
+        let table = [
+            (Mode::Idle, Mode::Active, true),
+            (Mode::Idle, Mode::Brew, false),
+            (Mode::Active, Mode::Brew, true),
+            (Mode::Brew, Mode::Standby, false),
+            (Mode::Standby, Mode::Active, true),
+            (Mode::Standby, Mode::Brew, false),
+            (Mode::Steam, Mode::Brew, false),
+            (Mode::Offline, Mode::Active, false),
+            (Mode::Active, Mode::Offline, false),
+        ];
+
+        for (from, to, supported) in table {
+            assert_eq!(find_transition(&from, &to).is_some(), supported, "{from:?} -> {to:?}");
+        }
+    }
+
+    #[test]
+    fn turning_off_is_never_guarded() {
+        for from in MODES.iter().filter(|mode| !matches!(mode, Mode::Idle | Mode::Offline)) {
+            let transition = find_transition(from, &Mode::Idle).unwrap();
+
+            assert!(transition.guards.is_empty(), "{from:?} -> Idle");
+            assert!(transition.actions.contains(&Action::PowerOff), "{from:?} -> Idle");
+        }
+    }
+
+    #[test]
+    fn relay_triggers() {
+        let table = [
+            (TransitionTrigger::User, false),
+            (TransitionTrigger::RelayPowerOn, true),
+            (TransitionTrigger::RelayPowerOff, true),
+            (TransitionTrigger::RelayUnavailable, true),
+            (TransitionTrigger::RelayCommandFailed, true),
+            (TransitionTrigger::ShotDetected, false),
+            (TransitionTrigger::Schedule, false),
+            (TransitionTrigger::Inactivity, false),
+            (TransitionTrigger::Wake, false),
+            (TransitionTrigger::SafetyTripped, false),
+        ];
+
+        for (trigger, is_relay) in table {
+            assert_eq!(trigger.is_relay(), is_relay, "{trigger:?}");
+        }
+    }
+
+    pub(crate) fn db_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("gesha-{name}-{}.db", std::process::id()))
+    }
+
+    // A state with an empty DB.
+    pub(crate) async fn state(name: &str) -> (State, broadcast::Receiver<Event>) {
+        let db_path = db_path(name);
+        let _ = std::fs::remove_file(&db_path);
+
+        state_at(&db_path).await
+    }
+
+    // The receiver has to be kept, the state fails to send events without one.
+    pub(crate) async fn state_at(db_path: &Path) -> (State, broadcast::Receiver<Event>) {
+        let config: Config = serde_yaml::from_str(&format!("dbPath: {}", db_path.display())).unwrap();
+        let (tx, rx) = broadcast::channel(100);
+
+        let state = State::new(tx, &config, Arc::new(PredictiveModels::new().unwrap()))
+            .await
+            .unwrap();
+
+        (state, rx)
+    }
+
+    async fn request(state: &mut State, mode: Mode, trigger: TransitionTrigger) -> (Vec<Event>, ModeTransition) {
+        let events = state
+            .handle_event(&Event::ModeTransitionRequest { mode, trigger })
+            .await
+            .unwrap();
+
+        let transition = events
+            .iter()
+            .find_map(|event| match event {
+                Event::OutgoingMqttMessage(MqttOutgoingMessage::ModeTransitionUpdate(transition)) => {
+                    Some(transition.clone())
+                }
+                _ => None,
+            })
+            .unwrap();
+
+        (events, transition)
+    }
+
+    fn is_relay_command(event: &Event) -> bool {
+        matches!(
+            event,
+            Event::OutgoingMqttMessage(MqttOutgoingMessage::ExternRelayPowerStateSetCmd(_))
+        )
+    }
+
+    #[tokio::test]
+    async fn rejected_transitions_are_published_with_the_reason_and_guard() {
+        let (mut state, _rx) = state("transition-rejected").await;
+
+        let (events, transition) = request(&mut state, Mode::Brew, TransitionTrigger::User).await;
+
+        assert!(!transition.accepted);
+        assert_eq!(transition.reason.as_deref(), Some("Moving from mode Idle to Brew is not supported"));
+        assert_eq!(transition.guard, None);
+        assert!(!events.iter().any(|event| matches!(event, Event::ModeChanged(_))));
+
+        state
+            .handle_event(&Event::IncomingMqttMessage(
+                MqttIncomingMessage::ExternRelayAvailabilityChanged(false),
+                None,
+            ))
+            .await
+            .unwrap();
+
+        let (events, transition) = request(&mut state, Mode::Active, TransitionTrigger::User).await;
+
+        assert!(!transition.accepted);
+        assert_eq!(transition.guard, Some(Guard::RelayAvailable));
+        assert_eq!(transition.reason.as_deref(), Some(Guard::RelayAvailable.reason()));
+        assert!(!events.iter().any(is_relay_command));
+        assert_eq!(state.mode, Mode::Idle);
+
+        state.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejections_are_reported_back_to_the_requester() {
+        let (mut state, _rx) = state("transition-requester").await;
+
+        let events = state
+            .handle_event(&Event::IncomingMqttMessage(
+                MqttIncomingMessage::ModeSet(Mode::Brew),
+                Some("gesha-ui".to_string()),
+            ))
+            .await
+            .unwrap();
+
+        let transition = events
+            .iter()
+            .find_map(|event| match event {
+                Event::OutgoingMqttMessage(MqttOutgoingMessage::ModeTransitionUpdate(transition)) => {
+                    Some(transition.clone())
+                }
+                _ => None,
+            })
+            .unwrap();
+
+        assert!(!transition.accepted);
+        assert_eq!(transition.trigger, TransitionTrigger::User);
+        assert_eq!(transition.requester.as_deref(), Some("gesha-ui"));
+
+        let (_, transition) = request(&mut state, Mode::Active, TransitionTrigger::Schedule).await;
+
+        assert!(transition.accepted);
+        assert_eq!(transition.requester, None);
+
+        state.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn relay_triggered_transitions_dont_command_the_relay() {
+        let (mut state, _rx) = state("transition-relay").await;
+
+        let (events, transition) = request(&mut state, Mode::Active, TransitionTrigger::RelayPowerOn).await;
+
+        assert!(transition.accepted);
+        assert!(!events.iter().any(is_relay_command));
+
+        let (events, transition) = request(&mut state, Mode::Idle, TransitionTrigger::User).await;
+
+        assert!(transition.accepted);
+        assert!(events.iter().any(is_relay_command));
+
+        state.stop().await.unwrap();
+    }
+}
@@ -34,4 +34,8 @@ where
 
         self.deque.push_back(value);
     }
+
+    pub fn iter(&self) -> std::collections::vec_deque::Iter<'_, T> {
+        self.deque.iter()
+    }
 }
@@ -1,3 +1,4 @@
+pub mod bench;
 pub mod controller;
 pub mod core;
 pub mod models;
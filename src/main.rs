@@ -1,26 +1,54 @@
-use clap::Parser;
-use gesha::{
-    controller,
-    core::{
-        config,
-        mqtt::Mqtt,
-        state::{self, Event},
-        thermocouple::ThermocouplePoller,
-    },
+use clap::{Parser, Subcommand};
+use gesha::core::{
+    config::Config,
+    db::{Db, DB_PATH},
+    shot_detector,
 };
-use log::{debug, error, info, trace};
+use log::{error, trace};
 use std::error::Error;
-use tokio::{
-    select,
-    signal::unix::{signal, SignalKind},
-    sync::broadcast,
-};
 use tokio_util::sync::CancellationToken;
 
+// Running the machines needs the Pi's GPIO and SPI, the other commands run anywhere.
+#[cfg(all(target_arch = "arm", target_os = "linux"))]
+use {
+    gesha::{
+        core::{
+            machine::{self, Machine},
+            state::Event,
+        },
+        models::PredictiveModels,
+    },
+    log::{debug, info},
+    std::sync::Arc,
+    tokio::{
+        select,
+        signal::unix::{signal, SignalKind},
+        sync::{broadcast, mpsc},
+    },
+};
+
 #[derive(Parser, Debug, Clone)]
 struct Args {
     #[arg(short, long)]
     pub config_path: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Benchmark the control methods against a simulated boiler
+    Bench {
+        /// Output the results as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Replay the measurements around the recorded shots through the shot detector
+    EvaluateShotDetection {
+        #[arg(long)]
+        db_path: Option<String>,
+    },
 }
 
 #[tokio::main]
@@ -29,80 +57,72 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let args = Args::parse();
 
+    if let Some(Command::Bench { json }) = args.command {
+        return Ok(gesha::bench::run(json)?);
+    }
+
     let panic_cancel_token = create_panic_cancel_token();
 
-    let config = config::Config::load(args.config_path).await?;
-    let config_clone = &config.clone();
+    let config = Config::load(args.config_path).await?;
+
+    if let Some(Command::EvaluateShotDetection { db_path }) = args.command {
+        let db = Db::new(db_path.as_deref().unwrap_or(DB_PATH)).await?;
+        let evaluation = shot_detector::evaluate(&db, &config.shot_detection).await?;
+
+        println!("{}", serde_json::to_string_pretty(&evaluation)?);
+
+        return Ok(());
+    }
 
     trace!("Using config:\n {:#?}", config);
 
-    let (tx, mut rx) = broadcast::channel::<Event>(10_000);
+    run_machines(config, panic_cancel_token).await
+}
 
-    let mut state = state::State::new(tx.clone()).await?;
+#[cfg(all(target_arch = "arm", target_os = "linux"))]
+async fn run_machines(
+    config: Config,
+    panic_cancel_token: CancellationToken,
+) -> Result<(), Box<dyn Error>> {
+    let models = Arc::new(PredictiveModels::load(&config.models)?);
 
-    let mut mqtt = Mqtt::new(
-        config.mqtt_url.expect("No MQTT server configured").as_ref(),
-        tx.clone(),
-    )?;
+    // The machines' events are forwarded to a single channel, so they can be handled in one loop.
+    let (machine_event_tx, mut machine_event_rx) = mpsc::channel::<(usize, Event)>(10_000);
 
-    mqtt.start().await?;
+    let mut machines: Vec<Machine> = vec![];
 
-    let mut controller_manager = controller::ControllerManager::new(
-        config.boiler_pin,
-        &state.control_method,
-        tx.clone(),
-        state.target_temperature,
-        state.mode.clone(),
-    )?;
+    for machine_config in config.machines()? {
+        let (machine, rx) = Machine::start(&machine_config, models.clone()).await?;
 
-    controller_manager.start()?;
+        forward_events(machines.len(), rx, machine_event_tx.clone());
 
-    let mut thermocouples =
-        ThermocouplePoller::new(state.mode.clone(), tx.clone(), config_clone.clone());
+        machines.push(machine);
+    }
 
-    thermocouples.poll()?;
+    if let Some(event) = machine::machines_update(&machines) {
+        machines[0].send(event)?;
+    }
 
     let mut hangup_signal = signal(SignalKind::hangup())?;
     let mut interrupt_signal = signal(SignalKind::interrupt())?;
 
     loop {
         select! {
-            Ok(event) = rx.recv() => {
-                match &event {
-                    // The state is not updated by outgoing MQTT messages,
-                    // they're use to update external devices to state changes that
-                    // have already happened. We don't want to handle these events in the state.
-                    Event::OutgoingMqttMessage(message) => {
-                        mqtt.publish(&message).await?;
-                    }
-                    event => {
-
-                        // Log all events except temperature changes -
-                        // they can happen every 100ms and would spam the logs.
-                        if !matches!(event, Event::TemperatureChanged(_)) {
-                            info!("Received event: {:?}", event);
-                        }
-
-                        match state.handle_event(event).await {
-                            Ok(events) => {
-                                for event in events.iter() {
-                                    tx.send(event.clone())?;
-                                }
-                            }
-                            Err(err) => {
-                                error!("Error updating state: {}", err);
-                            }
-                        }
-                    }
-                }
+            Some((index, event)) = machine_event_rx.recv() => {
+                machines[index].handle_event(&event).await?;
             },
             _ = interrupt_signal.recv() => {
                 debug!("SIGINT received");
                 break;
             },
             _ = hangup_signal.recv() => {
-                debug!("SIGHUP received");
-                break;
+                debug!("SIGHUP received, reloading models");
+
+                for machine in machines.iter_mut() {
+                    if let Err(err) = machine.reload_models().await {
+                        error!("Error reloading models: {}", err);
+                    }
+                }
             }
             _ = panic_cancel_token.cancelled() => {
                 debug!("Panic Cancel Token triggered");
@@ -113,15 +133,40 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     info!("Shutting down");
 
-    mqtt.stop().await?;
-    controller_manager.stop().await?;
-    state.stop().await?;
+    for machine in machines.iter_mut() {
+        machine.stop().await?;
+    }
 
-    drop(tx);
+    Ok(())
+}
 
-    let _ = rx.recv().await;
+#[cfg(not(all(target_arch = "arm", target_os = "linux")))]
+async fn run_machines(
+    _config: Config,
+    _panic_cancel_token: CancellationToken,
+) -> Result<(), Box<dyn Error>> {
+    Err("The machines are only available on ARM Linux".into())
+}
 
-    Ok(())
+#[cfg(all(target_arch = "arm", target_os = "linux"))]
+fn forward_events(
+    index: usize,
+    mut rx: broadcast::Receiver<Event>,
+    tx: mpsc::Sender<(usize, Event)>,
+) {
+    tokio::spawn(async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => {
+                    if tx.send((index, event)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+            }
+        }
+    });
 }
 
 fn create_panic_cancel_token() -> CancellationToken {
@@ -0,0 +1,131 @@
+use std::collections::VecDeque;
+
+// The proportion of measured values expected to fall within the interval.
+const COVERAGE: f32 = 0.9;
+
+// The number of most recent residuals the interval is computed from.
+pub const MAX_RESIDUALS: usize = 1000;
+
+// Too few residuals give a meaningless interval, so none is given until there are enough.
+const MIN_RESIDUALS: usize = 30;
+
+// A prediction interval from the empirical distribution of the model's residuals (measured - predicted),
+// which makes no assumptions about the shape of the errors.
+pub struct ResidualInterval {
+    residuals: VecDeque<f32>,
+}
+
+impl Default for ResidualInterval {
+    fn default() -> Self {
+        ResidualInterval::new()
+    }
+}
+
+impl ResidualInterval {
+    pub fn new() -> Self {
+        ResidualInterval {
+            residuals: VecDeque::with_capacity(MAX_RESIDUALS),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.residuals.clear();
+    }
+
+    pub fn push(&mut self, measured: f32, predicted: f32) {
+        if self.residuals.len() == MAX_RESIDUALS {
+            self.residuals.pop_front();
+        }
+
+        self.residuals.push_back(measured - predicted);
+    }
+
+    // The lower and upper bounds around the prediction.
+    pub fn interval(&self, predicted: f32) -> Option<(f32, f32)> {
+        if self.residuals.len() < MIN_RESIDUALS {
+            return None;
+        }
+
+        let mut residuals: Vec<f32> = self.residuals.iter().copied().collect();
+        residuals.sort_by(|a, b| a.total_cmp(b));
+
+        let tail = (1.0 - COVERAGE) / 2.0;
+
+        Some((
+            predicted + quantile(&residuals, tail),
+            predicted + quantile(&residuals, 1.0 - tail),
+        ))
+    }
+}
+
+// Linearly interpolates between the closest ranks of the sorted values.
+fn quantile(sorted: &[f32], q: f32) -> f32 {
+    let rank = q * (sorted.len() - 1) as f32;
+    let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_interval_until_there_are_enough_residuals() {
+        let mut interval = ResidualInterval::new();
+
+        for _ in 1..MIN_RESIDUALS {
+            interval.push(1.0, 0.0);
+        }
+
+        assert_eq!(interval.interval(90.0), None);
+
+        interval.push(1.0, 0.0);
+
+        assert_eq!(interval.interval(90.0), Some((91.0, 91.0)));
+    }
+
+    #[test]
+    fn interval_covers_ninety_percent_of_the_residuals() {
+        let mut interval = ResidualInterval::new();
+
+        // Shuffled residuals of 0 - 999, the order they arrive in shouldn't matter.
+        let residuals: Vec<f32> = (0..MAX_RESIDUALS).map(|i| ((i * 379) % MAX_RESIDUALS) as f32).collect();
+
+        for residual in residuals.iter() {
+            interval.push(90.0 + residual, 90.0);
+        }
+
+        let (lower, upper) = interval.interval(0.0).unwrap();
+
+        // The 5th and 95th percentiles, interpolated between ranks 49 - 50 and 949 - 950.
+        assert!((lower - 49.95).abs() < 1e-3, "{lower}");
+        assert!((upper - 949.05).abs() < 1e-3, "{upper}");
+
+        let covered = residuals
+            .iter()
+            .filter(|residual| (lower..=upper).contains(residual))
+            .count();
+
+        assert_eq!(covered as f32 / MAX_RESIDUALS as f32, COVERAGE);
+    }
+
+    #[test]
+    fn only_the_most_recent_residuals_are_kept() {
+        let mut interval = ResidualInterval::new();
+
+        for _ in 0..MAX_RESIDUALS {
+            interval.push(100.0, 0.0);
+        }
+
+        for _ in 0..MAX_RESIDUALS {
+            interval.push(-1.0, 0.0);
+        }
+
+        assert_eq!(interval.interval(0.0), Some((-1.0, -1.0)));
+
+        interval.clear();
+
+        assert_eq!(interval.interval(0.0), None);
+    }
+}
@@ -1,47 +1,101 @@
-use std::io::Cursor;
+use std::{fmt, fs, io::Cursor, ops::RangeInclusive};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use log::{error, info};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use tract_core::ndarray;
 use tract_onnx::prelude::*;
 
+use crate::core::config::ModelsConfig;
+
+mod interval;
+
+pub use interval::{ResidualInterval, MAX_RESIDUALS};
+
+type Model = SimplePlan<TypedFact, Box<dyn TypedOp>, Graph<TypedFact, Box<dyn TypedOp>>>;
+
+// The models built into the binary, used when no model path is configured or the configured model fails to load.
+const EMBEDDED_EXTRACTION_TEMP_MODEL: &[u8] =
+    include_bytes!("../../models/extraction_temperature/output/extraction_temperature.onnx");
+const EMBEDDED_BOILER_TEMP_DIFF_MODEL: &[u8] =
+    include_bytes!("../../models/predictive/output/subset_model.onnx");
+
+// What each model takes and produces. Every prediction is checked against the output range,
+// and each model is run with the probe input when it's loaded so a broken model is caught at startup.
+struct ModelSpec {
+    input_count: usize,
+    output_range: RangeInclusive<f32>,
+    // Clamp predictions to the output range instead of rejecting them.
+    saturate: bool,
+    probe: &'static [f32],
+}
+
+// grouphead temp, boiler temp -> extraction temp
+const EXTRACTION_TEMP_MODEL: ModelSpec = ModelSpec {
+    input_count: 2,
+    output_range: 0.0..=150.0,
+    saturate: false,
+    probe: &[80.0, 95.0],
+};
+
+// grouphead temp, boiler temp, q -> boiler temp change
+// The model overshoots the range it was trained on by a degree or two at the edges of its inputs,
+// the controller only cares about the sign relative to the target so those predictions are saturated.
+const BOILER_TEMP_DIFF_MODEL: ModelSpec = ModelSpec {
+    input_count: 3,
+    output_range: -30.0..=30.0,
+    saturate: true,
+    probe: &[80.0, 95.0, 100.0],
+};
+
 pub struct PredictiveModels {
-    extraction_temp_model:
-        SimplePlan<TypedFact, Box<dyn TypedOp>, Graph<TypedFact, Box<dyn TypedOp>>>,
-    boiler_temp_diff_model:
-        SimplePlan<TypedFact, Box<dyn TypedOp>, Graph<TypedFact, Box<dyn TypedOp>>>,
+    extraction_temp_model: Model,
+    boiler_temp_diff_model: Model,
+    pub info: PredictiveModelsInfo,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PredictiveModelsInfo {
+    pub extraction_temperature: ModelInfo,
+    pub boiler_temp_diff: ModelInfo,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelInfo {
+    // The path the model was loaded from, or "embedded".
+    pub source: String,
+    // The SHA-256 of the ONNX file, which identifies the model version.
+    pub hash: String,
 }
 
 impl PredictiveModels {
     pub fn new() -> Result<PredictiveModels> {
-        let mut extraction_temp_onnx = Cursor::new(
-            include_bytes!(
-                "../../models/extraction_temperature/output/extraction_temperature.onnx"
-            )
-            .to_vec()
-            .clone(),
-        );
-
-        let extraction_temp_model = tract_onnx::onnx()
-            .model_for_read(&mut extraction_temp_onnx)?
-            .with_output_fact(0, Default::default())?
-            .into_optimized()?
-            .into_runnable()?;
-
-        let mut boiler_temp_diff_onnx = Cursor::new(
-            include_bytes!("../../models/predictive/output/subset_model.onnx")
-                .to_vec()
-                .clone(),
-        );
-
-        let boiler_temp_diff_model = tract_onnx::onnx()
-            .model_for_read(&mut boiler_temp_diff_onnx)?
-            .with_output_fact(0, Default::default())?
-            .into_optimized()?
-            .into_runnable()?;
+        PredictiveModels::load(&ModelsConfig::default())
+    }
+
+    pub fn load(config: &ModelsConfig) -> Result<PredictiveModels> {
+        let (extraction_temp_model, extraction_temperature) = load_model(
+            config.extraction_temperature_path.as_deref(),
+            EMBEDDED_EXTRACTION_TEMP_MODEL,
+            &EXTRACTION_TEMP_MODEL,
+        )?;
+
+        let (boiler_temp_diff_model, boiler_temp_diff) = load_model(
+            config.boiler_temp_diff_path.as_deref(),
+            EMBEDDED_BOILER_TEMP_DIFF_MODEL,
+            &BOILER_TEMP_DIFF_MODEL,
+        )?;
 
         Ok(PredictiveModels {
             extraction_temp_model,
             boiler_temp_diff_model,
+            info: PredictiveModelsInfo {
+                extraction_temperature,
+                boiler_temp_diff,
+            },
         })
     }
 
@@ -50,48 +104,194 @@ impl PredictiveModels {
         grouphead_temp_c: f32,
         boiler_temp_c: f32,
     ) -> Result<f32> {
-        let input = ndarray::arr1(&[grouphead_temp_c, boiler_temp_c])
-            .into_shape([1, 2])?
-            .into_tensor();
+        predict(
+            &self.extraction_temp_model,
+            &EXTRACTION_TEMP_MODEL,
+            &[grouphead_temp_c, boiler_temp_c],
+        )
+    }
+
+    pub fn predict_boiler_temp_diff(&self, grouphead_temp_c: f32, boiler_temp_c: f32, q: f32) -> Result<f32> {
+        predict(
+            &self.boiler_temp_diff_model,
+            &BOILER_TEMP_DIFF_MODEL,
+            &[grouphead_temp_c, boiler_temp_c, q],
+        )
+    }
+}
 
-        let found = self
-            .extraction_temp_model
-            .run(tvec![input.into()])
-            .unwrap()
-            .remove(0);
-        let n = found.to_scalar::<f32>()?;
+fn predict(model: &Model, spec: &ModelSpec, inputs: &[f32]) -> Result<f32> {
+    if inputs.len() != spec.input_count {
+        return Err(anyhow!(
+            "Expected {} inputs, but got {}",
+            spec.input_count,
+            inputs.len()
+        ));
+    }
 
-        Ok(n.clone())
+    if let Some(input) = inputs.iter().find(|input| !input.is_finite()) {
+        return Err(anyhow!("Invalid input {input} in {inputs:?}"));
     }
 
-    pub fn predict_boiler_temp_diff(&self, grouphead_temp_c: f32, boiler_temp_c: f32, q: f32) -> Result<f32> {
-        let input = ndarray::arr1(&[grouphead_temp_c, boiler_temp_c, q])
-            .into_shape([1, 3])?
-            .into_tensor();
+    let input = ndarray::Array1::from_vec(inputs.to_vec())
+        .into_shape([1, spec.input_count])?
+        .into_tensor();
+
+    let output = model
+        .run(tvec![input.into()])
+        .map_err(|err| anyhow!("Failed to run the model with {inputs:?}: {err}"))?
+        .remove(0);
 
-        let found = self
-            .boiler_temp_diff_model
-            .run(tvec![input.into()])
-            .unwrap()
-            .remove(0);
-        let n = found.to_scalar::<f32>()?;
+    let prediction = *output.to_scalar::<f32>()?;
 
-        Ok(n.clone())
+    if spec.saturate && prediction.is_finite() {
+        return Ok(prediction.clamp(*spec.output_range.start(), *spec.output_range.end()));
+    }
+
+    if !spec.output_range.contains(&prediction) {
+        return Err(anyhow!(
+            "Prediction {prediction} for {inputs:?} is outside of the expected range {:?}",
+            spec.output_range
+        ));
+    }
+
+    Ok(prediction)
+}
+
+impl fmt::Debug for PredictiveModels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PredictiveModels")
+            .field("info", &self.info)
+            .finish()
+    }
+}
+
+fn load_model(path: Option<&str>, embedded: &[u8], spec: &ModelSpec) -> Result<(Model, ModelInfo)> {
+    if let Some(path) = path {
+        let model = fs::read(path)
+            .map_err(|err| anyhow!("Failed to read {path}: {err}"))
+            .and_then(|onnx| Ok((build_model(&onnx, spec)?, onnx)));
+
+        match model {
+            Ok((model, onnx)) => {
+                info!("Loaded model from {path}");
+
+                return Ok((
+                    model,
+                    ModelInfo {
+                        source: path.to_string(),
+                        hash: hash(&onnx),
+                    },
+                ));
+            }
+            Err(err) => {
+                error!("Failed to load model from {path}, using the embedded model instead. Error: {err}");
+            }
+        }
+    }
+
+    Ok((
+        build_model(embedded, spec)?,
+        ModelInfo {
+            source: String::from("embedded"),
+            hash: hash(embedded),
+        },
+    ))
+}
+
+// Every model takes a single [1, input_count] tensor and produces a single value.
+// Setting the input shape makes tract reject models that were trained with a different number of features.
+fn build_model(onnx: &[u8], spec: &ModelSpec) -> Result<Model> {
+    let model = tract_onnx::onnx()
+        .model_for_read(&mut Cursor::new(onnx))?
+        .with_input_fact(0, f32::fact([1, spec.input_count]).into())?
+        .with_output_fact(0, Default::default())?
+        .into_optimized()?;
+
+    if model.inputs.len() != 1 || model.outputs.len() != 1 {
+        return Err(anyhow!(
+            "Expected a model with one input and one output, but it has {} inputs and {} outputs",
+            model.inputs.len(),
+            model.outputs.len()
+        ));
+    }
+
+    let output_shape = model.output_fact(0)?.shape.as_concrete().map(|shape| shape.to_vec());
+
+    if output_shape.as_ref().map(|shape| shape.iter().product::<usize>()) != Some(1) {
+        return Err(anyhow!(
+            "Expected the model to output a single value, but the output shape is {:?}",
+            output_shape
+        ));
+    }
+
+    let model = model.into_runnable()?;
+
+    predict(&model, spec, spec.probe).map_err(|err| anyhow!("The model failed its probe: {err}"))?;
+
+    Ok(model)
+}
+
+fn hash(onnx: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(onnx))
+}
+
+// The grouphead temperature at which the group is preheated for brewing at the target temperature.
+pub fn get_preheat_temperature(target_temp: f64) -> f64 {
+    match target_temp {
+        t if t <= 90.0 => 74.0,
+        t if t <= 93.0 => 76.0,
+        t if t <= 95.0 => 78.0,
+        t if t <= 99.0 => 80.0,
+        t if t <= 101.0 => 82.0,
+        t if t <= 103.0 => 84.0,
+        t if t <= 107.0 => 86.0,
+        t if t <= 109.0 => 88.0,
+        _ => 90.0,
     }
 }
 
 pub fn get_preheat_level(target_temp: f64, grouphead_temp: f64) -> f64 {
-    let level = match target_temp {
-        t if t <= 90.0 => grouphead_temp / 74.0,
-        t if t <= 93.0 => grouphead_temp / 76.0,
-        t if t <= 95.0 => grouphead_temp / 78.0,
-        t if t <= 99.0 => grouphead_temp / 80.0,
-        t if t <= 101.0 => grouphead_temp / 82.0,
-        t if t <= 103.0 => grouphead_temp / 84.0,
-        t if t <= 107.0 => grouphead_temp / 86.0,
-        t if t <= 109.0 => grouphead_temp / 88.0,
-        _ => grouphead_temp / 90.0,
-    };
+    let level = grouphead_temp / get_preheat_temperature(target_temp);
 
     level.min(1.0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boiler_temp_diff_predictions_are_valid_over_a_realistic_grid() {
+        let models = PredictiveModels::new().unwrap();
+
+        for grouphead_temp in (20..=100).step_by(5) {
+            for boiler_temp in (20..=130).step_by(5) {
+                for q in (0..=500).step_by(20) {
+                    let inputs = (grouphead_temp as f32, boiler_temp as f32, q as f32);
+                    let prediction = models
+                        .predict_boiler_temp_diff(inputs.0, inputs.1, inputs.2)
+                        .unwrap_or_else(|err| panic!("{inputs:?}: {err}"));
+
+                    assert!(BOILER_TEMP_DIFF_MODEL.output_range.contains(&prediction));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn boiler_temp_diff_predictions_outside_the_range_are_saturated() {
+        let models = PredictiveModels::new().unwrap();
+
+        assert_eq!(models.predict_boiler_temp_diff(86.5, 86.75, 0.0).unwrap(), 30.0);
+        assert_eq!(models.predict_boiler_temp_diff(21.0, 44.0, 440.0).unwrap(), -30.0);
+    }
+
+    #[test]
+    fn non_finite_inputs_are_rejected() {
+        let models = PredictiveModels::new().unwrap();
+
+        assert!(models.predict_boiler_temp_diff(f32::NAN, 95.0, 100.0).is_err());
+        assert!(models.predict_extraction_temperature(80.0, f32::INFINITY).is_err());
+    }
+}
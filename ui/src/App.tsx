@@ -14,11 +14,11 @@ export function App() {
 
     const client = new GeshaClient();
 
-    const mode = client.createSignal("mode")
+    const availability = client.createSignal("availability")
     const connectionStatus = client.createSignal("status")
 
     return (
-        <main class={styles.app} data-connection-status={mode() == "offline" ? "disconnected" : connectionStatus()}>
+        <main class={styles.app} data-connection-status={availability() == "offline" ? "disconnected" : connectionStatus()}>
             <nav class={styles.nav}>
                 <NavItem active={isActive("main")} onClick={() => setScreen("main")}>
                     Main
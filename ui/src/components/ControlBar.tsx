@@ -85,6 +85,7 @@ export function ControlBar(_: ControlBarProps) {
                             Brew
                         </option>
                         <option value="steam">Steam</option>
+                        <option value="standby">Standby</option>
                     </select>
                 </label>
                 <label class={styles.verticalLabel}>
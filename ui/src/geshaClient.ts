@@ -22,12 +22,15 @@ type GeshaClientEvents = {
     "temperature/grouphead/history": (valueChange: ValueChange[]) => void
     "temperature/thermofilter": (valueChange: ValueChange) => void
     "temperature/thermofilter/history": (valueChange: ValueChange[]) => void
-    "temperature/thermofilter_predicted": (valueChange: ValueChange) => void
+    "temperature/thermofilter_predicted": (
+        valueChange: PredictedValueChange,
+    ) => void
     "temperature/target": (temperatureTarget: number) => void
     boiler_level: (value: ValueChange) => void
     "boiler_level/history": (value: ValueChange[]) => void
     control_method: (controlMethod: ControlMethod) => void
     mode: (mode: Mode) => void
+    availability: (availability: "online" | "offline") => void
 } & {
     [key: `shot/history/${string}`]: (shots: Shot[]) => void
 } & {
@@ -82,6 +85,7 @@ export class GeshaClient extends TypedEventEmitter<GeshaClientEvents> {
             case "config/*":
             case "temperature/target":
             case "mode":
+            case "availability":
             case "temperature/*":
             case "boiler_level": {
                 let data = payload.toString()
@@ -448,6 +452,12 @@ export type ValueChange = {
     value: number
 }
 
+// The bounds of the prediction interval are null until enough measurements have been seen.
+export type PredictedValueChange = ValueChange & {
+    lower: number | null
+    upper: number | null
+}
+
 export function assertValueChange(
     value: unknown,
 ): asserts value is ValueChange {
@@ -463,7 +473,7 @@ export function assertValueChange(
     }
 }
 
-export type Mode = "offline" | "idle" | "active" | "brew" | "steam"
+export type Mode = "offline" | "idle" | "active" | "brew" | "steam" | "standby"
 
 export type ControlMethod = "None" | "Threshold" | "PID" | "Predictive"
 